@@ -8,8 +8,8 @@ MEMORY {
 PROVIDE(_stext = 0xffffffff80200000);
 /* 如果要扩栈就改这个数 */
 PROVIDE(_hart_stack_size = 128K);
-/* 加核心的时候同时需要改这个数 */
-PROVIDE(_max_hart_id = 1);
+/* 加核心的时候同时需要改这个数，以及 process::MAX_HART_COUNT */
+PROVIDE(_max_hart_id = 7);
 PROVIDE(_heap_size = 16M);
 
 REGION_ALIAS("REGION_TEXT", VIRT_DRAM);
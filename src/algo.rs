@@ -16,6 +16,10 @@ pub trait Scheduler<ThreadType: Clone + PartialEq>: Default {
     fn remove_thread(&mut self, thread: &ThreadType);
     /// 设置线程的优先级
     fn set_priority<T>(&mut self, thread: ThreadType, priority: T);
+    /// 时间片的长度，单位为微秒。线程自己设置了时间片或者启动参数中指定了时间片时不使用
+    fn time_slice(&self) -> u64 {
+        10_000
+    }
 }
 
 pub type SchedulerImpl<T> = fifo_scheduler::FifoScheduler<T>;
@@ -1,11 +1,22 @@
-use crate::mem::{PhysicalAddress, VirtualAddress};
+use crate::mem::{MemoryLayout, PhysicalAddress, VirtualAddress};
 
 pub mod block;
 mod device_tree;
-mod ns16550a;
+mod goldfish_rtc;
+pub mod ns16550a;
+mod pci;
+pub mod plic;
+pub mod registry;
+pub mod syscon;
 mod virtio;
 
+use alloc::string::String;
+pub use registry::DeviceInfo;
 use riscv_sbi::println;
+use spin::Once;
+
+/// 启动参数，来自设备树的 `/chosen` 节点
+static BOOTARGS: Once<String> = Once::new();
 
 /// 从设备树的物理地址来获取全部设备信息并初始化
 pub fn init(dtb_pa: PhysicalAddress) {
@@ -14,40 +25,139 @@ pub fn init(dtb_pa: PhysicalAddress) {
     println!("mod driver initialized")
 }
 
-/// 驱动类型
+/// 启动参数，没有时为空
+pub fn bootargs() -> &'static str {
+    BOOTARGS.r#try().map_or("", |bootargs| bootargs.as_str())
+}
+
+/// 启动参数中 `key=value` 形式的参数的值
+fn bootarg(key: &str) -> Option<&'static str> {
+    bootargs().split_whitespace().find_map(|arg| {
+        if arg.len() > key.len() && arg.starts_with(key) && arg.as_bytes()[key.len()] == b'=' {
+            Some(&arg[key.len() + 1..])
+        } else {
+            None
+        }
+    })
+}
+
+/// 启动参数中 `root=` 指定的根文件系统所在的设备，例如 `/dev/vda`
+pub fn root_device() -> Option<&'static str> {
+    bootarg("root")
+}
+
+/// 启动参数中 `console=` 指定的控制台设备的名称，例如 `hvc0`
 ///
-/// 目前只有块设备，可能还有网络、GPU 设备等
-// 未来做成类似于GUID的结构
-#[derive(Debug, Eq, PartialEq)]
-#[non_exhaustive]
-pub enum DeviceType {
-    Block,
+/// 没有指定时，串口的输入作为控制台输入，输出使用 SBI（或者开启 uart-stdout 特性时使用串口）
+pub fn console_device() -> Option<&'static str> {
+    bootarg("console").map(|console| console.trim_start_matches("/dev/"))
 }
 
-use alloc::sync::Arc;
-use alloc::vec::Vec;
-use lazy_static::lazy_static;
-use spin::RwLock;
+/// 启动参数中 `timeslice=` 指定的调度时间片长度，单位为微秒
+pub fn time_slice() -> Option<u64> {
+    bootarg("timeslice").and_then(|time_slice| time_slice.parse().ok())
+}
 
-// 这一块需要重新设计，可以用Any，支持包括自定义在内的大量设备类型
+/// 启动参数中 `syscall_log=` 指定的系统调用日志，`unknown` 或者 `all`
+pub fn syscall_log() -> Option<&'static str> {
+    bootarg("syscall_log")
+}
+
+/// 从设备树中读取物理内存布局，需要在帧分配器初始化之前调用
+pub fn memory_layout(dtb_pa: PhysicalAddress) -> MemoryLayout {
+    device_tree::memory_layout(VirtualAddress::from(dtb_pa))
+}
+
+use alloc::sync::Arc;
 
-/// 驱动的接口
+/// 所有驱动共同的接口
+///
+/// 具体的功能由 [`BlockDriver`]、[`CharDriver`] 等 trait 提供，驱动以 [`Device`] 的形式注册
 pub trait Driver: Send + Sync {
-    /// 设备类型
-    fn device_type(&self) -> DeviceType;
+    /// 设备的名称、设备树路径、中断号等信息
+    fn info(&self) -> &DeviceInfo;
 
-    /// 读取某个块到 buf 中（块设备接口）
-    fn read_block(&self, _block_id: usize, _buf: &mut [u8]) -> bool {
-        unimplemented!("not a block driver")
-    }
+    /// 处理设备的中断，由 [`plic`] 在注册的中断到来时调用
+    fn handle_interrupt(&self) {}
+}
+
+/// 块设备
+pub trait BlockDriver: Driver {
+    /// 从第 `block_id` 个扇区开始读取到 buf 中，长度为扇区大小的整数倍
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> bool;
 
-    /// 将 buf 中的数据写入块中（块设备接口）
-    fn write_block(&self, _block_id: usize, _buf: &[u8]) -> bool {
-        unimplemented!("not a block driver")
+    /// 将 buf 中的数据从第 `block_id` 个扇区开始写入，长度为扇区大小的整数倍
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> bool;
+}
+
+/// 字符设备，例如串口
+pub trait CharDriver: Driver {
+    /// 读取一个字节，没有数据时返回 `None`
+    fn getchar(&self) -> Option<u8>;
+
+    /// 写入一个字节
+    fn putchar(&self, c: u8);
+
+    /// 写入一段数据
+    fn write(&self, data: &[u8]) {
+        data.iter().for_each(|&c| self.putchar(c));
     }
 }
 
-lazy_static! {
-    /// 所有驱动
-    pub static ref DRIVERS: RwLock<Vec<Arc<dyn Driver>>> = RwLock::new(Vec::new());
+/// 随机数发生器，产生的随机数放入 [`crate::fs::ENTROPY_POOL`]
+pub trait RngDriver: Driver {
+    /// 请求设备产生更多的随机数，设备完成后通过中断放入熵池
+    fn request(&self);
+}
+
+/// 网络设备，收发以太网帧
+pub trait NetDriver: Driver {
+    /// 网卡的 MAC 地址
+    fn mac_address(&self) -> [u8; 6];
+
+    /// 发送一个帧，返回是否成功
+    fn send(&self, frame: &[u8]) -> bool;
+
+    /// 接收一个帧到 buf 中，返回帧的长度，没有收到帧时返回 `None`
+    fn receive(&self, buf: &mut [u8]) -> Option<usize>;
+}
+
+/// 实时时钟
+pub trait RtcDriver: Driver {
+    /// 当前时间，自 Unix 纪元以来的纳秒数
+    fn read_time(&self) -> u64;
+}
+
+/// 注册到 [`registry`] 中的设备，按照所提供的功能分类
+#[derive(Clone)]
+pub enum Device {
+    Block(Arc<dyn BlockDriver>),
+    Char(Arc<dyn CharDriver>),
+    Net(Arc<dyn NetDriver>),
+    Rtc(Arc<dyn RtcDriver>),
+    Rng(Arc<dyn RngDriver>),
+}
+
+impl Device {
+    /// 设备的信息
+    pub fn info(&self) -> &DeviceInfo {
+        match self {
+            Device::Block(driver) => driver.info(),
+            Device::Char(driver) => driver.info(),
+            Device::Net(driver) => driver.info(),
+            Device::Rtc(driver) => driver.info(),
+            Device::Rng(driver) => driver.info(),
+        }
+    }
+
+    /// 处理设备的中断
+    pub fn handle_interrupt(&self) {
+        match self {
+            Device::Block(driver) => driver.handle_interrupt(),
+            Device::Char(driver) => driver.handle_interrupt(),
+            Device::Net(driver) => driver.handle_interrupt(),
+            Device::Rtc(driver) => driver.handle_interrupt(),
+            Device::Rng(driver) => driver.handle_interrupt(),
+        }
+    }
 }
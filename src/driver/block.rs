@@ -1,9 +1,16 @@
-use super::Driver;
+use super::BlockDriver;
 use alloc::sync::Arc;
 use rcore_fs::dev;
 
+/// 扇区大小，驱动读写的单位
+pub const SECTOR_SIZE: usize = 512;
+
+/// 每个块包含的扇区数
+const SECTORS_PER_BLOCK: usize =
+    (1 << <BlockDevice as dev::BlockDevice>::BLOCK_SIZE_LOG2) / SECTOR_SIZE;
+
 /// 块设备抽象（驱动的引用）
-pub struct BlockDevice(pub Arc<dyn Driver>);
+pub struct BlockDevice(pub Arc<dyn BlockDriver>);
 
 /// 为 [`BlockDevice`] 实现 [`rcore-fs`] 中 [`BlockDevice`] trait
 ///
@@ -11,12 +18,12 @@ pub struct BlockDevice(pub Arc<dyn Driver>);
 impl dev::BlockDevice for BlockDevice {
     /// 每个块的大小（取 2 的对数）
     ///
-    /// 这里取 512B 是因为 virtio 驱动对设备的操作粒度为 512B
-    const BLOCK_SIZE_LOG2: u8 = 9;
+    /// 取 4KB 和 SFS 的块大小一致，每个块由驱动一次读写多个扇区完成
+    const BLOCK_SIZE_LOG2: u8 = 12;
 
     /// 读取某个块到 buf 中
     fn read_at(&self, block_id: usize, buf: &mut [u8]) -> dev::Result<()> {
-        match self.0.read_block(block_id, buf) {
+        match self.0.read_block(block_id * SECTORS_PER_BLOCK, buf) {
             true => Ok(()),
             false => Err(dev::DevError),
         }
@@ -24,7 +31,7 @@ impl dev::BlockDevice for BlockDevice {
 
     /// 将 buf 中的数据写入块中
     fn write_at(&self, block_id: usize, buf: &[u8]) -> dev::Result<()> {
-        match self.0.write_block(block_id, buf) {
+        match self.0.write_block(block_id * SECTORS_PER_BLOCK, buf) {
             true => Ok(()),
             false => Err(dev::DevError),
         }
@@ -32,7 +39,7 @@ impl dev::BlockDevice for BlockDevice {
 
     /// 执行和设备的同步
     ///
-    /// 因为我们这里全部为阻塞 I/O 所以不存在同步的问题
+    /// 每个请求都会等待设备完成之后才返回，所以不存在同步的问题
     fn sync(&self) -> dev::Result<()> {
         Ok(())
     }
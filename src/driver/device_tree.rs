@@ -1,18 +1,74 @@
-use crate::mem::VirtualAddress;
+use crate::mem::{MemoryLayout, PhysicalAddress, VirtualAddress};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
 use core::slice;
-use device_tree::{DeviceTree, Node};
+use device_tree::{util::SliceRead, DeviceTree, Node};
 use riscv_sbi::println;
 
 const DEVICE_TREE_MAGIC: u32 = 0xd00d_feed;
 
-/// 递归遍历设备树
-fn walk(node: &Node) {
-    // 检查设备的协议支持并初始化
+/// 节点的 `reg` 属性中地址和长度各占几个 cell，由父节点的 `#address-cells` 和 `#size-cells` 决定
+#[derive(Clone, Copy, Debug)]
+pub struct RegCells {
+    pub address: usize,
+    pub size: usize,
+}
+
+impl RegCells {
+    /// 根节点的 `reg` 所使用的设置，没有父节点时使用规范中的默认值
+    const DEFAULT: Self = Self {
+        address: 2,
+        size: 1,
+    };
+
+    /// `node` 的子节点所使用的设置，`node` 没有给出时使用默认值
+    fn for_children(node: &Node) -> Self {
+        Self {
+            address: node
+                .prop_u32("#address-cells")
+                .map_or(Self::DEFAULT.address, |cells| cells as usize),
+            size: node
+                .prop_u32("#size-cells")
+                .map_or(Self::DEFAULT.size, |cells| cells as usize),
+        }
+    }
+}
+
+/// 按照 `compatible` 中的一项初始化设备，返回是否能够识别
+///
+/// `cells` 为读取节点的 `reg` 时使用的设置
+fn probe(compatible: &str, node: &Node, path: &str, cells: RegCells) -> bool {
+    match compatible {
+        "virtio,mmio" => super::virtio::virtio_probe(node, path, cells),
+        "ns16550a" => super::ns16550a::ns16550a_probe(node, path, cells),
+        "riscv,plic0" => super::plic::plic_probe(node, cells),
+        "pci-host-ecam-generic" => super::pci::pci_probe(node, path, cells),
+        "google,goldfish-rtc" => super::goldfish_rtc::goldfish_rtc_probe(node, path, cells),
+        "sifive,test0" | "sifive,test1" => super::syscon::syscon_probe(node, cells, true),
+        "syscon" => super::syscon::syscon_probe(node, cells, false),
+        "syscon-poweroff" => super::syscon::syscon_poweroff_probe(node),
+        "syscon-reboot" => super::syscon::syscon_reboot_probe(node),
+        _ => return false,
+    }
+    true
+}
+
+/// 递归遍历设备树，`path` 为节点在设备树中的路径，`cells` 来自父节点
+fn walk(node: &Node, path: &str, cells: RegCells) {
+    // 检查设备的协议支持并初始化。compatible 中可能有多项，以 \0 分隔，从最具体的开始尝试
     if let Ok(compatible) = node.prop_str("compatible") {
-        match compatible {
-            "virtio,mmio" => super::virtio::virtio_probe(node),
-            "ns16550a" => super::ns16550a::ns16550a_probe(node),
-            _ => {}
+        for compatible in compatible.split('\0') {
+            if probe(compatible, node, path, cells) {
+                break;
+            }
+        }
+    }
+    // 时钟频率记录在 /cpus 节点上
+    if path == "/cpus" {
+        if let Ok(frequency) = node.prop_u32("timebase-frequency") {
+            crate::time::set_timebase_frequency(frequency as u64);
         }
     }
     println!(
@@ -22,8 +78,14 @@ fn walk(node: &Node) {
     );
 
     // 遍历子树
+    let child_cells = RegCells::for_children(node);
     for child in node.children.iter() {
-        walk(child);
+        let child_path: String = if path == "/" {
+            format!("/{}", child.name)
+        } else {
+            format!("{}/{}", path, child.name)
+        };
+        walk(child, &child_path, child_cells);
     }
 }
 
@@ -33,17 +95,93 @@ struct DtbHeader {
     size: u32,
 }
 
-/// 遍历设备树并初始化设备
-pub fn init(dtb_va: VirtualAddress) {
+/// 验证设备树的 Header，返回设备树的数据
+fn dtb_data(dtb_va: VirtualAddress) -> Option<&'static [u8]> {
     let header = unsafe { &*(dtb_va.0 as *const DtbHeader) };
     // from_be 是大小端序的转换（from big endian）
     let magic = u32::from_be(header.magic);
     if magic == DEVICE_TREE_MAGIC {
         let size = u32::from_be(header.size);
-        // 拷贝数据，加载并遍历
-        let data = unsafe { slice::from_raw_parts(dtb_va.0 as *const u8, size as usize) };
+        Some(unsafe { slice::from_raw_parts(dtb_va.0 as *const u8, size as usize) })
+    } else {
+        None
+    }
+}
+
+/// 读取启动参数，然后遍历设备树并初始化设备
+pub fn init(dtb_va: VirtualAddress) {
+    // 拷贝数据，加载并遍历
+    if let Some(data) = dtb_data(dtb_va) {
         if let Ok(dt) = DeviceTree::load(data) {
-            walk(&dt.root);
+            let bootargs = dt
+                .root
+                .children
+                .iter()
+                .find(|node| node.name == "chosen")
+                .and_then(|chosen| chosen.prop_str("bootargs").ok())
+                .unwrap_or("");
+            super::BOOTARGS.call_once(|| bootargs.into());
+            walk(&dt.root, "/", RegCells::DEFAULT);
+        }
+    }
+}
+
+/// 从属性的 `offset` 字节处读取 `count` 个 32 位的 cell 组成的数，高位在前
+pub fn read_cells(data: &[u8], offset: usize, count: usize) -> usize {
+    (0..count).fold(0usize, |value, i| {
+        (value << 32) | data.read_be_u32(offset + i * 4).unwrap() as usize
+    })
+}
+
+/// 读取节点的 `reg` 属性，得到其中描述的所有地址区间
+///
+/// 每一项由 `cells.address` 个 32 位的地址和 `cells.size` 个 32 位的长度组成
+pub fn read_reg(node: &Node, cells: RegCells) -> Vec<Range<PhysicalAddress>> {
+    let reg = match node.prop_raw("reg") {
+        Some(reg) => reg.as_slice(),
+        _ => return Vec::new(),
+    };
+    let entry_size = (cells.address + cells.size) * 4;
+    if entry_size == 0 {
+        return Vec::new();
+    }
+    (0..reg.len() / entry_size)
+        .map(|i| {
+            let start = read_cells(reg, i * entry_size, cells.address);
+            let size = read_cells(reg, i * entry_size + cells.address * 4, cells.size);
+            PhysicalAddress(start)..PhysicalAddress(start + size)
+        })
+        .collect()
+}
+
+/// 节点的 `reg` 属性中的第一个地址区间，通常是设备的寄存器
+pub fn first_reg(node: &Node, cells: RegCells) -> Option<Range<PhysicalAddress>> {
+    read_reg(node, cells).into_iter().next()
+}
+
+/// 从设备树中读取物理内存布局
+///
+/// 包括所有 `device_type = "memory"` 的节点描述的内存条，
+/// 以及 `/reserved-memory` 中的保留区域和设备树本身所占的空间
+pub fn memory_layout(dtb_va: VirtualAddress) -> MemoryLayout {
+    let data = dtb_data(dtb_va).expect("invalid device tree magic");
+    let dt = DeviceTree::load(data).expect("failed to load device tree");
+    let cells = RegCells::for_children(&dt.root);
+
+    let mut layout = MemoryLayout::default();
+    for node in dt.root.children.iter() {
+        if let Ok("memory") = node.prop_str("device_type") {
+            layout.banks.extend(read_reg(node, cells));
+        } else if node.name == "reserved-memory" {
+            // 保留区域的子节点使用自己的 cells 设置
+            let cells = RegCells::for_children(node);
+            for child in node.children.iter() {
+                layout.reserved.extend(read_reg(child, cells));
+            }
         }
     }
+    // 设备树会在之后初始化驱动时再次读取，不能被分配出去
+    let dtb_pa = PhysicalAddress::from(dtb_va);
+    layout.reserved.push(dtb_pa..dtb_pa + data.len());
+    layout
 }
@@ -0,0 +1,78 @@
+//! QEMU virt 平台上的 goldfish 实时时钟驱动
+
+use super::device_tree::{first_reg, RegCells};
+use super::{registry, Device, DeviceInfo, Driver, RtcDriver};
+use crate::mem::ioremap;
+use alloc::format;
+use alloc::sync::Arc;
+use device_tree::Node;
+use riscv_sbi::println;
+
+/// 当前时间的低 32 位，读取它时设备会锁存高 32 位
+const TIME_LOW: usize = 0x00;
+/// 当前时间的高 32 位
+const TIME_HIGH: usize = 0x04;
+
+/// goldfish 实时时钟，时间以自 Unix 纪元以来的纳秒数表示
+struct GoldfishRtc {
+    info: DeviceInfo,
+    /// 寄存器映射后的虚拟地址
+    base: usize,
+}
+
+impl GoldfishRtc {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+}
+
+impl Driver for GoldfishRtc {
+    fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+}
+
+impl RtcDriver for GoldfishRtc {
+    /// 必须先读低 32 位，再读高 32 位
+    fn read_time(&self) -> u64 {
+        let low = self.read(TIME_LOW) as u64;
+        let high = self.read(TIME_HIGH) as u64;
+        high << 32 | low
+    }
+}
+
+/// 从设备树节点初始化实时时钟，以 `rtc0` 等名称登记到 [`registry`] 中
+///
+/// 不使用设备的闹钟和中断
+pub fn goldfish_rtc_probe(node: &Node, path: &str, cells: RegCells) {
+    let reg = match first_reg(node, cells) {
+        Some(reg) => reg,
+        _ => return,
+    };
+    let va = match ioremap(reg.clone()) {
+        Ok(va) => va,
+        Err(message) => {
+            println!(
+                "failed to map goldfish-rtc at {:x?}: {}",
+                reg.start, message
+            );
+            return;
+        }
+    };
+    let name = format!("rtc{}", registry::rtc_devices().len());
+    let info = DeviceInfo::from_node(name, path, node, cells);
+    let rtc = GoldfishRtc {
+        info: DeviceInfo {
+            irqs: Default::default(),
+            ..info
+        },
+        base: va.0,
+    };
+    println!(
+        "goldfish-rtc {} at {}: {} ns",
+        rtc.info.name,
+        path,
+        rtc.read_time()
+    );
+    registry::register(Device::Rtc(Arc::new(rtc)));
+}
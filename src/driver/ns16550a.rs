@@ -1,22 +1,149 @@
-#![allow(unused)]
-
-use device_tree::{util::SliceRead, Node};
-use volatile::Volatile;
-
-#[repr(C)]
-#[derive(Debug)]
-struct Ns16550a {
-    rbr_thr_dll: Volatile<u16>,
-    dlh_ier: Volatile<u16>,
-    iir_fcr: Volatile<u16>,
-    lcr: Volatile<u16>,
-    mcr: Volatile<u16>,
-    lsr: Volatile<u16>,
-    msr: Volatile<u16>,
-    spr: Volatile<u16>,
+//! ns16550a 串口驱动
+//!
+//! 从设备树中读取寄存器的位置并初始化，开启接收中断，收到的字符放入 [`STDIN`]
+
+use super::device_tree::{first_reg, RegCells};
+use super::{registry, CharDriver, Device, DeviceInfo, Driver};
+use crate::fs::STDIN;
+use crate::mem::ioremap;
+use alloc::sync::Arc;
+use device_tree::Node;
+use riscv_sbi::println;
+use spin::Once;
+
+/// 接收缓冲 / 发送保持寄存器，设置 DLAB 时为除数的低 8 位
+const RBR_THR_DLL: usize = 0;
+/// 中断使能寄存器，设置 DLAB 时为除数的高 8 位
+const IER_DLM: usize = 1;
+/// FIFO 控制寄存器
+const FCR: usize = 2;
+/// 线路控制寄存器
+const LCR: usize = 3;
+/// Modem 控制寄存器
+const MCR: usize = 4;
+/// 线路状态寄存器
+const LSR: usize = 5;
+
+/// 允许访问除数寄存器
+const LCR_DLAB: u8 = 1 << 7;
+/// 8 位数据、无校验、1 位停止位
+const LCR_8N1: u8 = 0b11;
+/// 开启并清空收发 FIFO
+const FCR_ENABLE_CLEAR: u8 = 0b111;
+/// DTR、RTS，以及用于连接中断线的 OUT2
+const MCR_DTR_RTS_OUT2: u8 = 0b1011;
+/// 接收到数据时产生中断
+const IER_RX_AVAILABLE: u8 = 1;
+/// 接收缓冲中有数据
+const LSR_DATA_READY: u8 = 1;
+/// 发送保持寄存器为空
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// 波特率
+const BAUD_RATE: u32 = 115200;
+
+/// 系统中的串口，只支持一个
+pub static UART: Once<Arc<Ns16550a>> = Once::new();
+
+/// ns16550a 兼容的串口
+///
+/// 寄存器访问都是单个字节的 volatile 读写，因此可以在多个核上同时使用
+pub struct Ns16550a {
+    info: DeviceInfo,
+    /// 寄存器映射后的虚拟地址
+    base: usize,
+    /// 寄存器之间的间隔为 `1 << reg_shift` 字节
+    reg_shift: usize,
+}
+
+impl Ns16550a {
+    fn read(&self, register: usize) -> u8 {
+        unsafe { ((self.base + (register << self.reg_shift)) as *const u8).read_volatile() }
+    }
+
+    fn write(&self, register: usize, value: u8) {
+        unsafe { ((self.base + (register << self.reg_shift)) as *mut u8).write_volatile(value) }
+    }
+
+    /// 设置波特率和 FIFO，开启接收中断
+    ///
+    /// 不知道时钟频率时保留固件设置的波特率
+    fn init(&self, clock_frequency: Option<u32>) {
+        // 关闭所有中断
+        self.write(IER_DLM, 0);
+        if let Some(clock_frequency) = clock_frequency {
+            let divisor = (clock_frequency / (16 * BAUD_RATE)).max(1);
+            self.write(LCR, LCR_DLAB);
+            self.write(RBR_THR_DLL, divisor as u8);
+            self.write(IER_DLM, (divisor >> 8) as u8);
+        }
+        self.write(LCR, LCR_8N1);
+        self.write(FCR, FCR_ENABLE_CLEAR);
+        self.write(MCR, MCR_DTR_RTS_OUT2);
+        self.write(IER_DLM, IER_RX_AVAILABLE);
+    }
+}
+
+impl Driver for Ns16550a {
+    fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// 处理接收中断，将收到的所有字符放入 [`STDIN`]
+    ///
+    /// 启动参数指定了其他控制台设备时，串口的输入被丢弃
+    fn handle_interrupt(&self) {
+        let is_console =
+            crate::driver::console_device().map_or(true, |name| name == self.info.name);
+        while let Some(c) = self.getchar() {
+            if is_console {
+                STDIN.push(c);
+            }
+        }
+    }
+}
+
+impl CharDriver for Ns16550a {
+    /// 读取一个字节，没有数据时返回 `None`
+    fn getchar(&self) -> Option<u8> {
+        if self.read(LSR) & LSR_DATA_READY != 0 {
+            Some(self.read(RBR_THR_DLL))
+        } else {
+            None
+        }
+    }
+
+    /// 发送一个字节，发送缓冲满时等待
+    fn putchar(&self, c: u8) {
+        while self.read(LSR) & LSR_THR_EMPTY == 0 {}
+        self.write(RBR_THR_DLL, c);
+    }
 }
 
-pub fn ns16550a_probe(node: &Node) {
-    // todo!!!
-    // riscv_sbi::println!("{:#?}", node);
+/// 从设备树节点初始化串口，登记为 `ttyS0`
+pub fn ns16550a_probe(node: &Node, path: &str, cells: RegCells) {
+    if UART.r#try().is_some() {
+        println!("ignoring extra ns16550a: {}", node.name);
+        return;
+    }
+    // reg 属性中包含了寄存器的物理地址和长度
+    let reg = match first_reg(node, cells) {
+        Some(reg) => reg,
+        _ => return,
+    };
+    let va = match ioremap(reg.clone()) {
+        Ok(va) => va,
+        Err(message) => {
+            println!("failed to map ns16550a at {:x?}: {}", reg.start, message);
+            return;
+        }
+    };
+    let uart = Ns16550a {
+        info: DeviceInfo::from_node("ttyS0".into(), path, node, cells),
+        base: va.0,
+        reg_shift: node.prop_u32("reg-shift").unwrap_or(0) as usize,
+    };
+    uart.init(node.prop_u32("clock-frequency").ok());
+    let uart = UART.call_once(|| Arc::new(uart));
+    registry::register(Device::Char(uart.clone()));
 }
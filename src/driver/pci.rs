@@ -0,0 +1,308 @@
+//! PCI 主桥（`pci-host-ecam-generic`）驱动
+//!
+//! 通过 ECAM 访问配置空间，扫描 0 号总线上的所有设备，从主桥的 `ranges` 中为设备的 BAR 分配地址，
+//! 并按照 `interrupt-map` 找到 INTx 中断在 PLIC 上的中断号。目前只有 virtio 设备会被初始化
+
+use super::device_tree::{first_reg, read_cells, RegCells};
+use super::{virtio, DeviceInfo};
+use crate::mem::{ioremap, PhysicalAddress, VirtualAddress};
+use alloc::format;
+use alloc::vec::Vec;
+use core::ops::Range;
+use device_tree::{util::SliceRead, Node};
+use riscv_sbi::println;
+
+/// 每条总线的配置空间大小
+const BUS_CONFIG_SIZE: usize = 1 << 20;
+
+const CONFIG_VENDOR_ID: usize = 0x00;
+const CONFIG_DEVICE_ID: usize = 0x02;
+const CONFIG_COMMAND: usize = 0x04;
+const CONFIG_HEADER_TYPE: usize = 0x0e;
+const CONFIG_BAR0: usize = 0x10;
+const CONFIG_INTERRUPT_PIN: usize = 0x3d;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// 多功能设备的标志，在 header type 的最高位
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+
+const VENDOR_VIRTIO: u16 = 0x1af4;
+
+/// `ranges` 中 phys.hi 的第 24、25 位表示地址空间的类型
+const SPACE_IO: u32 = 0b01;
+const SPACE_MEMORY_32: u32 = 0b10;
+
+/// 一段可以分配给 BAR 的地址窗口
+struct Window {
+    /// PCI 总线上的起始地址
+    pci_start: usize,
+    /// CPU 看到的物理地址
+    cpu_start: PhysicalAddress,
+    size: usize,
+    /// 已经分配出去的长度
+    used: usize,
+}
+
+impl Window {
+    /// 分配一段按照 `size` 对齐的地址，返回 PCI 总线上的地址
+    fn alloc(&mut self, size: usize) -> Option<usize> {
+        let start = (self.pci_start + self.used + size - 1) / size * size;
+        if start + size > self.pci_start + self.size {
+            return None;
+        }
+        self.used = start + size - self.pci_start;
+        Some(start)
+    }
+
+    /// PCI 总线上的地址对应的物理地址
+    fn to_cpu(&self, pci_address: usize) -> PhysicalAddress {
+        self.cpu_start + (pci_address - self.pci_start)
+    }
+}
+
+/// 一个 PCI 主桥
+struct HostBridge<'a> {
+    node: &'a Node,
+    path: &'a str,
+    /// 0 号总线的配置空间映射后的虚拟地址
+    config: usize,
+    io: Option<Window>,
+    memory: Option<Window>,
+}
+
+impl HostBridge<'_> {
+    fn config_address(&self, device: usize, function: usize, offset: usize) -> usize {
+        self.config + (device << 15 | function << 12 | offset)
+    }
+
+    fn read<T>(&self, device: usize, function: usize, offset: usize) -> T {
+        let address = self.config_address(device, function, offset);
+        unsafe { (address as *const T).read_volatile() }
+    }
+
+    fn write<T>(&self, device: usize, function: usize, offset: usize, value: T) {
+        let address = self.config_address(device, function, offset);
+        unsafe { (address as *mut T).write_volatile(value) }
+    }
+
+    /// 扫描 0 号总线上的所有设备和功能
+    fn scan(&mut self) {
+        for device in 0..32 {
+            if self.read::<u16>(device, 0, CONFIG_VENDOR_ID) == 0xffff {
+                continue;
+            }
+            let functions =
+                if self.read::<u8>(device, 0, CONFIG_HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {
+                    8
+                } else {
+                    1
+                };
+            for function in 0..functions {
+                if self.read::<u16>(device, function, CONFIG_VENDOR_ID) != 0xffff {
+                    self.probe(device, function);
+                }
+            }
+        }
+    }
+
+    /// 初始化一个功能：分配 BAR，开启访问，然后交给对应的驱动
+    fn probe(&mut self, device: usize, function: usize) {
+        let vendor_id = self.read::<u16>(device, function, CONFIG_VENDOR_ID);
+        let device_id = self.read::<u16>(device, function, CONFIG_DEVICE_ID);
+        let path = format!("{}/00:{:02x}.{}", self.path, device, function);
+        println!(
+            "pci {}: vendor {:#06x}, device {:#06x}",
+            path, vendor_id, device_id
+        );
+
+        let bars = self.assign_bars(device, function);
+        let command = self.read::<u16>(device, function, CONFIG_COMMAND);
+        self.write(
+            device,
+            function,
+            CONFIG_COMMAND,
+            command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER,
+        );
+
+        let irqs = self.interrupt(device, function).into_iter().collect();
+        if vendor_id == VENDOR_VIRTIO {
+            // legacy 接口的寄存器在 BAR0 中
+            let bar0 = match bars.get(0).cloned().flatten() {
+                Some(bar0) => bar0,
+                None => {
+                    println!("virtio-pci {} has no BAR0", path);
+                    return;
+                }
+            };
+            let io_base = match ioremap(bar0.clone()) {
+                Ok(io_base) => io_base,
+                Err(message) => {
+                    println!("failed to map BAR0 of {}: {}", path, message);
+                    return;
+                }
+            };
+            let info = DeviceInfo {
+                name: Default::default(),
+                path,
+                irqs,
+                mmio: Some(bar0),
+            };
+            virtio::virtio_pci_probe(device_id, io_base, info);
+        }
+    }
+
+    /// 为设备的 BAR 分配地址，返回每个 BAR 对应的物理地址区间
+    ///
+    /// 64 位的 BAR 占用两项，第二项为 `None`；只从 32 位的窗口中分配
+    fn assign_bars(
+        &mut self,
+        device: usize,
+        function: usize,
+    ) -> Vec<Option<Range<PhysicalAddress>>> {
+        let mut bars = Vec::new();
+        let mut index = 0;
+        while index < 6 {
+            let offset = CONFIG_BAR0 + index * 4;
+            let original = self.read::<u32>(device, function, offset);
+            self.write(device, function, offset, !0u32);
+            let mask = self.read::<u32>(device, function, offset);
+            let is_io = original & 1 != 0;
+            let is_64bit = !is_io && (original >> 1) & 0b11 == 0b10;
+            if is_64bit {
+                self.write(device, function, offset + 4, !0u32);
+            }
+            let size_mask = if is_io { mask & !0b11 } else { mask & !0b1111 };
+            let range = if size_mask == 0 {
+                self.write(device, function, offset, original);
+                None
+            } else {
+                let size = (!size_mask).wrapping_add(1) as usize;
+                let window = if is_io {
+                    self.io.as_mut()
+                } else {
+                    self.memory.as_mut()
+                };
+                let allocated = window.and_then(|window| {
+                    let pci_address = window.alloc(size)?;
+                    Some((pci_address, window.to_cpu(pci_address)))
+                });
+                match allocated {
+                    Some((pci_address, start)) => {
+                        self.write(device, function, offset, pci_address as u32);
+                        Some(start..start + size)
+                    }
+                    None => {
+                        println!(
+                            "no space for BAR{} of 00:{:02x}.{}",
+                            index, device, function
+                        );
+                        self.write(device, function, offset, original);
+                        None
+                    }
+                }
+            };
+            if is_64bit {
+                self.write(device, function, offset + 4, 0u32);
+            }
+            bars.push(range);
+            index += 1;
+            if is_64bit {
+                bars.push(None);
+                index += 1;
+            }
+        }
+        bars
+    }
+
+    /// 设备的 INTx 中断在 PLIC 上的中断号
+    ///
+    /// 按照 `interrupt-map` 查找，每一项为 3 个 cell 的 PCI 地址、1 个 cell 的引脚、
+    /// PLIC 的 phandle 和 1 个 cell 的中断号。没有 `interrupt-map` 时按照 QEMU virt 平台的规则计算
+    fn interrupt(&self, device: usize, function: usize) -> Option<u32> {
+        let pin = self.read::<u8>(device, function, CONFIG_INTERRUPT_PIN) as u32;
+        if pin == 0 {
+            return None;
+        }
+        let address = (device << 11 | function << 8) as u32;
+        let map = match self.node.prop_raw("interrupt-map") {
+            Some(map) => map.as_slice(),
+            None => return Some(32 + (device as u32 + pin - 1) % 4),
+        };
+        let map_mask = self.node.prop_raw("interrupt-map-mask");
+        let mask = |cell: usize| {
+            map_mask.map_or(!0, |map_mask| {
+                map_mask.as_slice().read_be_u32(cell * 4).unwrap_or(!0)
+            })
+        };
+        (0..map.len() / 24).find_map(|i| {
+            let entry = |cell: usize| map.read_be_u32(i * 24 + cell * 4).unwrap();
+            if entry(0) == address & mask(0) && entry(3) == pin & mask(3) {
+                Some(entry(5))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// 读取 `ranges` 中的 I/O 和 32 位内存窗口
+///
+/// 每一项为 3 个 cell 的 PCI 地址、按照父节点 `cells.address` 个 cell 的 CPU 地址，
+/// 以及按照主桥自己的 `#size-cells`（默认 2）个 cell 的长度
+fn read_windows(node: &Node, cells: RegCells) -> (Option<Window>, Option<Window>) {
+    let ranges = match node.prop_raw("ranges") {
+        Some(ranges) => ranges.as_slice(),
+        None => return (None, None),
+    };
+    let size_cells = node.prop_u32("#size-cells").unwrap_or(2) as usize;
+    let entry_size = (3 + cells.address + size_cells) * 4;
+    let (mut io, mut memory) = (None, None);
+    for i in 0..ranges.len() / entry_size {
+        let offset = i * entry_size;
+        let space = (ranges.read_be_u32(offset).unwrap() >> 24) & 0b11;
+        let window = Window {
+            pci_start: read_cells(ranges, offset + 4, 2),
+            cpu_start: PhysicalAddress(read_cells(ranges, offset + 12, cells.address)),
+            size: read_cells(ranges, offset + (3 + cells.address) * 4, size_cells),
+            used: 0,
+        };
+        match space {
+            SPACE_IO => io = Some(window),
+            SPACE_MEMORY_32 => memory = Some(window),
+            _ => {}
+        }
+    }
+    // 地址 0 不能分配给 BAR
+    if let Some(io) = io.as_mut() {
+        io.used = io.used.max(0x1000);
+    }
+    (io, memory)
+}
+
+/// 从设备树节点初始化 PCI 主桥，并初始化 0 号总线上的设备
+pub fn pci_probe(node: &Node, path: &str, cells: RegCells) {
+    // 只扫描 0 号总线，只需要映射它的配置空间
+    let pa = match first_reg(node, cells) {
+        Some(reg) => reg.start,
+        _ => return,
+    };
+    let config: VirtualAddress = match ioremap(pa..pa + BUS_CONFIG_SIZE) {
+        Ok(config) => config,
+        Err(message) => {
+            println!("failed to map pci config space at {:x?}: {}", pa, message);
+            return;
+        }
+    };
+    let (io, memory) = read_windows(node, cells);
+    let mut bridge = HostBridge {
+        node,
+        path,
+        config: config.0,
+        io,
+        memory,
+    };
+    bridge.scan();
+}
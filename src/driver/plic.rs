@@ -0,0 +1,167 @@
+//! PLIC（Platform-Level Interrupt Controller）驱动
+//!
+//! 设备的中断经过 PLIC 分发给各个核的 S 态外部中断。设备登记时按照 [`super::DeviceInfo::irqs`] 注册中断号，
+//! 每个核在初始化时为自己的 context 开启所有已经注册的中断
+
+use super::device_tree::{first_reg, RegCells};
+use super::Device;
+use crate::mem::ioremap;
+use crate::process::hart_id;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use device_tree::{util::SliceRead, Node};
+use lazy_static::lazy_static;
+use riscv::register::sie;
+use riscv_sbi::println;
+use spin::{Once, RwLock};
+
+/// 每个中断源的优先级寄存器
+const PRIORITY_OFFSET: usize = 0;
+/// 每个 context 的中断使能位
+const ENABLE_OFFSET: usize = 0x2000;
+/// 相邻 context 的中断使能位之间的间隔
+const ENABLE_STRIDE: usize = 0x80;
+/// 每个 context 的优先级阈值寄存器，后面紧跟 claim / complete 寄存器
+const CONTEXT_OFFSET: usize = 0x20_0000;
+/// 相邻 context 的阈值寄存器之间的间隔
+const CONTEXT_STRIDE: usize = 0x1000;
+
+/// `interrupts-extended` 中表示 S 态外部中断的编号
+const SUPERVISOR_EXTERNAL: u32 = 9;
+
+/// 系统中的 PLIC，只支持一个
+static PLIC: Once<Plic> = Once::new();
+
+lazy_static! {
+    /// 中断号到设备的映射，PCI 设备的 INTx 中断可能由多个设备共享
+    static ref HANDLERS: RwLock<BTreeMap<u32, Vec<Device>>> = RwLock::new(BTreeMap::new());
+}
+
+/// PLIC 的寄存器
+struct Plic {
+    /// 寄存器映射后的虚拟地址
+    base: usize,
+    /// 第 `i` 个核 S 态所对应的 context 编号
+    contexts: Vec<usize>,
+}
+
+impl Plic {
+    fn register(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    /// 当前核 S 态的 context
+    fn current_context(&self) -> usize {
+        let hart = hart_id();
+        self.contexts.get(hart).copied().unwrap_or(hart * 2 + 1)
+    }
+
+    fn set_priority(&self, irq: u32, priority: u32) {
+        let register = self.register(PRIORITY_OFFSET + irq as usize * 4);
+        unsafe { register.write_volatile(priority) }
+    }
+
+    fn enable(&self, context: usize, irq: u32) {
+        let register =
+            self.register(ENABLE_OFFSET + context * ENABLE_STRIDE + (irq as usize / 32) * 4);
+        unsafe { register.write_volatile(register.read_volatile() | 1 << (irq % 32)) }
+    }
+
+    fn set_threshold(&self, context: usize, threshold: u32) {
+        let register = self.register(CONTEXT_OFFSET + context * CONTEXT_STRIDE);
+        unsafe { register.write_volatile(threshold) }
+    }
+
+    /// 取得一个待处理的中断，没有时返回 0
+    fn claim(&self, context: usize) -> u32 {
+        let register = self.register(CONTEXT_OFFSET + context * CONTEXT_STRIDE + 4);
+        unsafe { register.read_volatile() }
+    }
+
+    /// 通知 PLIC 中断已经处理完成
+    fn complete(&self, context: usize, irq: u32) {
+        let register = self.register(CONTEXT_OFFSET + context * CONTEXT_STRIDE + 4);
+        unsafe { register.write_volatile(irq) }
+    }
+}
+
+/// 从设备树节点初始化 PLIC
+pub fn plic_probe(node: &Node, cells: RegCells) {
+    let reg = match first_reg(node, cells) {
+        Some(reg) => reg,
+        _ => return,
+    };
+    let va = match ioremap(reg.clone()) {
+        Ok(va) => va,
+        Err(message) => {
+            println!("failed to map plic at {:x?}: {}", reg.start, message);
+            return;
+        }
+    };
+    // interrupts-extended 中每一项是 (phandle, 中断编号)，第 i 项对应第 i 个 context，
+    // 各个核的 S 态 context 按照核的顺序排列
+    let contexts = node
+        .prop_raw("interrupts-extended")
+        .map(|property| {
+            let property = property.as_slice();
+            (0..property.len() / 8)
+                .filter(|&i| property.read_be_u32(i * 8 + 4).unwrap() == SUPERVISOR_EXTERNAL)
+                .collect()
+        })
+        .unwrap_or_default();
+    PLIC.call_once(|| Plic {
+        base: va.0,
+        contexts,
+    });
+}
+
+/// 系统中是否有 PLIC，没有时设备的中断不会到来，驱动只能轮询
+pub fn available() -> bool {
+    PLIC.r#try().is_some()
+}
+
+/// 由设备处理中断号为 `irq` 的中断，由 [`super::registry::register`] 调用
+///
+/// 共享中断时每个设备都会被调用，驱动需要自己判断中断是否来自自己的设备
+pub fn register_handler(irq: u32, device: Device) {
+    HANDLERS.write().entry(irq).or_default().push(device);
+}
+
+/// 为当前核开启所有已经注册的中断，并开启 S 态外部中断
+///
+/// 需要在所有驱动初始化之后调用
+pub fn init_hart() {
+    let plic = match PLIC.r#try() {
+        Some(plic) => plic,
+        None => return,
+    };
+    let context = plic.current_context();
+    for &irq in HANDLERS.read().keys() {
+        plic.set_priority(irq, 1);
+        plic.enable(context, irq);
+    }
+    plic.set_threshold(context, 0);
+    unsafe { sie::set_sext() };
+}
+
+/// 处理外部中断：取得所有待处理的中断，分发给对应的驱动
+pub fn handle_interrupt() {
+    let plic = match PLIC.r#try() {
+        Some(plic) => plic,
+        None => return,
+    };
+    let context = plic.current_context();
+    loop {
+        let irq = plic.claim(context);
+        if irq == 0 {
+            break;
+        }
+        // 驱动在处理时可能会唤醒线程，不能持有 HANDLERS 的锁
+        let devices = HANDLERS.read().get(&irq).cloned();
+        match devices {
+            Some(devices) => devices.iter().for_each(Device::handle_interrupt),
+            None => println!("unhandled external interrupt {}", irq),
+        }
+        plic.complete(context, irq);
+    }
+}
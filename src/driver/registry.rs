@@ -0,0 +1,146 @@
+//! 已经初始化的设备 [`Device`] 的登记
+//!
+//! 设备可以按照类别枚举，也可以按照名称或设备树路径查找
+
+use super::device_tree::{first_reg, RegCells};
+use super::{plic, BlockDriver, CharDriver, Device, NetDriver, RngDriver, RtcDriver};
+use crate::mem::PhysicalAddress;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use device_tree::{util::SliceRead, Node};
+use lazy_static::lazy_static;
+use spin::RwLock;
+
+/// 设备的基本信息
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    /// 设备的名称，例如 `vda`、`ttyS0`
+    pub name: String,
+    /// 设备在设备树中的路径，例如 `/soc/virtio_mmio@10008000`
+    pub path: String,
+    /// 设备的中断号
+    pub irqs: Vec<u32>,
+    /// 设备寄存器所在的物理地址区间
+    pub mmio: Option<Range<PhysicalAddress>>,
+}
+
+impl DeviceInfo {
+    /// 从设备树节点读取中断号和寄存器区间
+    ///
+    /// 寄存器区间为 `reg` 中的第一项，按照 `cells` 读取；假设每个中断占一个 cell
+    pub(super) fn from_node(name: String, path: &str, node: &Node, cells: RegCells) -> Self {
+        let mmio = first_reg(node, cells);
+        let irqs = node
+            .prop_raw("interrupts")
+            .map(|interrupts| {
+                let interrupts = interrupts.as_slice();
+                (0..interrupts.len() / 4)
+                    .map(|i| interrupts.read_be_u32(i * 4).unwrap())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            name,
+            path: path.into(),
+            irqs,
+            mmio,
+        }
+    }
+}
+
+lazy_static! {
+    /// 所有设备，按照注册的顺序排列
+    static ref DEVICES: RwLock<Vec<Device>> = RwLock::new(Vec::new());
+}
+
+/// 登记设备，并为它注册 [`DeviceInfo::irqs`] 中的中断
+pub fn register(device: Device) {
+    for &irq in device.info().irqs.iter() {
+        plic::register_handler(irq, device.clone());
+    }
+    DEVICES.write().push(device);
+}
+
+/// 所有设备
+pub fn devices() -> Vec<Device> {
+    DEVICES.read().clone()
+}
+
+/// 按照名称或者设备树路径查找设备
+pub fn find(name_or_path: &str) -> Option<Device> {
+    DEVICES
+        .read()
+        .iter()
+        .find(|device| device.info().name == name_or_path || device.info().path == name_or_path)
+        .cloned()
+}
+
+/// 所有块设备
+pub fn block_devices() -> Vec<Arc<dyn BlockDriver>> {
+    DEVICES
+        .read()
+        .iter()
+        .filter_map(|device| match device {
+            Device::Block(driver) => Some(driver.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// 所有字符设备
+pub fn char_devices() -> Vec<Arc<dyn CharDriver>> {
+    DEVICES
+        .read()
+        .iter()
+        .filter_map(|device| match device {
+            Device::Char(driver) => Some(driver.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// 所有网络设备
+pub fn net_devices() -> Vec<Arc<dyn NetDriver>> {
+    DEVICES
+        .read()
+        .iter()
+        .filter_map(|device| match device {
+            Device::Net(driver) => Some(driver.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// 所有实时时钟
+pub fn rtc_devices() -> Vec<Arc<dyn RtcDriver>> {
+    DEVICES
+        .read()
+        .iter()
+        .filter_map(|device| match device {
+            Device::Rtc(driver) => Some(driver.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// 所有随机数发生器
+pub fn rng_devices() -> Vec<Arc<dyn RngDriver>> {
+    DEVICES
+        .read()
+        .iter()
+        .filter_map(|device| match device {
+            Device::Rng(driver) => Some(driver.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// 启动参数指定的控制台设备
+pub fn console() -> Option<Arc<dyn CharDriver>> {
+    match find(super::console_device()?) {
+        Some(Device::Char(driver)) => Some(driver),
+        _ => None,
+    }
+}
@@ -0,0 +1,163 @@
+//! 通过 syscon 寄存器关机和重启
+//!
+//! 设备树中的 `syscon-poweroff` 和 `syscon-reboot` 节点通过 `regmap` 指向一个 syscon 设备，
+//! 向其中 `offset` 处的寄存器写入 `value` 即可关机或重启。QEMU virt 平台上的 syscon 是
+//! SiFive 的测试设备（`sifive,test0`），关机时还可以带上退出码，QEMU 会以它作为自己的退出状态
+
+use super::device_tree::{first_reg, RegCells};
+use crate::mem::ioremap;
+use crate::sbi::{self, ResetReason, ResetType};
+use alloc::collections::BTreeMap;
+use device_tree::Node;
+use lazy_static::lazy_static;
+use riscv_sbi::println;
+use spin::{Once, RwLock};
+
+/// SiFive 测试设备：成功退出
+const SIFIVE_TEST_PASS: u32 = 0x5555;
+/// SiFive 测试设备：失败退出，退出码放在高 16 位
+const SIFIVE_TEST_FAIL: u32 = 0x3333;
+
+/// 一个 syscon 设备
+#[derive(Clone, Copy)]
+struct Syscon {
+    /// 寄存器映射后的虚拟地址
+    base: usize,
+    /// 是否为 SiFive 的测试设备，可以报告退出码
+    sifive_test: bool,
+}
+
+/// 关机或重启时写入的寄存器
+#[derive(Clone, Copy)]
+struct SysconAction {
+    /// 所使用 syscon 的 phandle
+    regmap: u32,
+    offset: usize,
+    value: u32,
+    mask: u32,
+}
+
+lazy_static! {
+    /// 所有 syscon 设备，以 phandle 为键
+    static ref SYSCONS: RwLock<BTreeMap<u32, Syscon>> = RwLock::new(BTreeMap::new());
+}
+
+static POWEROFF: Once<SysconAction> = Once::new();
+static REBOOT: Once<SysconAction> = Once::new();
+
+/// 记录一个 syscon 设备，`sifive_test` 表示它是否为 SiFive 的测试设备
+pub fn syscon_probe(node: &Node, cells: RegCells, sifive_test: bool) {
+    let phandle = match node.prop_u32("phandle") {
+        Ok(phandle) => phandle,
+        // 没有被引用的 syscon 用不到
+        Err(_) => return,
+    };
+    let reg = match first_reg(node, cells) {
+        Some(reg) => reg,
+        _ => return,
+    };
+    match ioremap(reg.clone()) {
+        Ok(va) => {
+            SYSCONS.write().insert(
+                phandle,
+                Syscon {
+                    base: va.0,
+                    sifive_test,
+                },
+            );
+        }
+        Err(message) => println!("failed to map syscon at {:x?}: {}", reg.start, message),
+    }
+}
+
+/// 读取 `syscon-poweroff` / `syscon-reboot` 节点
+///
+/// 所引用的 syscon 可能在设备树中更靠后的位置，到真正使用时才查找
+fn read_action(node: &Node) -> Option<SysconAction> {
+    let regmap = node.prop_u32("regmap").ok()?;
+    let offset = node.prop_u32("offset").ok()? as usize;
+    let mask = node.prop_u32("mask").unwrap_or(!0);
+    // 按照规范，没有 value 时使用 mask 作为写入的值
+    let value = node.prop_u32("value").unwrap_or(mask);
+    Some(SysconAction {
+        regmap,
+        offset,
+        value,
+        mask,
+    })
+}
+
+/// 从设备树节点读取关机的方式
+pub fn syscon_poweroff_probe(node: &Node) {
+    if let Some(action) = read_action(node) {
+        POWEROFF.call_once(|| action);
+    }
+}
+
+/// 从设备树节点读取重启的方式
+pub fn syscon_reboot_probe(node: &Node) {
+    if let Some(action) = read_action(node) {
+        REBOOT.call_once(|| action);
+    }
+}
+
+/// 执行关机或重启。`code` 非 0 且 syscon 为 SiFive 测试设备时以失败状态退出
+///
+/// 找不到对应的 syscon 时返回
+fn perform(action: &SysconAction, code: u16) {
+    let syscon = match SYSCONS.read().get(&action.regmap) {
+        Some(&syscon) => syscon,
+        None => return,
+    };
+    let value = if syscon.sifive_test && code != 0 && action.value == SIFIVE_TEST_PASS {
+        (code as u32) << 16 | SIFIVE_TEST_FAIL
+    } else {
+        action.value
+    };
+    let register = (syscon.base + action.offset) as *mut u32;
+    unsafe {
+        // 只修改 mask 中的位
+        let old = register.read_volatile();
+        let mask = if value == action.value {
+            action.mask
+        } else {
+            !0
+        };
+        register.write_volatile((old & !mask) | (value & mask));
+    }
+}
+
+/// 把退出码截断为 16 位，低 16 位为 0 的非 0 退出码变为 0xffff，仍然表示失败
+fn exit_code_u16(code: usize) -> u16 {
+    match code as u16 {
+        0 if code != 0 => u16::MAX,
+        code => code,
+    }
+}
+
+/// 关机，`code` 作为退出码报告给 QEMU（只使用低 16 位，非 0 的退出码总是报告为失败）
+///
+/// 没有 `syscon-poweroff` 时使用 SBI 关机，此时只能区分成功和失败
+pub fn poweroff(code: usize) -> ! {
+    println!("[Kernel] Power off, exit code {}", code);
+    if let Some(action) = POWEROFF.r#try() {
+        perform(action, exit_code_u16(code));
+    }
+    let reason = if code == 0 {
+        ResetReason::NoReason
+    } else {
+        ResetReason::SystemFailure
+    };
+    sbi::system_reset(ResetType::Shutdown, reason)
+}
+
+/// 重启
+///
+/// 没有 `syscon-reboot` 时使用 SBI 重启，固件不支持时会关机
+pub fn reboot() -> ! {
+    println!("[Kernel] Reboot");
+    if let Some(action) = REBOOT.r#try() {
+        perform(action, 0);
+    }
+    sbi::system_reset(ResetType::ColdReboot, ResetReason::NoReason)
+}
@@ -1,29 +1,75 @@
+mod transport;
 mod virtio_blk;
+mod virtio_console;
 mod virtio_mmio;
+mod virtio_net;
+mod virtio_pci;
+mod virtio_queue;
+mod virtio_rng;
 
-use crate::mem::{PhysicalAddress, VirtualAddress};
-use device_tree::{util::SliceRead, Node};
+use super::device_tree::{first_reg, RegCells};
+use super::DeviceInfo;
+use crate::mem::VirtualAddress;
+use alloc::boxed::Box;
+use alloc::string::String;
+use device_tree::Node;
 use riscv_sbi::println;
+use transport::Transport;
 use virtio_drivers::{DeviceType, VirtIOHeader};
+use virtio_mmio::MmioTransport;
+use virtio_pci::PciTransport;
+
+/// PCI 设备号到 virtio 设备类型的对应，只包括 transitional 设备
+const PCI_DEVICE_NET: u16 = 0x1000;
+const PCI_DEVICE_BLOCK: u16 = 0x1001;
+const PCI_DEVICE_CONSOLE: u16 = 0x1003;
+const PCI_DEVICE_ENTROPY_SOURCE: u16 = 0x1005;
 
 /// 从设备树的某个节点探测 virtio 协议具体类型
-pub fn virtio_probe(node: &Node) {
+pub fn virtio_probe(node: &Node, path: &str, cells: RegCells) {
     // println!("{:x?}", node);
     // reg 属性中包含了描述设备的 Header 的位置
-    let reg = match node.prop_raw("reg") {
-        Some(reg) => reg,
+    let pa = match first_reg(node, cells) {
+        Some(reg) => reg.start,
         _ => return,
     };
-    let pa = PhysicalAddress(reg.as_slice().read_be_u64(0).unwrap() as usize);
     let va = VirtualAddress::from(pa);
     let header = unsafe { &mut *(va.0 as *mut VirtIOHeader) };
     // 目前只支持某个特定版本的 virtio 协议
     if !header.verify() {
         return;
     }
-    // 判断设备类型
-    match header.device_type() {
-        DeviceType::Block => virtio_blk::add_driver(header),
+    add_driver(
+        header.device_type(),
+        Box::new(MmioTransport::new(va)),
+        DeviceInfo::from_node(String::new(), path, node, cells),
+    );
+}
+
+/// 初始化 PCI 总线上的 virtio 设备，`io_base` 为映射后的 BAR0（I/O 空间）
+///
+/// `info` 中的名称为空，由具体的驱动决定
+pub fn virtio_pci_probe(device_id: u16, io_base: VirtualAddress, info: DeviceInfo) {
+    let device_type = match device_id {
+        PCI_DEVICE_NET => DeviceType::Network,
+        PCI_DEVICE_BLOCK => DeviceType::Block,
+        PCI_DEVICE_CONSOLE => DeviceType::Console,
+        PCI_DEVICE_ENTROPY_SOURCE => DeviceType::EntropySource,
+        _ => {
+            println!("unrecognized virtio-pci device: {:#x}", device_id);
+            return;
+        }
+    };
+    add_driver(device_type, Box::new(PciTransport::new(io_base)), info);
+}
+
+/// 按照设备类型初始化驱动，与传输层无关
+fn add_driver(device_type: DeviceType, transport: Box<dyn Transport>, info: DeviceInfo) {
+    match device_type {
+        DeviceType::Block => virtio_blk::add_driver(transport, info),
+        DeviceType::Network => virtio_net::add_driver(transport, info),
+        DeviceType::Console => virtio_console::add_driver(transport, info),
+        DeviceType::EntropySource => virtio_rng::add_driver(transport, info),
         device => println!("unrecognized virtio device: {:?}", device),
     }
 }
@@ -0,0 +1,32 @@
+//! virtio 设备的传输层 [`Transport`]
+
+use crate::mem::PhysicalPageNumber;
+
+/// 访问 virtio 设备寄存器的方式
+///
+/// 目前都使用 legacy 接口：队列以页号告诉设备，used ring 按页对齐。
+/// 驱动只通过这个 trait 访问设备，因此同一个驱动可以用于 MMIO 和 PCI 设备
+pub trait Transport: Send + Sync {
+    /// 重置设备并协商特性，`negotiate` 根据设备支持的特性返回驱动使用的特性
+    fn begin_init(&self, negotiate: &mut dyn FnMut(u32) -> u32);
+
+    /// 完成初始化，此后设备开始处理请求
+    fn finish_init(&self);
+
+    /// 队列的实际长度，队列不存在时为 0
+    ///
+    /// 可以选择长度的设备使用不超过 `preferred` 的长度，否则使用设备规定的长度
+    fn queue_size(&self, queue: u32, preferred: u16) -> u16;
+
+    /// 告诉设备队列所在的物理页
+    fn setup_queue(&self, queue: u32, size: u16, page_number: PhysicalPageNumber);
+
+    /// 通知设备队列中有新的请求
+    fn notify(&self, queue: u32);
+
+    /// 确认设备的中断，返回是否确实有中断
+    fn ack_interrupt(&self) -> bool;
+
+    /// 设备配置空间的虚拟地址
+    fn config(&self) -> usize;
+}
@@ -1,34 +1,208 @@
-use super::super::{DeviceType, Driver, DRIVERS};
+use super::super::{block::SECTOR_SIZE, plic, registry, BlockDriver, Device, DeviceInfo, Driver};
+use super::transport::Transport;
+use super::virtio_queue::{Buffer, VirtQueue};
+use crate::kernel::condvar::Condvar;
+use crate::mem::{Mapping, VirtualAddress, PAGE_SIZE};
+use crate::process::PROCESSOR;
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::format;
 use alloc::sync::Arc;
-use spin::Mutex;
-use virtio_drivers::{VirtIOBlk, VirtIOHeader};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use riscv_sbi::println;
+use spin::{Mutex, MutexGuard};
 
-/// virtio 协议的块设备驱动
-struct VirtIOBlkDriver(Mutex<VirtIOBlk<'static>>);
+/// 期望的请求队列长度，决定了同时进行的请求数量，设备可能使用其他长度
+const QUEUE_SIZE: u16 = 16;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// 请求的头部和设备写回的状态，放在堆上，在请求完成之前不能释放
+#[repr(C)]
+struct Request {
+    request_type: u32,
+    reserved: u32,
+    sector: u64,
+    status: u8,
+}
+
+/// 请求头部的长度，不包括状态
+const REQUEST_HEADER_SIZE: usize = 16;
+
+struct BlkInner {
+    queue: VirtQueue,
+    /// 已经完成、还没有被发起者取走的请求，以描述符链的头作为编号
+    completed: BTreeSet<u16>,
+}
 
-/// 为 [`VirtIOBlkDriver`] 实现 [`Driver`] trait
+/// virtio 协议的块设备驱动
 ///
-/// 调用了 [`virtio_drivers`] 库，其中规定的块大小为 512B
+/// 请求放入队列后，发起请求的线程休眠，由设备的中断唤醒。
+/// 不持有锁等待，因此可以同时有多个请求在进行
+struct VirtIOBlkDriver {
+    info: DeviceInfo,
+    transport: Box<dyn Transport>,
+    inner: Mutex<BlkInner>,
+    /// 有请求完成、描述符被回收时通知
+    condvar: Condvar,
+}
+
+impl VirtIOBlkDriver {
+    /// 是否可以休眠等待中断
+    ///
+    /// 启动阶段读取文件系统时还没有线程，也没有开启中断，只能轮询
+    fn can_sleep(&self) -> bool {
+        !self.info.irqs.is_empty() && plic::available() && PROCESSOR.get().has_current_thread()
+    }
+
+    /// 取出所有已经完成的请求，唤醒等待的线程
+    fn process_used(&self, inner: &mut BlkInner) {
+        let mut any = false;
+        while let Some((head, _)) = inner.queue.pop_used() {
+            inner.completed.insert(head);
+            any = true;
+        }
+        if any {
+            self.condvar.notify_all();
+        }
+    }
+
+    /// 释放锁并等待队列发生变化
+    fn wait(&self, mut inner: MutexGuard<BlkInner>) {
+        if self.can_sleep() {
+            self.condvar.wait_with(inner);
+        } else {
+            self.process_used(&mut inner);
+        }
+    }
+
+    /// 从 `sector` 开始读写 `buf` 指向的 `len` 字节，`write` 表示写入设备
+    fn transfer(&self, sector: usize, buf: usize, len: usize, write: bool) -> bool {
+        if len == 0 || len % SECTOR_SIZE != 0 {
+            return false;
+        }
+        let mut request = Box::new(Request {
+            request_type: if write {
+                VIRTIO_BLK_T_OUT
+            } else {
+                VIRTIO_BLK_T_IN
+            },
+            reserved: 0,
+            sector: sector as u64,
+            status: !0,
+        });
+        let request_address = &mut *request as *mut Request as usize;
+
+        // 缓冲区可能在用户空间或者内核栈上，物理上不连续，按页拆成多个描述符
+        let mut buffers = Vec::new();
+        buffers.push(Buffer {
+            address: Mapping::lookup(VirtualAddress(request_address)).unwrap(),
+            len: REQUEST_HEADER_SIZE,
+            device_writable: false,
+        });
+        let mut va = buf;
+        while va < buf + len {
+            let next = ((va / PAGE_SIZE + 1) * PAGE_SIZE).min(buf + len);
+            let address = match Mapping::lookup(VirtualAddress(va)) {
+                Some(address) => address,
+                None => return false,
+            };
+            buffers.push(Buffer {
+                address,
+                len: next - va,
+                device_writable: !write,
+            });
+            va = next;
+        }
+        buffers.push(Buffer {
+            address: Mapping::lookup(VirtualAddress(request_address + REQUEST_HEADER_SIZE))
+                .unwrap(),
+            len: size_of::<u8>(),
+            device_writable: true,
+        });
+        if buffers.len() > self.inner.lock().queue.size() as usize {
+            return false;
+        }
+
+        // 放入队列，描述符不够时等待其他请求完成
+        let head = loop {
+            let mut inner = self.inner.lock();
+            if let Some(head) = inner.queue.add(&buffers) {
+                self.transport.notify(inner.queue.index());
+                break head;
+            }
+            self.wait(inner);
+        };
+        // 等待请求完成
+        loop {
+            let mut inner = self.inner.lock();
+            if inner.completed.remove(&head) {
+                break;
+            }
+            self.wait(inner);
+        }
+        unsafe { core::ptr::read_volatile(&request.status) == VIRTIO_BLK_S_OK }
+    }
+}
+
 impl Driver for VirtIOBlkDriver {
-    /// 设备类型
-    fn device_type(&self) -> DeviceType {
-        DeviceType::Block
+    fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// 请求完成的中断
+    fn handle_interrupt(&self) {
+        if self.transport.ack_interrupt() {
+            self.process_used(&mut self.inner.lock());
+        }
     }
+}
 
+/// 为 [`VirtIOBlkDriver`] 实现 [`BlockDriver`] trait
+///
+/// 块号以 [`SECTOR_SIZE`] 为单位，一次可以读写多个扇区
+impl BlockDriver for VirtIOBlkDriver {
     /// 读取某个块到 buf 中
     fn read_block(&self, block_id: usize, buf: &mut [u8]) -> bool {
-        self.0.lock().read_block(block_id, buf).is_ok()
+        self.transfer(block_id, buf.as_mut_ptr() as usize, buf.len(), false)
     }
 
     /// 将 buf 中的数据写入块中
     fn write_block(&self, block_id: usize, buf: &[u8]) -> bool {
-        self.0.lock().write_block(block_id, buf).is_ok()
+        self.transfer(block_id, buf.as_ptr() as usize, buf.len(), true)
     }
 }
 
-/// 将从设备树中读取出的设备信息放到 [`static@DRIVERS`] 中
-pub fn add_driver(header: &'static mut VirtIOHeader) {
-    let virtio_blk = VirtIOBlk::new(header).expect("failed to init blk driver");
-    let driver = Arc::new(VirtIOBlkDriver(Mutex::new(virtio_blk)));
-    DRIVERS.write().push(driver);
+/// 初始化设备，以 `vda`、`vdb` 等名称登记到 [`registry`] 中
+pub fn add_driver(transport: Box<dyn Transport>, info: DeviceInfo) {
+    // 不使用任何可选的特性
+    transport.begin_init(&mut |_| 0);
+    let queue = match VirtQueue::new(&*transport, 0, QUEUE_SIZE) {
+        Ok(queue) => queue,
+        Err(message) => {
+            println!("failed to init virtio-blk: {}", message);
+            return;
+        }
+    };
+    transport.finish_init();
+    let capacity = unsafe { (transport.config() as *const u64).read_volatile() };
+    let name = format!(
+        "vd{}",
+        (b'a' + registry::block_devices().len() as u8) as char
+    );
+    println!("virtio-blk {} at {}: {} sectors", name, info.path, capacity);
+
+    let driver = Arc::new(VirtIOBlkDriver {
+        info: DeviceInfo { name, ..info },
+        transport,
+        inner: Mutex::new(BlkInner {
+            queue,
+            completed: BTreeSet::new(),
+        }),
+        condvar: Condvar::default(),
+    });
+    registry::register(Device::Block(driver));
 }
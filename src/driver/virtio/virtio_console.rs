@@ -0,0 +1,285 @@
+use super::super::{registry, CharDriver, Device, DeviceInfo, Driver};
+use super::transport::Transport;
+use super::virtio_queue::{Buffer, VirtQueue};
+use crate::fs::STDIN;
+use crate::mem::{FrameTracker, MemoryResult, FRAME_ALLOCATOR, PAGE_SIZE};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use riscv_sbi::println;
+use spin::Mutex;
+
+/// 设备支持多个端口，通过控制队列管理
+const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1 << 1;
+
+/// 最多使用的端口数
+const MAX_PORTS: usize = 4;
+/// 每个队列的长度
+const QUEUE_SIZE: u16 = 4;
+
+/// 控制消息的类型
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+
+/// 设备的配置空间
+#[repr(C)]
+struct ConsoleConfig {
+    cols: u16,
+    rows: u16,
+    max_nr_ports: u32,
+}
+
+/// 控制队列中的消息
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ControlMessage {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+/// 一对接收和发送队列
+struct QueuePair {
+    receive: VirtQueue,
+    transmit: VirtQueue,
+    /// 交给设备的接收缓冲区，以描述符链的头为键
+    receive_buffers: BTreeMap<u16, FrameTracker>,
+    /// 发送时使用的缓冲区
+    transmit_buffer: FrameTracker,
+}
+
+impl QueuePair {
+    fn new(transport: &dyn Transport, receive_index: u32) -> MemoryResult<Self> {
+        let mut pair = Self {
+            receive: VirtQueue::new(transport, receive_index, QUEUE_SIZE)?,
+            transmit: VirtQueue::new(transport, receive_index + 1, QUEUE_SIZE)?,
+            receive_buffers: BTreeMap::new(),
+            transmit_buffer: FRAME_ALLOCATOR.lock().alloc()?,
+        };
+        for _ in 0..QUEUE_SIZE {
+            let frame = FRAME_ALLOCATOR.lock().alloc()?;
+            pair.post_receive_buffer(frame);
+        }
+        Ok(pair)
+    }
+
+    fn post_receive_buffer(&mut self, frame: FrameTracker) {
+        let buffer = [Buffer {
+            address: frame.address(),
+            len: PAGE_SIZE,
+            device_writable: true,
+        }];
+        let head = self.receive.add(&buffer).unwrap();
+        self.receive_buffers.insert(head, frame);
+    }
+
+    /// 取出收到的所有数据，缓冲区重新交给设备
+    fn receive_all(&mut self, transport: &dyn Transport) -> Vec<u8> {
+        let mut data = Vec::new();
+        while let Some((head, len)) = self.receive.pop_used() {
+            let frame = self.receive_buffers.remove(&head).unwrap();
+            data.extend_from_slice(&frame[..(len as usize).min(PAGE_SIZE)]);
+            self.post_receive_buffer(frame);
+        }
+        if !data.is_empty() {
+            transport.notify(self.receive.index());
+        }
+        data
+    }
+
+    /// 发送数据，等待设备取走之后才返回
+    fn transmit_all(&mut self, transport: &dyn Transport, data: &[u8]) {
+        for chunk in data.chunks(PAGE_SIZE) {
+            self.transmit_buffer[..chunk.len()].copy_from_slice(chunk);
+            let buffer = [Buffer {
+                address: self.transmit_buffer.address(),
+                len: chunk.len(),
+                device_writable: false,
+            }];
+            self.transmit.add(&buffer).unwrap();
+            transport.notify(self.transmit.index());
+            while self.transmit.pop_used().is_none() {}
+        }
+    }
+}
+
+struct ConsoleInner {
+    /// 每个端口的队列，端口 0 使用队列 0、1，端口 n 使用队列 2n + 2、2n + 3
+    ports: Vec<QueuePair>,
+    /// 控制队列，使用队列 2、3，只有支持多端口时才有
+    control: Option<QueuePair>,
+    /// 每个端口收到、还没有被读取的数据
+    input: Vec<VecDeque<u8>>,
+}
+
+/// virtio 协议的控制台，每个端口登记为一个字符设备
+struct VirtIOConsole {
+    transport: Box<dyn Transport>,
+    inner: Mutex<ConsoleInner>,
+    /// 各个端口在 [`registry`] 中的名称
+    names: Vec<String>,
+}
+
+impl VirtIOConsole {
+    /// 发送一条控制消息
+    fn send_control(&self, inner: &mut ConsoleInner, id: u32, event: u16, value: u16) {
+        let message = ControlMessage { id, event, value };
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &message as *const _ as *const u8,
+                size_of::<ControlMessage>(),
+            )
+        };
+        if let Some(control) = inner.control.as_mut() {
+            control.transmit_all(&*self.transport, bytes);
+        }
+    }
+
+    /// 处理设备发来的控制消息
+    fn handle_control(&self, inner: &mut ConsoleInner) {
+        let data = match inner.control.as_mut() {
+            Some(control) => control.receive_all(&self.transport),
+            None => return,
+        };
+        for chunk in data.chunks_exact(size_of::<ControlMessage>()) {
+            let message = unsafe { (chunk.as_ptr() as *const ControlMessage).read_unaligned() };
+            match message.event {
+                DEVICE_ADD if (message.id as usize) < inner.ports.len() => {
+                    self.send_control(inner, message.id, PORT_READY, 1)
+                }
+                DEVICE_ADD => self.send_control(inner, message.id, PORT_READY, 0),
+                CONSOLE_PORT => self.send_control(inner, message.id, PORT_OPEN, 1),
+                _ => {}
+            }
+        }
+    }
+
+    /// 处理中断：收到的数据放入各个端口的缓冲区，作为控制台的端口放入 [`STDIN`]
+    fn handle_interrupt(&self) {
+        if !self.transport.ack_interrupt() {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        self.handle_control(&mut inner);
+        let mut console_input = Vec::new();
+        for id in 0..inner.ports.len() {
+            let data = inner.ports[id].receive_all(&self.transport);
+            if crate::driver::console_device() == Some(self.names[id].as_str()) {
+                console_input.extend(data);
+            } else {
+                inner.input[id].extend(data);
+            }
+        }
+        drop(inner);
+        for c in console_input {
+            STDIN.push(c);
+        }
+    }
+}
+
+/// virtio 控制台的一个端口
+struct ConsolePort {
+    info: DeviceInfo,
+    console: Arc<VirtIOConsole>,
+    id: usize,
+}
+
+impl Driver for ConsolePort {
+    fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// 所有端口共用设备的中断
+    fn handle_interrupt(&self) {
+        self.console.handle_interrupt();
+    }
+}
+
+impl CharDriver for ConsolePort {
+    fn getchar(&self) -> Option<u8> {
+        self.console.inner.lock().input[self.id].pop_front()
+    }
+
+    fn putchar(&self, c: u8) {
+        self.write(&[c]);
+    }
+
+    fn write(&self, data: &[u8]) {
+        let mut inner = self.console.inner.lock();
+        inner.ports[self.id].transmit_all(&*self.console.transport, data);
+    }
+}
+
+/// 初始化设备，每个端口以 `hvc0`、`hvc1` 等名称登记到 [`registry`] 中
+pub fn add_driver(transport: Box<dyn Transport>, info: DeviceInfo) {
+    let mut multiport = false;
+    transport.begin_init(&mut |features| {
+        multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+        features & VIRTIO_CONSOLE_F_MULTIPORT
+    });
+    let port_count = if multiport {
+        let config = transport.config() as *const ConsoleConfig;
+        let max_nr_ports = unsafe { core::ptr::read_volatile(&(*config).max_nr_ports) };
+        (max_nr_ports as usize).min(MAX_PORTS).max(1)
+    } else {
+        1
+    };
+    let queues = (0..port_count)
+        .map(|id| QueuePair::new(&*transport, if id == 0 { 0 } else { 2 * id as u32 + 2 }))
+        .collect::<MemoryResult<Vec<_>>>()
+        .and_then(|ports| {
+            let control = if multiport {
+                Some(QueuePair::new(&*transport, 2)?)
+            } else {
+                None
+            };
+            Ok((ports, control))
+        });
+    let (ports, control) = match queues {
+        Ok(queues) => queues,
+        Err(message) => {
+            println!("failed to init virtio-console: {}", message);
+            return;
+        }
+    };
+    transport.finish_init();
+
+    let first = registry::char_devices()
+        .iter()
+        .filter(|driver| driver.info().name.starts_with("hvc"))
+        .count();
+    let names = (first..first + port_count)
+        .map(|i| format!("hvc{}", i))
+        .collect::<Vec<_>>();
+    println!("virtio-console at {}: {:?}", info.path, names);
+    let console = Arc::new(VirtIOConsole {
+        transport,
+        inner: Mutex::new(ConsoleInner {
+            ports,
+            control,
+            input: (0..port_count).map(|_| VecDeque::new()).collect(),
+        }),
+        names: names.clone(),
+    });
+    if multiport {
+        let mut inner = console.inner.lock();
+        console.send_control(&mut inner, 0, DEVICE_READY, 1);
+    }
+    for (id, name) in names.into_iter().enumerate() {
+        registry::register(Device::Char(Arc::new(ConsolePort {
+            info: DeviceInfo {
+                name,
+                ..info.clone()
+            },
+            console: console.clone(),
+            id,
+        })));
+    }
+}
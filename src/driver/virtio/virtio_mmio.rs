@@ -1,13 +1,16 @@
+use super::transport::Transport;
 use crate::mem::{
-    FrameTracker, Mapping, PhysicalAddress, VirtualAddress, FRAME_ALLOCATOR, PAGE_SIZE,
+    FrameRangeTracker, Mapping, PhysicalAddress, PhysicalPageNumber, VirtualAddress,
+    FRAME_ALLOCATOR, PAGE_SIZE,
 };
 use alloc::collections::BTreeMap;
 use lazy_static::lazy_static;
+use riscv_sbi::println;
 use spin::RwLock;
 
 lazy_static! {
-    /// 用于放置给设备 DMA 所用的物理页（[`FrameTracker`]）
-    pub static ref TRACKERS: RwLock<BTreeMap<PhysicalAddress, FrameTracker>> =
+    /// 用于放置给设备 DMA 所用的连续物理页（[`FrameRangeTracker`]），以起始地址为键
+    pub static ref TRACKERS: RwLock<BTreeMap<PhysicalAddress, FrameRangeTracker>> =
         RwLock::new(BTreeMap::new());
 }
 
@@ -15,31 +18,44 @@ lazy_static! {
 ///
 /// 为什么要求连续的物理内存？设备的 DMA 操作只涉及到内存和对应设备
 /// 这个过程不会涉及到 CPU 的 MMU 机制，我们只能给设备传递物理地址
-/// 而陷于我们之前每次只能分配一个物理页的设计，这里我们假设我们连续分配的地址是连续的
+/// 因此这里直接向帧分配器申请一段物理上连续的帧
+///
+/// 不能在 `extern "C"` 函数中 panic，因此内存不足时返回物理地址 0
 #[no_mangle]
 extern "C" fn virtio_dma_alloc(pages: usize) -> PhysicalAddress {
-    let mut pa: PhysicalAddress = Default::default();
-    let mut last: PhysicalAddress = Default::default();
-    for i in 0..pages {
-        let tracker: FrameTracker = FRAME_ALLOCATOR.lock().alloc().unwrap();
-        if i == 0 {
-            pa = tracker.address();
-        } else {
-            assert_eq!(last + PAGE_SIZE, tracker.address());
+    let frames = match FRAME_ALLOCATOR.lock().alloc_contiguous(pages, 1) {
+        Ok(frames) => frames,
+        Err(error) => {
+            println!("virtio: failed to allocate {} dma pages: {}", pages, error);
+            return PhysicalAddress(0);
         }
-        last = tracker.address();
-        TRACKERS.write().insert(last, tracker);
-    }
-    return pa;
+    };
+    let pa = frames.address();
+    TRACKERS.write().insert(pa, frames);
+    pa
 }
 
 /// 为 DMA 操作释放对应的之前申请的连续的物理页（为 [`virtio_drivers`] 库提供）
+///
+/// 不能在 `extern "C"` 函数中 panic，因此地址没有分配过时返回 -1。
+/// 页数不一致时仍然释放申请时的整段页面，输出警告并返回 -2
 #[no_mangle]
 extern "C" fn virtio_dma_dealloc(pa: PhysicalAddress, pages: usize) -> i32 {
-    for i in 0..pages {
-        TRACKERS.write().remove(&(pa + i * PAGE_SIZE));
+    let frames = match TRACKERS.write().remove(&pa) {
+        Some(frames) => frames,
+        None => return -1,
+    };
+    if frames.count() == pages {
+        0
+    } else {
+        println!(
+            "virtio: dma dealloc at {:x?} with {} pages, but {} were allocated",
+            pa,
+            pages,
+            frames.count()
+        );
+        -2
     }
-    0
 }
 
 /// 将物理地址转为虚拟地址（为 [`virtio_drivers`] 库提供）
@@ -61,3 +77,108 @@ extern "C" fn virtio_phys_to_virt(pa: PhysicalAddress) -> VirtualAddress {
 extern "C" fn virtio_virt_to_phys(va: VirtualAddress) -> PhysicalAddress {
     Mapping::lookup(va).unwrap()
 }
+
+/// virtio-mmio 设备的状态位
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+
+/// legacy 接口的寄存器偏移
+const REG_HOST_FEATURES: usize = 0x010;
+const REG_HOST_FEATURES_SEL: usize = 0x014;
+const REG_GUEST_FEATURES: usize = 0x020;
+const REG_GUEST_FEATURES_SEL: usize = 0x024;
+const REG_GUEST_PAGE_SIZE: usize = 0x028;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_ALIGN: usize = 0x03c;
+const REG_QUEUE_PFN: usize = 0x040;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_CONFIG: usize = 0x100;
+
+/// legacy 接口的 virtio-mmio 寄存器
+///
+/// 不经过 [`virtio_drivers`] 库直接访问设备，驱动可以自己管理 virtqueue，从而不必在每次请求时轮询等待
+pub struct MmioTransport {
+    /// 寄存器的虚拟地址
+    base: usize,
+}
+
+impl MmioTransport {
+    /// 使用已经通过 [`VirtIOHeader::verify`] 检查的寄存器
+    ///
+    /// [`VirtIOHeader::verify`]: virtio_drivers::VirtIOHeader::verify
+    pub fn new(base: VirtualAddress) -> Self {
+        Self { base: base.0 }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+}
+
+impl Transport for MmioTransport {
+    fn begin_init(&self, negotiate: &mut dyn FnMut(u32) -> u32) {
+        self.write(REG_STATUS, 0);
+        self.write(REG_STATUS, STATUS_ACKNOWLEDGE);
+        self.write(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        self.write(REG_HOST_FEATURES_SEL, 0);
+        let features = negotiate(self.read(REG_HOST_FEATURES));
+        self.write(REG_GUEST_FEATURES_SEL, 0);
+        self.write(REG_GUEST_FEATURES, features);
+        self.write(REG_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+    }
+
+    fn finish_init(&self) {
+        self.write(
+            REG_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+        );
+    }
+
+    /// MMIO 设备可以选择队列长度，取不超过设备上限的 2 的幂
+    fn queue_size(&self, queue: u32, preferred: u16) -> u16 {
+        self.write(REG_QUEUE_SEL, queue);
+        let max = self.read(REG_QUEUE_NUM_MAX);
+        if max == 0 {
+            0
+        } else if max >= preferred as u32 {
+            preferred
+        } else {
+            1 << (31 - max.leading_zeros())
+        }
+    }
+
+    fn setup_queue(&self, queue: u32, size: u16, page_number: PhysicalPageNumber) {
+        self.write(REG_QUEUE_SEL, queue);
+        self.write(REG_QUEUE_NUM, size as u32);
+        self.write(REG_QUEUE_ALIGN, PAGE_SIZE as u32);
+        self.write(REG_QUEUE_PFN, page_number.0 as u32);
+    }
+
+    fn notify(&self, queue: u32) {
+        self.write(REG_QUEUE_NOTIFY, queue);
+    }
+
+    fn ack_interrupt(&self) -> bool {
+        let status = self.read(REG_INTERRUPT_STATUS);
+        if status != 0 {
+            self.write(REG_INTERRUPT_ACK, status);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn config(&self) -> usize {
+        self.base + REG_CONFIG
+    }
+}
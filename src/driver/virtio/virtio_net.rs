@@ -0,0 +1,178 @@
+use super::super::{registry, Device, DeviceInfo, Driver, NetDriver};
+use super::transport::Transport;
+use super::virtio_queue::{Buffer, VirtQueue};
+use crate::mem::{FrameTracker, FRAME_ALLOCATOR, PAGE_SIZE};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::sync::Arc;
+use riscv_sbi::println;
+use spin::Mutex;
+
+/// 设备在配置空间中提供 MAC 地址
+const VIRTIO_NET_F_MAC: u32 = 1 << 5;
+
+/// 接收队列和发送队列的编号
+const RECEIVE_QUEUE: u32 = 0;
+const TRANSMIT_QUEUE: u32 = 1;
+/// 队列的长度，每个缓冲区占用两个描述符
+const QUEUE_SIZE: u16 = 16;
+
+/// 每个帧前面的 virtio_net_hdr，没有协商 MRG_RXBUF 时为 10 字节
+const HEADER_SIZE: usize = 10;
+
+struct NetInner {
+    receive_queue: VirtQueue,
+    transmit_queue: VirtQueue,
+    /// 交给设备的接收缓冲区，以描述符链的头为键
+    receive_buffers: BTreeMap<u16, FrameTracker>,
+    /// 正在发送的帧，发送完成后释放
+    transmit_buffers: BTreeMap<u16, FrameTracker>,
+}
+
+impl NetInner {
+    /// 把一页作为接收缓冲区交给设备
+    fn post_receive_buffer(&mut self, frame: FrameTracker) {
+        let buffers = [
+            Buffer {
+                address: frame.address(),
+                len: HEADER_SIZE,
+                device_writable: true,
+            },
+            Buffer {
+                address: frame.address() + HEADER_SIZE,
+                len: PAGE_SIZE - HEADER_SIZE,
+                device_writable: true,
+            },
+        ];
+        let head = self.receive_queue.add(&buffers).unwrap();
+        self.receive_buffers.insert(head, frame);
+    }
+
+    /// 回收已经发送完成的缓冲区
+    fn reclaim_transmitted(&mut self) {
+        while let Some((head, _)) = self.transmit_queue.pop_used() {
+            self.transmit_buffers.remove(&head);
+        }
+    }
+}
+
+/// virtio 协议的网卡驱动
+///
+/// 每个帧使用一页作为缓冲区。帧的头部 virtio_net_hdr 全部置零，不使用校验和卸载等特性
+struct VirtIONetDriver {
+    info: DeviceInfo,
+    transport: Box<dyn Transport>,
+    mac: [u8; 6],
+    inner: Mutex<NetInner>,
+}
+
+impl Driver for VirtIONetDriver {
+    fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// 收到帧或者发送完成时，让协议栈进行处理
+    fn handle_interrupt(&self) {
+        if self.transport.ack_interrupt() {
+            crate::net::poll();
+        }
+    }
+}
+
+impl NetDriver for VirtIONetDriver {
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn send(&self, frame: &[u8]) -> bool {
+        if frame.len() > PAGE_SIZE - HEADER_SIZE {
+            return false;
+        }
+        let mut buffer = match FRAME_ALLOCATOR.lock().alloc() {
+            Ok(buffer) => buffer,
+            Err(_) => return false,
+        };
+        buffer[..HEADER_SIZE].fill(0);
+        buffer[HEADER_SIZE..HEADER_SIZE + frame.len()].copy_from_slice(frame);
+        let buffers = [
+            Buffer {
+                address: buffer.address(),
+                len: HEADER_SIZE,
+                device_writable: false,
+            },
+            Buffer {
+                address: buffer.address() + HEADER_SIZE,
+                len: frame.len(),
+                device_writable: false,
+            },
+        ];
+
+        let mut inner = self.inner.lock();
+        inner.reclaim_transmitted();
+        match inner.transmit_queue.add(&buffers) {
+            Some(head) => {
+                inner.transmit_buffers.insert(head, buffer);
+                self.transport.notify(TRANSMIT_QUEUE);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let (head, len) = inner.receive_queue.pop_used()?;
+        let frame = inner.receive_buffers.remove(&head).unwrap();
+        let len = (len as usize).saturating_sub(HEADER_SIZE).min(buf.len());
+        buf[..len].copy_from_slice(&frame[HEADER_SIZE..HEADER_SIZE + len]);
+        // 缓冲区重新交给设备
+        inner.post_receive_buffer(frame);
+        self.transport.notify(RECEIVE_QUEUE);
+        Some(len)
+    }
+}
+
+/// 初始化设备，以 `eth0`、`eth1` 等名称登记到 [`registry`] 中
+pub fn add_driver(transport: Box<dyn Transport>, info: DeviceInfo) {
+    transport.begin_init(&mut |features| features & VIRTIO_NET_F_MAC);
+    let (receive_queue, transmit_queue) = match (
+        VirtQueue::new(&*transport, RECEIVE_QUEUE, QUEUE_SIZE),
+        VirtQueue::new(&*transport, TRANSMIT_QUEUE, QUEUE_SIZE),
+    ) {
+        (Ok(receive_queue), Ok(transmit_queue)) => (receive_queue, transmit_queue),
+        _ => {
+            println!("failed to init virtio-net: no virtqueue");
+            return;
+        }
+    };
+    let mut inner = NetInner {
+        receive_queue,
+        transmit_queue,
+        receive_buffers: BTreeMap::new(),
+        transmit_buffers: BTreeMap::new(),
+    };
+    for _ in 0..QUEUE_SIZE / 2 {
+        match FRAME_ALLOCATOR.lock().alloc() {
+            Ok(frame) => inner.post_receive_buffer(frame),
+            Err(message) => {
+                println!("failed to init virtio-net: {}", message);
+                return;
+            }
+        }
+    }
+    transport.finish_init();
+    transport.notify(RECEIVE_QUEUE);
+
+    let mac = unsafe { (transport.config() as *const [u8; 6]).read_volatile() };
+    let name = format!("eth{}", registry::net_devices().len());
+    println!("virtio-net {} at {}: mac {:02x?}", name, info.path, mac);
+
+    let driver = Arc::new(VirtIONetDriver {
+        info: DeviceInfo { name, ..info },
+        transport,
+        mac,
+        inner: Mutex::new(inner),
+    });
+    registry::register(Device::Net(driver));
+}
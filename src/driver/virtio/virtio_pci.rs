@@ -0,0 +1,85 @@
+//! legacy 接口的 virtio-pci 传输层 [`PciTransport`]
+
+use super::transport::Transport;
+use crate::mem::{PhysicalPageNumber, VirtualAddress};
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+
+/// legacy 接口在 BAR0（I/O 空间）中的寄存器偏移
+const REG_HOST_FEATURES: usize = 0x00;
+const REG_GUEST_FEATURES: usize = 0x04;
+const REG_QUEUE_PFN: usize = 0x08;
+const REG_QUEUE_SIZE: usize = 0x0c;
+const REG_QUEUE_SELECT: usize = 0x0e;
+const REG_QUEUE_NOTIFY: usize = 0x10;
+const REG_STATUS: usize = 0x12;
+const REG_ISR: usize = 0x13;
+/// 没有开启 MSI-X 时，设备配置空间紧跟在通用寄存器之后
+const REG_CONFIG: usize = 0x14;
+
+/// 通过 BAR0 访问的 transitional virtio-pci 设备
+///
+/// QEMU 中直接接在根总线上的 virtio-pci 设备同时提供 legacy 和 modern 接口，这里只使用 legacy 接口。
+/// 不使用 MSI-X，中断通过 INTx 经 PLIC 到达
+pub struct PciTransport {
+    /// BAR0 映射后的虚拟地址
+    base: usize,
+}
+
+impl PciTransport {
+    /// `base` 为 BAR0 映射后的虚拟地址
+    pub fn new(base: VirtualAddress) -> Self {
+        Self { base: base.0 }
+    }
+
+    fn read<T>(&self, offset: usize) -> T {
+        unsafe { ((self.base + offset) as *const T).read_volatile() }
+    }
+
+    fn write<T>(&self, offset: usize, value: T) {
+        unsafe { ((self.base + offset) as *mut T).write_volatile(value) }
+    }
+}
+
+impl Transport for PciTransport {
+    fn begin_init(&self, negotiate: &mut dyn FnMut(u32) -> u32) {
+        self.write::<u8>(REG_STATUS, 0);
+        self.write(REG_STATUS, STATUS_ACKNOWLEDGE);
+        self.write(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let features = negotiate(self.read(REG_HOST_FEATURES));
+        self.write(REG_GUEST_FEATURES, features);
+    }
+
+    fn finish_init(&self) {
+        self.write(
+            REG_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+        );
+    }
+
+    /// legacy PCI 设备的队列长度是固定的
+    fn queue_size(&self, queue: u32, _preferred: u16) -> u16 {
+        self.write(REG_QUEUE_SELECT, queue as u16);
+        self.read(REG_QUEUE_SIZE)
+    }
+
+    fn setup_queue(&self, queue: u32, _size: u16, page_number: PhysicalPageNumber) {
+        self.write(REG_QUEUE_SELECT, queue as u16);
+        self.write(REG_QUEUE_PFN, page_number.0 as u32);
+    }
+
+    fn notify(&self, queue: u32) {
+        self.write(REG_QUEUE_NOTIFY, queue as u16);
+    }
+
+    /// 读取 ISR 寄存器的同时清除中断
+    fn ack_interrupt(&self) -> bool {
+        self.read::<u8>(REG_ISR) != 0
+    }
+
+    fn config(&self) -> usize {
+        self.base + REG_CONFIG
+    }
+}
@@ -0,0 +1,194 @@
+//! legacy 布局的 virtqueue [`VirtQueue`]
+
+use super::transport::Transport;
+use crate::mem::{
+    FrameRangeTracker, MemoryError, MemoryResult, PhysicalAddress, VirtualAddress, FRAME_ALLOCATOR,
+    PAGE_SIZE,
+};
+use core::mem::size_of;
+use core::sync::atomic::{fence, Ordering};
+
+/// 描述符链中还有下一个描述符
+const DESC_F_NEXT: u16 = 1;
+/// 描述符指向的缓冲区由设备写入
+const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// 一段交给设备的缓冲区
+pub struct Buffer {
+    pub address: PhysicalAddress,
+    pub len: usize,
+    /// 是否由设备写入
+    pub device_writable: bool,
+}
+
+/// 驱动和设备之间的请求队列
+///
+/// 描述符表、available ring 放在第一页开始，used ring 放在下一个页对齐的位置。
+/// 队列本身不加锁，由驱动负责互斥
+pub struct VirtQueue {
+    /// 队列所在的物理页
+    frames: FrameRangeTracker,
+    /// 队列编号
+    index: u32,
+    /// 队列长度
+    size: u16,
+    /// 空闲描述符链表的头
+    free_head: u16,
+    /// 空闲描述符的数量
+    free_count: u16,
+    /// 下一个要放入 available ring 的位置
+    avail_index: u16,
+    /// 下一个要从 used ring 取出的位置
+    last_used_index: u16,
+}
+
+impl VirtQueue {
+    /// 分配队列并告诉设备
+    ///
+    /// 队列的实际长度由 [`Transport::queue_size`] 决定，可能和 `size` 不同
+    pub fn new(transport: &dyn Transport, index: u32, size: u16) -> MemoryResult<Self> {
+        let size = transport.queue_size(index, size);
+        if size == 0 {
+            return Err(MemoryError::Invalid("virtqueue not available"));
+        }
+        let pages = (Self::used_offset(size) + 6 + 8 * size as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+        let frames = FRAME_ALLOCATOR.lock().alloc_contiguous(pages, 1)?;
+        let mut queue = Self {
+            frames,
+            index,
+            size,
+            free_head: 0,
+            free_count: size,
+            avail_index: 0,
+            last_used_index: 0,
+        };
+        unsafe {
+            core::ptr::write_bytes(queue.base() as *mut u8, 0, pages * PAGE_SIZE);
+        }
+        for i in 0..size {
+            queue.descriptor(i).next = i + 1;
+        }
+        transport.setup_queue(index, size, queue.frames.page_number());
+        Ok(queue)
+    }
+
+    /// 队列编号
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// 队列长度
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn base(&self) -> usize {
+        VirtualAddress::from(self.frames.address()).0
+    }
+
+    /// used ring 相对队列起始的偏移
+    fn used_offset(size: u16) -> usize {
+        let avail_end = size_of::<Descriptor>() * size as usize + 6 + 2 * size as usize;
+        (avail_end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+    }
+
+    fn descriptor(&mut self, i: u16) -> &mut Descriptor {
+        unsafe { &mut *(self.base() as *mut Descriptor).add(i as usize) }
+    }
+
+    /// available ring 中的 idx 和 ring 字段
+    fn avail(&self) -> (*mut u16, *mut u16) {
+        let avail = self.base() + size_of::<Descriptor>() * self.size as usize;
+        ((avail + 2) as *mut u16, (avail + 4) as *mut u16)
+    }
+
+    /// used ring 中的 idx 和 ring 字段
+    fn used(&self) -> (*const u16, *const UsedElement) {
+        let used = self.base() + Self::used_offset(self.size);
+        ((used + 2) as *const u16, (used + 4) as *const UsedElement)
+    }
+
+    /// 把一组缓冲区作为一个请求放入队列，返回描述符链的头
+    ///
+    /// 空闲描述符不够时返回 `None`。放入之后还需要通知设备
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+        let head = self.free_head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let current = self.free_head;
+            let descriptor = self.descriptor(current);
+            descriptor.addr = buffer.address.0 as u64;
+            descriptor.len = buffer.len as u32;
+            descriptor.flags = if buffer.device_writable {
+                DESC_F_WRITE
+            } else {
+                0
+            };
+            let next = descriptor.next;
+            if i + 1 < buffers.len() {
+                descriptor.flags |= DESC_F_NEXT;
+            }
+            self.free_head = next;
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let (avail_idx, avail_ring) = self.avail();
+        unsafe {
+            avail_ring
+                .add((self.avail_index % self.size) as usize)
+                .write_volatile(head);
+        }
+        // 设备必须先看到 ring 中的内容，再看到新的 idx
+        fence(Ordering::SeqCst);
+        self.avail_index = self.avail_index.wrapping_add(1);
+        unsafe { avail_idx.write_volatile(self.avail_index) };
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// 取出一个已经完成的请求，回收它的描述符，返回描述符链的头和设备写入的长度
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let (used_idx, used_ring) = self.used();
+        if self.last_used_index == unsafe { used_idx.read_volatile() } {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let element = unsafe {
+            used_ring
+                .add((self.last_used_index % self.size) as usize)
+                .read_volatile()
+        };
+        let head = element.id as u16;
+        self.last_used_index = self.last_used_index.wrapping_add(1);
+
+        // 把整条链放回空闲链表
+        let mut current = head;
+        loop {
+            self.free_count += 1;
+            let descriptor = self.descriptor(current);
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                descriptor.next = self.free_head;
+                break;
+            }
+            current = descriptor.next;
+        }
+        self.free_head = head;
+        Some((head, element.len))
+    }
+}
@@ -0,0 +1,102 @@
+use super::super::{registry, Device, DeviceInfo, Driver, RngDriver};
+use super::transport::Transport;
+use super::virtio_queue::{Buffer, VirtQueue};
+use crate::fs::ENTROPY_POOL;
+use crate::mem::{FrameTracker, FRAME_ALLOCATOR};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use riscv_sbi::println;
+use spin::Mutex;
+
+/// 每次请求的字节数
+const REQUEST_SIZE: usize = 256;
+
+struct RngInner {
+    queue: VirtQueue,
+    /// 设备写入随机数的缓冲区
+    buffer: FrameTracker,
+    /// 是否有请求正在进行
+    pending: bool,
+}
+
+/// virtio 协议的随机数发生器
+///
+/// 同一时间只有一个请求，设备完成后把随机数放入 [`ENTROPY_POOL`]
+struct VirtIORngDriver {
+    info: DeviceInfo,
+    transport: Box<dyn Transport>,
+    inner: Mutex<RngInner>,
+}
+
+impl Driver for VirtIORngDriver {
+    fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    fn handle_interrupt(&self) {
+        if !self.transport.ack_interrupt() {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        if let Some((_, len)) = inner.queue.pop_used() {
+            inner.pending = false;
+            let len = (len as usize).min(REQUEST_SIZE);
+            let data = inner.buffer[..len].to_vec();
+            drop(inner);
+            ENTROPY_POOL.push(&data);
+        }
+    }
+}
+
+impl RngDriver for VirtIORngDriver {
+    fn request(&self) {
+        let mut inner = self.inner.lock();
+        if inner.pending {
+            return;
+        }
+        let buffer = [Buffer {
+            address: inner.buffer.address(),
+            len: REQUEST_SIZE,
+            device_writable: true,
+        }];
+        if inner.queue.add(&buffer).is_some() {
+            inner.pending = true;
+            self.transport.notify(inner.queue.index());
+        }
+    }
+}
+
+/// 初始化设备，以 `hwrng0` 等名称登记到 [`registry`] 中，并立即请求一次随机数
+pub fn add_driver(transport: Box<dyn Transport>, info: DeviceInfo) {
+    transport.begin_init(&mut |_| 0);
+    let queue = match VirtQueue::new(&*transport, 0, 1) {
+        Ok(queue) => queue,
+        Err(message) => {
+            println!("failed to init virtio-rng: {}", message);
+            return;
+        }
+    };
+    let buffer = match FRAME_ALLOCATOR.lock().alloc() {
+        Ok(buffer) => buffer,
+        Err(message) => {
+            println!("failed to init virtio-rng: {}", message);
+            return;
+        }
+    };
+    transport.finish_init();
+
+    let name = format!("hwrng{}", registry::rng_devices().len());
+    println!("virtio-rng {} at {}", name, info.path);
+    let driver = Arc::new(VirtIORngDriver {
+        info: DeviceInfo { name, ..info },
+        transport,
+        inner: Mutex::new(RngInner {
+            queue,
+            buffer,
+            pending: false,
+        }),
+    });
+    driver.request();
+    registry::register(Device::Rng(driver));
+}
@@ -1,11 +1,15 @@
+mod file;
 mod inode_ext;
+mod random;
 mod stdin;
 mod stdout;
+pub use file::*;
 pub use inode_ext::*;
+pub use random::*;
 pub use stdin::*;
 pub use stdout::*;
 
-use crate::driver::{block::BlockDevice, DeviceType, DRIVERS};
+use crate::driver::{block::BlockDevice, registry, Device, Driver};
 use alloc::sync::Arc;
 use lazy_static::lazy_static;
 use rcore_fs::{dev::block_cache::BlockCache, vfs::*};
@@ -18,21 +22,38 @@ pub const BLOCK_CACHE_CAPACITY: usize = 0x10;
 lazy_static! {
     /// 根文件系统的根目录的 INode
     pub static ref ROOT_INODE: Arc<dyn INode> = {
-        // 选择第一个块设备
-        for driver in DRIVERS.read().iter() {
-            if driver.device_type() == DeviceType::Block {
-                let device = BlockDevice(driver.clone());
-                // 动态分配一段内存空间作为设备 Cache
-                let device_with_cache = Arc::new(BlockCache::new(device, BLOCK_CACHE_CAPACITY));
-                return SimpleFileSystem::open(device_with_cache)
-                    .expect("failed to open SFS")
-                    .root_inode();
-            }
-        }
-        panic!("failed to load fs")
+        // 启动参数中用 root= 指定了设备时使用该设备，否则选择第一个块设备
+        let driver = match crate::driver::root_device() {
+            Some(root) => match registry::find(root.trim_start_matches("/dev/")) {
+                Some(Device::Block(driver)) => driver,
+                _ => panic!("root device {} is not a block device", root),
+            },
+            None => registry::block_devices()
+                .into_iter()
+                .next()
+                .expect("failed to load fs: no block device"),
+        };
+        println!("root device: {}", driver.info().name);
+        let device = BlockDevice(driver);
+        // 动态分配一段内存空间作为设备 Cache
+        let device_with_cache = Arc::new(BlockCache::new(device, BLOCK_CACHE_CAPACITY));
+        SimpleFileSystem::open(device_with_cache)
+            .expect("failed to open SFS")
+            .root_inode()
     };
 }
 
+/// `/dev` 下名为 `name` 的设备文件
+pub fn device_node(name: &str) -> Option<Arc<dyn INode>> {
+    match name {
+        "stdin" => Some(STDIN.clone()),
+        "stdout" | "stderr" => Some(STDOUT.clone()),
+        "random" => Some(RANDOM.clone()),
+        "urandom" => Some(URANDOM.clone()),
+        _ => None,
+    }
+}
+
 /// 打印某个目录的全部文件
 pub fn ls(path: &str) {
     let mut id = 0;
@@ -0,0 +1,235 @@
+//! 打开的文件 [`File`] 和进程的描述符表 [`FileTable`]
+
+use super::*;
+use crate::kernel::Errno;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+/// 一个进程最多同时打开的描述符个数
+pub const MAX_DESCRIPTORS: usize = 256;
+
+/// 一个打开的文件
+///
+/// dup 和 fork 得到的描述符共享同一个 `File`，因此也共享读写位置
+pub struct File {
+    inode: Arc<dyn INode>,
+    readable: bool,
+    writable: bool,
+    /// 每次写入之前移动到文件末尾
+    append: bool,
+    /// 读写位置。只有文件系统中的普通文件有读写位置，设备和 socket 总是从 0 开始读写
+    offset: Option<Mutex<usize>>,
+}
+
+impl File {
+    /// 打开文件系统中的文件
+    pub fn new(inode: Arc<dyn INode>, readable: bool, writable: bool, append: bool) -> Self {
+        Self {
+            inode,
+            readable,
+            writable,
+            append,
+            offset: Some(Mutex::new(0)),
+        }
+    }
+
+    /// 打开设备或者 socket，可以读写
+    pub fn device(inode: Arc<dyn INode>) -> Self {
+        Self {
+            inode,
+            readable: true,
+            writable: true,
+            append: false,
+            offset: None,
+        }
+    }
+
+    pub fn inode(&self) -> &Arc<dyn INode> {
+        &self.inode
+    }
+
+    /// 从读写位置读取，返回读取的字节数
+    ///
+    /// 读取可能会休眠，因此不在读取期间持有读写位置的锁
+    pub fn read(&self, buf: &mut [u8]) -> core::result::Result<usize, Errno> {
+        if !self.readable {
+            return Err(Errno::EBADF);
+        }
+        let offset = self.offset.as_ref().map_or(0, |offset| *offset.lock());
+        let len = self.inode.read_at(offset, buf)?;
+        if let Some(offset) = self.offset.as_ref() {
+            *offset.lock() += len;
+        }
+        Ok(len)
+    }
+
+    /// 写入到读写位置，返回写入的字节数
+    pub fn write(&self, buf: &[u8]) -> core::result::Result<usize, Errno> {
+        if !self.writable {
+            return Err(Errno::EBADF);
+        }
+        let offset = match self.offset.as_ref() {
+            Some(_) if self.append => self.inode.metadata()?.size,
+            Some(offset) => *offset.lock(),
+            None => 0,
+        };
+        let len = self.inode.write_at(offset, buf)?;
+        if let Some(position) = self.offset.as_ref() {
+            *position.lock() = offset + len;
+        }
+        Ok(len)
+    }
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("File")
+            .field("readable", &self.readable)
+            .field("writable", &self.writable)
+            .field("append", &self.append)
+            .field("offset", &self.offset.as_ref().map(|offset| *offset.lock()))
+            .finish()
+    }
+}
+
+/// 描述符表中的一项
+#[derive(Clone, Debug)]
+struct Descriptor {
+    file: Arc<File>,
+    /// exec 时关闭
+    close_on_exec: bool,
+}
+
+/// 进程的描述符表，描述符是表中的下标
+///
+/// 进程中的所有线程共用，fork 时复制，其中的文件由父子进程共享
+#[derive(Clone, Debug, Default)]
+pub struct FileTable {
+    slots: Vec<Option<Descriptor>>,
+}
+
+impl FileTable {
+    /// 打开了标准输入、标准输出和标准错误的描述符表
+    pub fn new() -> Self {
+        let stdin = Arc::new(File::device(STDIN.clone()));
+        let stdout = Arc::new(File::device(STDOUT.clone()));
+        let mut table = Self::default();
+        for file in [stdin, stdout.clone(), stdout].iter() {
+            table.add(file.clone(), false).unwrap();
+        }
+        table
+    }
+
+    /// 描述符对应的文件
+    pub fn get(&self, fd: usize) -> core::result::Result<Arc<File>, Errno> {
+        self.descriptor(fd)
+            .map(|descriptor| descriptor.file.clone())
+    }
+
+    fn descriptor(&self, fd: usize) -> core::result::Result<&Descriptor, Errno> {
+        self.slots
+            .get(fd)
+            .and_then(Option::as_ref)
+            .ok_or(Errno::EBADF)
+    }
+
+    /// 放入最小的空闲描述符，返回这个描述符
+    pub fn add(
+        &mut self,
+        file: Arc<File>,
+        close_on_exec: bool,
+    ) -> core::result::Result<usize, Errno> {
+        let fd = match self.slots.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.slots.len() < MAX_DESCRIPTORS => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+            None => return Err(Errno::EMFILE),
+        };
+        self.slots[fd] = Some(Descriptor {
+            file,
+            close_on_exec,
+        });
+        Ok(fd)
+    }
+
+    /// 关闭描述符，返回原来的文件
+    ///
+    /// 文件可能在最后一个引用消失时执行关闭连接等操作，调用者应当在释放进程的锁之后再丢弃它
+    pub fn close(&mut self, fd: usize) -> core::result::Result<Arc<File>, Errno> {
+        let descriptor = self
+            .slots
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(Errno::EBADF)?;
+        // 去掉末尾的空位
+        while let Some(None) = self.slots.last() {
+            self.slots.pop();
+        }
+        Ok(descriptor.file)
+    }
+
+    /// 复制描述符到最小的空闲描述符，新的描述符在 exec 时不会关闭
+    pub fn dup(&mut self, fd: usize) -> core::result::Result<usize, Errno> {
+        let file = self.get(fd)?;
+        self.add(file, false)
+    }
+
+    /// 复制描述符到 `new_fd`，`new_fd` 原来打开的文件会被关闭并返回
+    pub fn dup2(
+        &mut self,
+        fd: usize,
+        new_fd: usize,
+    ) -> core::result::Result<Option<Arc<File>>, Errno> {
+        let file = self.get(fd)?;
+        if new_fd >= MAX_DESCRIPTORS {
+            return Err(Errno::EBADF);
+        }
+        if fd == new_fd {
+            return Ok(None);
+        }
+        if self.slots.len() <= new_fd {
+            self.slots.resize(new_fd + 1, None);
+        }
+        let old = self.slots[new_fd].replace(Descriptor {
+            file,
+            close_on_exec: false,
+        });
+        Ok(old.map(|descriptor| descriptor.file))
+    }
+
+    /// 描述符是否会在 exec 时关闭
+    pub fn close_on_exec(&self, fd: usize) -> core::result::Result<bool, Errno> {
+        self.descriptor(fd)
+            .map(|descriptor| descriptor.close_on_exec)
+    }
+
+    pub fn set_close_on_exec(
+        &mut self,
+        fd: usize,
+        close_on_exec: bool,
+    ) -> core::result::Result<(), Errno> {
+        self.descriptor(fd)?;
+        self.slots[fd].as_mut().unwrap().close_on_exec = close_on_exec;
+        Ok(())
+    }
+
+    /// exec 时关闭所有设置了 close-on-exec 的描述符，返回被关闭的文件
+    pub fn exec(&mut self) -> Vec<Arc<File>> {
+        let mut closed = Vec::new();
+        for slot in self.slots.iter_mut() {
+            if slot
+                .as_ref()
+                .map_or(false, |descriptor| descriptor.close_on_exec)
+            {
+                closed.push(slot.take().unwrap().file);
+            }
+        }
+        while let Some(None) = self.slots.last() {
+            self.slots.pop();
+        }
+        closed
+    }
+}
@@ -0,0 +1,161 @@
+use super::*;
+use crate::driver::{registry, RngDriver};
+use crate::kernel::condvar::Condvar;
+use alloc::collections::VecDeque;
+use core::any::Any;
+use riscv::register::time;
+use spin::Mutex;
+
+/// 熵池最多保存的字节数
+const POOL_CAPACITY: usize = 4096;
+
+lazy_static! {
+    /// 内核的熵池
+    pub static ref ENTROPY_POOL: EntropyPool = EntropyPool::new();
+    /// `/dev/random`，熵池为空时等待
+    pub static ref RANDOM: Arc<RandomDevice> = Arc::new(RandomDevice { blocking: true });
+    /// `/dev/urandom`，从不等待
+    pub static ref URANDOM: Arc<RandomDevice> = Arc::new(RandomDevice { blocking: false });
+}
+
+/// 熵池
+///
+/// 保存随机数发生器设备产生的随机数，同时用它们不断扰动一个伪随机数发生器。
+/// 伪随机数发生器使用 xoshiro256**，不是密码学安全的
+pub struct EntropyPool {
+    /// 设备产生、还没有被读取的随机数
+    bytes: Mutex<VecDeque<u8>>,
+    /// 伪随机数发生器的状态
+    state: Mutex<[u64; 4]>,
+    /// 有新的随机数时通知等待的线程
+    condvar: Condvar,
+}
+
+impl EntropyPool {
+    fn new() -> Self {
+        // 没有随机数发生器设备时只能以时间作为种子
+        let mut seed = time::read64() | 1;
+        let mut state = [0; 4];
+        for word in state.iter_mut() {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            *word = seed;
+        }
+        Self {
+            bytes: Mutex::new(VecDeque::new()),
+            state: Mutex::new(state),
+            condvar: Condvar::default(),
+        }
+    }
+
+    /// 放入设备产生的随机数，然后唤醒等待的线程
+    pub fn push(&self, data: &[u8]) {
+        {
+            let mut state = self.state.lock();
+            for (i, &byte) in data.iter().enumerate() {
+                state[i % 4] ^= (byte as u64) << ((i / 4 % 8) * 8);
+            }
+        }
+        let mut bytes = self.bytes.lock();
+        let count = data.len().min(POOL_CAPACITY - bytes.len());
+        bytes.extend(&data[..count]);
+        drop(bytes);
+        self.condvar.notify_all();
+    }
+
+    /// 熵池中还有多少字节
+    fn available(&self) -> usize {
+        self.bytes.lock().len()
+    }
+
+    /// 熵池不足一半时，请求设备产生更多的随机数
+    fn request_more(&self) {
+        if self.available() < POOL_CAPACITY / 2 {
+            for driver in registry::rng_devices() {
+                driver.request();
+            }
+        }
+    }
+
+    /// 从熵池中读取设备产生的随机数
+    ///
+    /// `blocking` 为真时，熵池为空则等待；否则直接返回 `None`
+    pub fn read_random(&self, buf: &mut [u8], blocking: bool) -> Option<usize> {
+        if buf.is_empty() {
+            return Some(0);
+        }
+        loop {
+            self.request_more();
+            let mut bytes = self.bytes.lock();
+            if bytes.is_empty() {
+                if !blocking {
+                    return None;
+                }
+                self.condvar.wait_with(bytes);
+                continue;
+            }
+            let count = buf.len().min(bytes.len());
+            for (byte, random) in buf.iter_mut().zip(bytes.drain(..count)) {
+                *byte = random;
+            }
+            return Some(count);
+        }
+    }
+
+    /// 从伪随机数发生器读取，总是填满 `buf`
+    pub fn read_pseudo(&self, buf: &mut [u8]) -> usize {
+        self.request_more();
+        let mut state = self.state.lock();
+        for chunk in buf.chunks_mut(8) {
+            let value = next(&mut state);
+            chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+        }
+        buf.len()
+    }
+}
+
+/// xoshiro256** 的一步
+fn next(s: &mut [u64; 4]) -> u64 {
+    let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+    let t = s[1] << 17;
+    s[2] ^= s[0];
+    s[3] ^= s[1];
+    s[1] ^= s[2];
+    s[0] ^= s[3];
+    s[2] ^= t;
+    s[3] = s[3].rotate_left(45);
+    result
+}
+
+/// `/dev/random` 和 `/dev/urandom`
+pub struct RandomDevice {
+    /// 是否只读取设备产生的随机数，没有时等待
+    blocking: bool,
+}
+
+impl INode for RandomDevice {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if self.blocking {
+            Ok(ENTROPY_POOL.read_random(buf, true).unwrap())
+        } else {
+            Ok(ENTROPY_POOL.read_pseudo(buf))
+        }
+    }
+
+    /// 写入的数据混入熵池
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        ENTROPY_POOL.push(buf);
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::NotSupported)
+    }
+
+    /// This is used to implement dynamics cast.
+    /// Simply return self in the implement of the function.
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
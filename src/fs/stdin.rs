@@ -22,13 +22,15 @@ impl INode for Stdin {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
         if offset != 0 {
             // 不支持 offset
-            Err(FsError::NotSupported)
-        } else if self.buffer.lock().len() == 0 {
-            // 缓冲区没有数据，将当前线程休眠
-            self.condvar.wait();
-            Ok(0)
-        } else {
+            return Err(FsError::NotSupported);
+        }
+        loop {
             let mut stdin_buffer = self.buffer.lock();
+            if stdin_buffer.is_empty() {
+                // 缓冲区没有数据，将当前线程休眠，有输入时再重新读取
+                self.condvar.wait_with(stdin_buffer);
+                continue;
+            }
             for (i, byte) in buf.iter_mut().enumerate() {
                 if let Some(b) = stdin_buffer.pop_front() {
                     *byte = b;
@@ -36,7 +38,7 @@ impl INode for Stdin {
                     return Ok(i);
                 }
             }
-            Ok(buf.len())
+            return Ok(buf.len());
         }
     }
 
@@ -1,4 +1,5 @@
 use super::*;
+use crate::driver::CharDriver;
 use core::any::Any;
 use lazy_static::lazy_static;
 
@@ -15,6 +16,19 @@ impl INode for Stdout {
         if offset != 0 {
             Err(FsError::NotSupported)
         } else if let Ok(string) = core::str::from_utf8(buf) {
+            // 启动参数指定了控制台设备时写入该设备
+            if let Some(console) = crate::driver::registry::console() {
+                console.write(buf);
+                return Ok(buf.len());
+            }
+            // 开启 uart-stdout 特性并且找到了串口时，直接写入串口
+            #[cfg(feature = "uart-stdout")]
+            {
+                if let Some(uart) = crate::driver::ns16550a::UART.r#try() {
+                    string.bytes().for_each(|c| uart.putchar(c));
+                    return Ok(buf.len());
+                }
+            }
             riscv_sbi::print!("{}", string);
             Ok(buf.len())
         } else {
@@ -0,0 +1,25 @@
+//! 中断和异常处理
+//!
+//! 入口 `__interrupt` 在 `interrupt.asm` 中，它保存 [`Context`] 后调用 [`handler`] 中的处理函数
+
+mod context;
+mod handler;
+
+pub use context::Context;
+
+global_asm!(include_str!("interrupt/interrupt.asm"));
+
+/// 设置中断入口，每个核都需要调用一次
+pub fn init() {
+    extern "C" {
+        fn __interrupt();
+    }
+    unsafe {
+        // sscratch 为 0 表示当前在内核态
+        riscv::register::sscratch::write(0);
+        riscv::register::stvec::write(
+            __interrupt as usize,
+            riscv::register::stvec::TrapMode::Direct,
+        );
+    }
+}
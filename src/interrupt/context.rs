@@ -0,0 +1,47 @@
+//! 中断 / 异常发生时保存的上下文 [`Context`]
+
+use riscv::register::sstatus::Sstatus;
+
+/// 发生中断 / 异常时保存的全部寄存器
+///
+/// 布局必须与 `interrupt.asm` 一致：依次是 x1 到 x31、`sstatus` 和 `sepc`，
+/// 最后一项用于从用户态陷入时找回内核的 `tp`，同时让整个结构保持 16 字节对齐
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Context {
+    pub ra: usize,
+    pub sp: usize,
+    pub gp: usize,
+    pub tp: usize,
+    pub t0: usize,
+    pub t1: usize,
+    pub t2: usize,
+    pub s0: usize,
+    pub s1: usize,
+    pub a0: usize,
+    pub a1: usize,
+    pub a2: usize,
+    pub a3: usize,
+    pub a4: usize,
+    pub a5: usize,
+    pub a6: usize,
+    pub a7: usize,
+    pub s2: usize,
+    pub s3: usize,
+    pub s4: usize,
+    pub s5: usize,
+    pub s6: usize,
+    pub s7: usize,
+    pub s8: usize,
+    pub s9: usize,
+    pub s10: usize,
+    pub s11: usize,
+    pub t3: usize,
+    pub t4: usize,
+    pub t5: usize,
+    pub t6: usize,
+    pub sstatus: Sstatus,
+    pub sepc: usize,
+    /// 返回用户态时由 `__restore` 写入内核的 `tp`
+    kernel_tp: usize,
+}
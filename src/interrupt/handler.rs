@@ -0,0 +1,143 @@
+use super::Context;
+use crate::driver::plic;
+use crate::kernel::syscall::syscall_handler;
+use crate::kernel::user;
+use crate::mem::{Flags, VirtualAddress};
+use crate::process::PROCESSOR;
+use crate::sbi;
+use crate::timer;
+use riscv::register::{
+    scause::{self, Exception, Interrupt, Scause, Trap},
+    sepc,
+    sstatus::SPP,
+    stval,
+};
+use riscv_sbi::println;
+
+/// 中断 / 异常的分发，由 `__interrupt` 调用
+///
+/// 需要切换线程时，会在处理函数内部切换到其他线程，等再次被调度时才返回，
+/// 因此返回的总是当前线程的 [`Context`]
+#[no_mangle]
+pub extern "C" fn handle_trap(context: &mut Context) -> *mut Context {
+    let scause = scause::read();
+    let stval = stval::read();
+    match scause.cause() {
+        Trap::Interrupt(Interrupt::SupervisorTimer) => supervisor_timer(context),
+        Trap::Interrupt(Interrupt::SupervisorSoft) => supervisor_soft(context),
+        Trap::Interrupt(Interrupt::SupervisorExternal) => supervisor_external(context),
+        Trap::Exception(Exception::Breakpoint) => breakpoint(context),
+        Trap::Exception(Exception::UserEnvCall) => syscall_handler(context),
+        Trap::Exception(Exception::LoadPageFault)
+        | Trap::Exception(Exception::StorePageFault)
+        | Trap::Exception(Exception::InstructionPageFault) => page_fault(context, scause, stval),
+        _ => fault(context, scause, stval, "unhandled trap"),
+    }
+}
+
+/// 内核栈溢出，`__interrupt` 发现栈上放不下 [`Context`] 时切换到备用栈后调用
+#[no_mangle]
+pub extern "C" fn kernel_stack_overflow(sp: usize) -> ! {
+    panic!(
+        "kernel stack overflow: {:?} at {:#x}, sepc = {:#x}, sp = {:#x}",
+        scause::read().cause(),
+        stval::read(),
+        sepc::read(),
+        sp
+    )
+}
+
+/// 时钟中断：执行到期的定时器，时间片用完时让出当前线程
+fn supervisor_timer(context: &mut Context) -> *mut Context {
+    let slice_expired = timer::handle_timer_interrupt();
+    if slice_expired {
+        PROCESSOR.get().yield_current_thread();
+    }
+    context
+}
+
+/// 核间中断：用于唤醒空闲的核，调度循环会在返回后重新选择线程
+///
+/// exec 也用它让进程中其他仍在运行的线程尽快离开，见 [`crate::process::Thread::exec`]
+fn supervisor_soft(context: &mut Context) -> *mut Context {
+    sbi::clear_ipi();
+    // 停止的核把定时器交给启动核，需要按照最早的定时器重新设置时钟
+    timer::program_next();
+    let processor = PROCESSOR.get();
+    if processor.has_current_thread() && processor.current_thread().is_stale() {
+        processor.yield_current_thread();
+    }
+    context
+}
+
+/// 外部中断：由 PLIC 分发给设备的驱动
+fn supervisor_external(context: &mut Context) -> *mut Context {
+    plic::handle_interrupt();
+    context
+}
+
+fn breakpoint(context: &mut Context) -> *mut Context {
+    println!("Breakpoint at 0x{:x}", context.sepc);
+    context.sepc += 2;
+    context
+}
+
+/// 处理缺页异常
+///
+/// 在当前进程的 [`crate::mem::MemorySet`] 中为地址分配页面；如果地址不合法，
+/// 用户态的异常会终止当前进程，[`user`] 复制用户内存时的异常使复制返回失败，
+/// 内核其他地方的异常则直接 panic
+fn page_fault(context: &mut Context, scause: Scause, stval: usize) -> *mut Context {
+    // 内核只在复制用户内存时访问用户的页面，其他地方的缺页异常都是内核的错误
+    if context.sstatus.spp() == SPP::Supervisor && !user::in_user_copy(context) {
+        return fault(context, scause, stval, "page fault in kernel");
+    }
+    let access = match scause.cause() {
+        Trap::Exception(Exception::StorePageFault) => Flags::WRITABLE,
+        Trap::Exception(Exception::InstructionPageFault) => Flags::EXECUTABLE,
+        _ => Flags::READABLE,
+    };
+    let result = PROCESSOR
+        .get()
+        .current_thread()
+        .process()
+        .write()
+        .memory_set
+        .handle_page_fault(VirtualAddress(stval), access);
+    match result {
+        Ok(()) => context,
+        Err(_) if context.sstatus.spp() == SPP::Supervisor => {
+            user::abort_user_copy(context);
+            context
+        }
+        Err(error) => fault(context, scause, stval, error.message()),
+    }
+}
+
+/// 无法处理的异常：来自用户态则终止当前进程，来自内核态则 panic
+fn fault(context: &mut Context, scause: Scause, stval: usize, message: &str) -> *mut Context {
+    if context.sstatus.spp() == SPP::User {
+        println!(
+            "[Kernel] Process {:?} killed: {:?} at {:#x}, sepc = {:#x}: {}",
+            PROCESSOR
+                .get()
+                .current_thread()
+                .process()
+                .read()
+                .process_id(),
+            scause.cause(),
+            stval,
+            context.sepc,
+            message
+        );
+        PROCESSOR.get().kill_current_process()
+    } else {
+        panic!(
+            "{:?} in kernel at {:#x}, sepc = {:#x}: {}",
+            scause.cause(),
+            stval,
+            context.sepc,
+            message
+        )
+    }
+}
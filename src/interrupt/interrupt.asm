@@ -0,0 +1,165 @@
+# 中断 / 异常的入口和出口
+#
+# 在用户态运行时，sscratch 保存当前线程内核栈的栈顶（即 Context 的上方）；
+# 在内核态运行时，sscratch 为 0。据此判断中断来自用户态还是内核态。
+#
+# Context 的布局见 context.rs：x1 到 x31、sstatus、sepc，以及保存内核 tp 的一项
+
+    .set    CONTEXT_SIZE, 34
+
+# 线程内核栈所在的区域，与 mem/address.rs 中的 SHARED_KERNEL_AREA_START 和 MMIO_AREA_START 一致
+    .set    KERNEL_STACK_AREA_START, 0xfffffffe00000000
+    .set    KERNEL_STACK_AREA_END, 0xfffffffe20000000
+# 每个内核栈占用 2^KERNEL_STACK_SLOT_SHIFT 字节，栈在上一半，下一半不映射，见 process/kernel_stack.rs
+    .set    KERNEL_STACK_SLOT_SHIFT, 18
+# 内核栈溢出时每个核使用的备用栈
+    .set    EMERGENCY_STACK_SIZE, 4096 * 4
+    .set    MAX_HART_COUNT, 8
+
+    .section .text
+    .globl __interrupt
+    .balign 4
+__interrupt:
+    # 交换 sp 和 sscratch。来自用户态时 sp 变为内核栈顶
+    csrrw   sp, sscratch, sp
+    bnez    sp, 1f
+    # 来自内核态，换回原来的 sp，直接在当前的栈上保存 Context
+    csrrw   sp, sscratch, sp
+    # 先检查栈上是否还放得下 Context，否则保存 Context 时会在保护区域中再次触发异常，
+    # 无限地陷入下去。检查时借用 sscratch 暂存 t0，之后恢复为 0
+    csrw    sscratch, t0
+    # 不在线程内核栈区域中的（启动时的栈）不检查
+    li      t0, KERNEL_STACK_AREA_START
+    bltu    sp, t0, 5f
+    li      t0, KERNEL_STACK_AREA_END
+    bgtu    sp, t0, 5f
+    # sp 在所属区域中的偏移。为 0 时 sp 恰好是栈顶，栈是空的
+    li      t0, (1 << KERNEL_STACK_SLOT_SHIFT) - 1
+    and     t0, t0, sp
+    beqz    t0, 5f
+    # 放入 Context 之后仍然要在区域的上一半中
+    addi    t0, t0, -CONTEXT_SIZE*8
+    bltz    t0, 6f
+    srli    t0, t0, KERNEL_STACK_SLOT_SHIFT - 1
+    beqz    t0, 6f
+5:
+    csrrw   t0, sscratch, x0
+1:
+    addi    sp, sp, -CONTEXT_SIZE*8
+    sd      x1, 0*8(sp)
+    sd      x3, 2*8(sp)
+    sd      x4, 3*8(sp)
+    sd      x5, 4*8(sp)
+    sd      x6, 5*8(sp)
+    sd      x7, 6*8(sp)
+    sd      x8, 7*8(sp)
+    sd      x9, 8*8(sp)
+    sd      x10, 9*8(sp)
+    sd      x11, 10*8(sp)
+    sd      x12, 11*8(sp)
+    sd      x13, 12*8(sp)
+    sd      x14, 13*8(sp)
+    sd      x15, 14*8(sp)
+    sd      x16, 15*8(sp)
+    sd      x17, 16*8(sp)
+    sd      x18, 17*8(sp)
+    sd      x19, 18*8(sp)
+    sd      x20, 19*8(sp)
+    sd      x21, 20*8(sp)
+    sd      x22, 21*8(sp)
+    sd      x23, 22*8(sp)
+    sd      x24, 23*8(sp)
+    sd      x25, 24*8(sp)
+    sd      x26, 25*8(sp)
+    sd      x27, 26*8(sp)
+    sd      x28, 27*8(sp)
+    sd      x29, 28*8(sp)
+    sd      x30, 29*8(sp)
+    sd      x31, 30*8(sp)
+    # 取出原来的 sp，同时将 sscratch 置 0，表示已经进入内核态
+    csrrw   t0, sscratch, x0
+    bnez    t0, 2f
+    # 来自内核态，原来的 sp 就在 Context 上方
+    addi    t0, sp, CONTEXT_SIZE*8
+    j       3f
+2:
+    # 来自用户态，恢复内核的 tp
+    ld      tp, 33*8(sp)
+3:
+    sd      t0, 1*8(sp)
+    csrr    t1, sstatus
+    csrr    t2, sepc
+    sd      t1, 31*8(sp)
+    sd      t2, 32*8(sp)
+
+    # handle_trap(context: &mut Context) -> *mut Context
+    mv      a0, sp
+    call    handle_trap
+
+    # 返回值为要恢复的 Context，继续执行 __restore
+
+# __restore(context: *mut Context) -> !
+#
+# 从 Context 中恢复所有寄存器并 sret
+    .globl __restore
+__restore:
+    mv      sp, a0
+    ld      t0, 31*8(sp)
+    ld      t1, 32*8(sp)
+    csrw    sstatus, t0
+    csrw    sepc, t1
+    # SPP 为 0 表示返回用户态：记录内核栈顶和内核的 tp，供下一次陷入时使用
+    andi    t0, t0, 1 << 8
+    bnez    t0, 4f
+    addi    t1, sp, CONTEXT_SIZE*8
+    csrw    sscratch, t1
+    sd      tp, 33*8(sp)
+4:
+    ld      x1, 0*8(sp)
+    ld      x3, 2*8(sp)
+    ld      x4, 3*8(sp)
+    ld      x5, 4*8(sp)
+    ld      x6, 5*8(sp)
+    ld      x7, 6*8(sp)
+    ld      x8, 7*8(sp)
+    ld      x9, 8*8(sp)
+    ld      x10, 9*8(sp)
+    ld      x11, 10*8(sp)
+    ld      x12, 11*8(sp)
+    ld      x13, 12*8(sp)
+    ld      x14, 13*8(sp)
+    ld      x15, 14*8(sp)
+    ld      x16, 15*8(sp)
+    ld      x17, 16*8(sp)
+    ld      x18, 17*8(sp)
+    ld      x19, 18*8(sp)
+    ld      x20, 19*8(sp)
+    ld      x21, 20*8(sp)
+    ld      x22, 21*8(sp)
+    ld      x23, 22*8(sp)
+    ld      x24, 23*8(sp)
+    ld      x25, 24*8(sp)
+    ld      x26, 25*8(sp)
+    ld      x27, 26*8(sp)
+    ld      x28, 27*8(sp)
+    ld      x29, 28*8(sp)
+    ld      x30, 29*8(sp)
+    ld      x31, 30*8(sp)
+    ld      x2, 1*8(sp)
+    sret
+
+6:
+    # 内核栈溢出：切换到当前核的备用栈，报告后 panic，不再返回
+    # 内核态中 tp 保存着核的编号
+    mv      a0, sp
+    la      sp, __emergency_stack
+    addi    t0, tp, 1
+    li      t1, EMERGENCY_STACK_SIZE
+    mul     t0, t0, t1
+    add     sp, sp, t0
+    call    kernel_stack_overflow
+
+    .section .bss.emergency_stack
+    .balign 4096
+__emergency_stack:
+    .space  EMERGENCY_STACK_SIZE * MAX_HART_COUNT
@@ -1,4 +1,11 @@
 pub mod condvar;
+mod errno;
 pub mod fs;
+pub mod linux;
+pub mod net;
 pub mod process;
 pub mod syscall;
+pub mod time;
+pub mod user;
+
+pub use errno::Errno;
@@ -12,6 +12,8 @@ pub struct Condvar {
 
 impl Condvar {
     /// 令当前线程休眠，等待此条件变量
+    ///
+    /// 线程在内核中阻塞，被唤醒后从这里返回
     pub fn wait(&self) {
         self.watchers
             .lock()
@@ -19,10 +21,37 @@ impl Condvar {
         PROCESSOR.get().sleep_current_thread();
     }
 
+    /// 释放 `guard` 之后令当前线程休眠，等待此条件变量
+    ///
+    /// 线程在释放锁之前就已经加入等待队列，因此持有同一把锁进行通知的一方不会错过它
+    pub fn wait_with<T>(&self, guard: T) {
+        self.watchers
+            .lock()
+            .push_back(PROCESSOR.get().current_thread());
+        drop(guard);
+        PROCESSOR.get().sleep_current_thread();
+    }
+
     /// 唤起一个等待此条件变量的线程
     pub fn notify_one(&self) {
         if let Some(thread) = self.watchers.lock().pop_front() {
             PROCESSOR.get().wake_thread(thread);
         }
     }
+
+    /// 唤起所有等待此条件变量的线程
+    pub fn notify_all(&self) {
+        let watchers = core::mem::take(&mut *self.watchers.lock());
+        for thread in watchers {
+            PROCESSOR.get().wake_thread(thread);
+        }
+    }
+}
+
+impl core::fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Condvar")
+            .field("watchers", &self.watchers.lock().len())
+            .finish()
+    }
 }
@@ -0,0 +1,142 @@
+//! 系统调用的错误码
+//!
+//! 所有系统调用失败时都返回负的错误码，取值和 Linux 相同，两种调用约定可以共用。
+//! 文件系统的 [`FsError`] 和内存管理的 [`MemoryError`] 可以直接转换；
+//! 用户给出的地址不可用为 [`Errno::EFAULT`]，其余参数错误为 [`Errno::EINVAL`]
+
+use crate::mem::MemoryError;
+use rcore_fs::vfs::FsError;
+
+/// 错误码，取值和 Linux 相同
+#[repr(isize)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EXDEV = 18,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOTTY = 25,
+    ENOSPC = 28,
+    EPIPE = 32,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    ELOOP = 40,
+    ENOTSOCK = 88,
+    EDESTADDRREQ = 89,
+    EOPNOTSUPP = 95,
+    EADDRINUSE = 98,
+    ENETDOWN = 100,
+    EISCONN = 106,
+    ENOTCONN = 107,
+    ECONNREFUSED = 111,
+}
+
+/// 每个错误码对应的说明，和 glibc 的 `strerror` 相同，通过系统调用提供给用户程序
+static STRERROR: [(Errno, &str); 34] = [
+    (Errno::EPERM, "Operation not permitted"),
+    (Errno::ENOENT, "No such file or directory"),
+    (Errno::ESRCH, "No such process"),
+    (Errno::EINTR, "Interrupted system call"),
+    (Errno::EIO, "Input/output error"),
+    (Errno::ENOEXEC, "Exec format error"),
+    (Errno::EBADF, "Bad file descriptor"),
+    (Errno::ECHILD, "No child processes"),
+    (Errno::EAGAIN, "Resource temporarily unavailable"),
+    (Errno::ENOMEM, "Cannot allocate memory"),
+    (Errno::EFAULT, "Bad address"),
+    (Errno::EBUSY, "Device or resource busy"),
+    (Errno::EEXIST, "File exists"),
+    (Errno::EXDEV, "Invalid cross-device link"),
+    (Errno::ENODEV, "No such device"),
+    (Errno::ENOTDIR, "Not a directory"),
+    (Errno::EISDIR, "Is a directory"),
+    (Errno::EINVAL, "Invalid argument"),
+    (Errno::EMFILE, "Too many open files"),
+    (Errno::ENOTTY, "Inappropriate ioctl for device"),
+    (Errno::ENOSPC, "No space left on device"),
+    (Errno::EPIPE, "Broken pipe"),
+    (Errno::ENAMETOOLONG, "File name too long"),
+    (Errno::ENOSYS, "Function not implemented"),
+    (Errno::ENOTEMPTY, "Directory not empty"),
+    (Errno::ELOOP, "Too many levels of symbolic links"),
+    (Errno::ENOTSOCK, "Socket operation on non-socket"),
+    (Errno::EDESTADDRREQ, "Destination address required"),
+    (Errno::EOPNOTSUPP, "Operation not supported"),
+    (Errno::EADDRINUSE, "Address already in use"),
+    (Errno::ENETDOWN, "Network is down"),
+    (Errno::EISCONN, "Transport endpoint is already connected"),
+    (Errno::ENOTCONN, "Transport endpoint is not connected"),
+    (Errno::ECONNREFUSED, "Connection refused"),
+];
+
+impl Errno {
+    /// 按照数值查找错误码
+    pub fn from_code(code: isize) -> Option<Self> {
+        STRERROR
+            .iter()
+            .map(|&(errno, _)| errno)
+            .find(|&errno| errno as isize == code)
+    }
+
+    /// 错误码的说明
+    pub fn message(self) -> &'static str {
+        STRERROR
+            .iter()
+            .find(|&&(errno, _)| errno == self)
+            .map(|&(_, message)| message)
+            .unwrap()
+    }
+}
+
+impl From<FsError> for Errno {
+    fn from(error: FsError) -> Self {
+        match error {
+            FsError::NotSupported => Errno::EOPNOTSUPP,
+            FsError::NotFile => Errno::EISDIR,
+            FsError::IsDir => Errno::EISDIR,
+            FsError::NotDir => Errno::ENOTDIR,
+            FsError::EntryNotFound => Errno::ENOENT,
+            FsError::EntryExist => Errno::EEXIST,
+            FsError::NotSameFs => Errno::EXDEV,
+            FsError::InvalidParam => Errno::EINVAL,
+            FsError::NoDeviceSpace => Errno::ENOSPC,
+            FsError::DirRemoved => Errno::ENOENT,
+            FsError::DirNotEmpty => Errno::ENOTEMPTY,
+            FsError::WrongFs => Errno::EINVAL,
+            FsError::DeviceError => Errno::EIO,
+            FsError::IOCTLError => Errno::ENOTTY,
+            FsError::NoDevice => Errno::ENODEV,
+            FsError::Again => Errno::EAGAIN,
+            FsError::SymLoop => Errno::ELOOP,
+            FsError::Busy => Errno::EBUSY,
+            FsError::Interrupted => Errno::EINTR,
+        }
+    }
+}
+
+impl From<MemoryError> for Errno {
+    fn from(error: MemoryError) -> Self {
+        match error {
+            MemoryError::NoMemory(_) => Errno::ENOMEM,
+            MemoryError::BadAddress(_) => Errno::EFAULT,
+            MemoryError::Invalid(_) => Errno::EINVAL,
+        }
+    }
+}
@@ -1,49 +1,203 @@
 use super::syscall::*;
+use super::user::{UserPtr, UserSlice, MAX_TRANSFER_SIZE};
+use super::Errno;
+use crate::fs::{device_node, File, ENTROPY_POOL, ROOT_INODE};
 use crate::PROCESSOR;
 use alloc::sync::Arc;
-use rcore_fs::vfs::INode;
+use alloc::vec;
+use rcore_fs::vfs::{FileType, FsError};
 
 const FUNCTION_FS_READ: usize = 0x10002000;
 const FUNCTION_FS_WRITE: usize = 0x30004000;
+const FUNCTION_FS_GETRANDOM: usize = 0x50006000;
+const FUNCTION_FS_OPEN: usize = 0x70008000;
+const FUNCTION_FS_CLOSE: usize = 0x9000A000;
+const FUNCTION_FS_DUP: usize = 0xB000C000;
+const FUNCTION_FS_DUP2: usize = 0xD000E000;
+const FUNCTION_FS_FCNTL: usize = 0xF0010000;
 
-pub fn module_fs(function: usize, param0: usize, param1: usize, param2: usize) -> SyscallResult {
-    match function {
-        FUNCTION_FS_READ => function_fs_read(param0, param1 as *const u8 as *mut _, param2),
-        FUNCTION_FS_WRITE => function_fs_write(param0, param1 as *const u8, param2),
-        _ => unimplemented!(),
-    }
+/// open 的选项，取值和 Linux 相同
+pub const O_WRONLY: usize = 0o1;
+pub const O_RDWR: usize = 0o2;
+pub const O_ACCMODE: usize = 0o3;
+pub const O_CREAT: usize = 0o100;
+pub const O_EXCL: usize = 0o200;
+pub const O_TRUNC: usize = 0o1000;
+pub const O_APPEND: usize = 0o2000;
+pub const O_CLOEXEC: usize = 0o2000000;
+
+/// fcntl 的命令：读取和设置描述符的标志，目前只有 [`FD_CLOEXEC`]
+pub const F_GETFD: usize = 1;
+pub const F_SETFD: usize = 2;
+pub const FD_CLOEXEC: usize = 1;
+
+/// getrandom 的选项：没有足够的随机数时立即返回
+const GRND_NONBLOCK: usize = 1;
+/// getrandom 的选项：只使用设备产生的随机数，和读取 `/dev/random` 相同
+const GRND_RANDOM: usize = 2;
+
+/// 注册文件相关的系统调用，读写成功时返回字节数
+///
+/// 一次最多读写 [`MAX_TRANSFER_SIZE`] 字节
+pub fn register(module: &mut SyscallModule) {
+    module.register(FUNCTION_FS_READ, "read", 3, |args, _| {
+        function_fs_read(args[0], UserSlice::new(args[1], args[2])).into()
+    });
+    module.register(FUNCTION_FS_WRITE, "write", 3, |args, _| {
+        function_fs_write(args[0], UserSlice::new(args[1], args[2])).into()
+    });
+    module.register(FUNCTION_FS_GETRANDOM, "getrandom", 3, |args, _| {
+        function_fs_getrandom(UserSlice::new(args[0], args[1]), args[2]).into()
+    });
+    module.register(FUNCTION_FS_OPEN, "open", 2, |args, _| {
+        function_fs_open(UserPtr::new(args[0]), args[1]).into()
+    });
+    module.register(FUNCTION_FS_CLOSE, "close", 1, |args, _| {
+        function_fs_close(args[0]).into()
+    });
+    module.register(FUNCTION_FS_DUP, "dup", 1, |args, _| {
+        function_fs_dup(args[0]).into()
+    });
+    module.register(FUNCTION_FS_DUP2, "dup2", 2, |args, _| {
+        function_fs_dup2(args[0], args[1]).into()
+    });
+    module.register(FUNCTION_FS_FCNTL, "fcntl", 3, |args, _| {
+        function_fs_fcntl(args[0], args[1], args[2]).into()
+    });
 }
 
-fn function_fs_read(fd: usize, buffer: *mut u8, size: usize) -> SyscallResult {
-    // 从线程中获取 inode，注意避免锁
-    let inode: Arc<dyn INode> =
-        if let Some(inode) = PROCESSOR.get().current_thread().inner().descriptors.get(fd) {
-            inode.clone()
-        } else {
-            return SyscallResult::ProceedTwo(0, 1); // err = 1
-        };
-    let buffer = unsafe { core::slice::from_raw_parts_mut(buffer, size) };
-    if let Ok(ret) = inode.read_at(0, buffer) {
-        let ret = ret as isize;
-        if ret > 0 {
-            return SyscallResult::ProceedTwo(ret, 0);
-        }
-        if ret == 0 {
-            return SyscallResult::ParkTwo(ret, 0);
+/// 取得当前进程的描述符对应的文件
+///
+/// 读写文件时可能休眠或者访问用户内存，不能持有进程的锁，因此复制一份 `Arc` 返回
+pub fn get_descriptor(fd: usize) -> Result<Arc<File>, Errno> {
+    let process = PROCESSOR.get().current_thread().process();
+    let file = process.read().files.get(fd);
+    file
+}
+
+/// 把文件放入当前进程的描述符表，返回描述符
+pub fn add_descriptor(file: File, close_on_exec: bool) -> Result<isize, Errno> {
+    let process = PROCESSOR.get().current_thread().process();
+    let fd = process.write().files.add(Arc::new(file), close_on_exec)?;
+    Ok(fd as isize)
+}
+
+fn function_fs_read(fd: usize, buffer: UserSlice<u8>) -> Result<isize, Errno> {
+    let file = get_descriptor(fd)?;
+    let buffer = buffer.truncate(MAX_TRANSFER_SIZE);
+    buffer.check_writable()?;
+    let mut data = vec![0; buffer.len()];
+    let len = file.read(&mut data)?;
+    buffer.write(&data[..len])?;
+    Ok(len as isize)
+}
+
+fn function_fs_write(fd: usize, buffer: UserSlice<u8>) -> Result<isize, Errno> {
+    let file = get_descriptor(fd)?;
+    let data = buffer.truncate(MAX_TRANSFER_SIZE).read()?;
+    Ok(file.write(&data)? as isize)
+}
+
+/// 按照 `flags` 打开 `path` 对应的文件
+///
+/// `/dev/` 下是 [`device_node`] 中的设备，不区分读写方式；其余路径在根文件系统中查找，
+/// 设置了 [`O_CREAT`] 时在已经存在的目录中创建不存在的文件
+pub fn open_file(path: &str, flags: usize) -> Result<File, Errno> {
+    if let Some(name) = path.strip_prefix("/dev/") {
+        return device_node(name).map(File::device).ok_or(Errno::ENOENT);
+    }
+    let readable = flags & O_ACCMODE != O_WRONLY;
+    let writable = flags & O_ACCMODE != 0;
+    let inode = match ROOT_INODE.lookup(path) {
+        Ok(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => return Err(Errno::EEXIST),
+        Ok(inode) => inode,
+        Err(FsError::EntryNotFound) if flags & O_CREAT != 0 => {
+            let path = path.trim_end_matches('/');
+            let (parent, name) = match path.rfind('/') {
+                Some(index) => (&path[..index], &path[index + 1..]),
+                None => ("", path),
+            };
+            if name.is_empty() {
+                return Err(Errno::EISDIR);
+            }
+            ROOT_INODE
+                .lookup(parent)?
+                .create(name, FileType::File, 0o644)?
         }
+        Err(error) => return Err(error.into()),
+    };
+    if writable && inode.metadata()?.type_ == FileType::Dir {
+        return Err(Errno::EISDIR);
+    }
+    if writable && flags & O_TRUNC != 0 {
+        inode.resize(0)?;
     }
-    SyscallResult::ProceedTwo(0, 1) // err = 1
+    Ok(File::new(inode, readable, writable, flags & O_APPEND != 0))
 }
 
-fn function_fs_write(fd: usize, buffer: *const u8, size: usize) -> SyscallResult {
-    if let Some(inode) = PROCESSOR.get().current_thread().inner().descriptors.get(fd) {
-        let buffer = unsafe { core::slice::from_raw_parts(buffer, size) };
-        if let Ok(ret) = inode.write_at(0, buffer) {
-            let ret = ret as isize;
-            if ret >= 0 {
-                return SyscallResult::ProceedTwo(ret, 0);
-            }
+/// 打开文件，返回最小的空闲描述符
+fn function_fs_open(path: UserPtr<u8>, flags: usize) -> Result<isize, Errno> {
+    let path = path.read_string()?;
+    let file = open_file(&path, flags)?;
+    add_descriptor(file, flags & O_CLOEXEC != 0)
+}
+
+pub fn function_fs_close(fd: usize) -> Result<isize, Errno> {
+    let process = PROCESSOR.get().current_thread().process();
+    // 文件在释放进程的锁之后才被丢弃
+    let file = process.write().files.close(fd)?;
+    drop(file);
+    Ok(0)
+}
+
+/// 复制描述符，返回最小的空闲描述符
+pub fn function_fs_dup(fd: usize) -> Result<isize, Errno> {
+    let process = PROCESSOR.get().current_thread().process();
+    let new_fd = process.write().files.dup(fd)?;
+    Ok(new_fd as isize)
+}
+
+/// 复制描述符到 `new_fd`，先关闭 `new_fd` 原来的文件，返回 `new_fd`
+pub fn function_fs_dup2(fd: usize, new_fd: usize) -> Result<isize, Errno> {
+    let process = PROCESSOR.get().current_thread().process();
+    let old = process.write().files.dup2(fd, new_fd)?;
+    drop(old);
+    Ok(new_fd as isize)
+}
+
+/// 读取或设置描述符的 [`FD_CLOEXEC`] 标志
+pub fn function_fs_fcntl(fd: usize, command: usize, arg: usize) -> Result<isize, Errno> {
+    let process = PROCESSOR.get().current_thread().process();
+    let mut process = process.write();
+    match command {
+        F_GETFD => Ok(if process.files.close_on_exec(fd)? {
+            FD_CLOEXEC as isize
+        } else {
+            0
+        }),
+        F_SETFD => {
+            process.files.set_close_on_exec(fd, arg & FD_CLOEXEC != 0)?;
+            Ok(0)
         }
+        _ => Err(Errno::EINVAL),
     }
-    SyscallResult::ProceedTwo(0, 1)
+}
+
+/// 向 buffer 中填入随机数，返回填入的字节数
+///
+/// 默认和读取 `/dev/urandom` 相同，总是填满；设置了 [`GRND_RANDOM`] 时和读取 `/dev/random` 相同，
+/// 熵池为空时等待，如果同时设置了 [`GRND_NONBLOCK`] 则返回 [`Errno::EAGAIN`]
+fn function_fs_getrandom(buffer: UserSlice<u8>, flags: usize) -> Result<isize, Errno> {
+    let buffer = buffer.truncate(MAX_TRANSFER_SIZE);
+    buffer.check_writable()?;
+    let mut data = vec![0; buffer.len()];
+    let len = if flags & GRND_RANDOM == 0 {
+        ENTROPY_POOL.read_pseudo(&mut data)
+    } else {
+        ENTROPY_POOL
+            .read_random(&mut data, flags & GRND_NONBLOCK == 0)
+            .ok_or(Errno::EAGAIN)?
+    };
+    buffer.write(&data[..len])?;
+    Ok(len as isize)
 }
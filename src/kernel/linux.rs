@@ -0,0 +1,237 @@
+//! Linux riscv64 约定的系统调用
+//!
+//! `a7` 为系统调用号，`a0` 到 `a5` 为参数，返回值放在 `a0` 中，失败时为负的错误码。
+//! 目前实现的调用足以运行静态链接的 musl 程序
+
+use super::fs::*;
+use super::syscall::{SyscallModule, SyscallResult};
+use super::time::TimeSpec;
+use super::user::{UserPtr, UserSlice, MAX_TRANSFER_SIZE};
+use super::Errno;
+use crate::mem::{Flags, VirtualAddress, VirtualPageNumber, PAGE_SIZE};
+use crate::process::Process;
+use crate::time::{monotonic, realtime};
+use crate::PROCESSOR;
+use alloc::vec;
+
+const SYS_DUP: usize = 23;
+const SYS_DUP3: usize = 24;
+const SYS_FCNTL: usize = 25;
+const SYS_IOCTL: usize = 29;
+const SYS_OPENAT: usize = 56;
+const SYS_CLOSE: usize = 57;
+const SYS_READ: usize = 63;
+const SYS_WRITE: usize = 64;
+const SYS_WRITEV: usize = 66;
+const SYS_EXIT: usize = 93;
+const SYS_EXIT_GROUP: usize = 94;
+const SYS_SET_TID_ADDRESS: usize = 96;
+const SYS_CLOCK_GETTIME: usize = 113;
+const SYS_GETPID: usize = 172;
+const SYS_BRK: usize = 214;
+const SYS_MUNMAP: usize = 215;
+const SYS_MMAP: usize = 222;
+
+/// openat 的 `dirfd`：相对于当前目录，目前当前目录总是根目录
+const AT_FDCWD: isize = -100;
+
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+const CLOCK_MONOTONIC_RAW: usize = 4;
+const CLOCK_BOOTTIME: usize = 7;
+
+const PROT_READ: usize = 1;
+const PROT_WRITE: usize = 2;
+const PROT_EXEC: usize = 4;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+/// writev 一次最多使用的缓冲区个数
+const IOV_MAX: usize = 1024;
+
+/// writev 使用的缓冲区描述
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IoVec {
+    base: usize,
+    len: usize,
+}
+
+/// 注册 Linux 约定的系统调用，函数编号为系统调用号
+pub fn register(module: &mut SyscallModule) {
+    module.register(SYS_DUP, "dup", 1, |args, _| function_fs_dup(args[0]).into());
+    module.register(SYS_DUP3, "dup3", 3, |args, _| {
+        sys_dup3(args[0], args[1], args[2]).into()
+    });
+    module.register(SYS_FCNTL, "fcntl", 3, |args, _| {
+        function_fs_fcntl(args[0], args[1], args[2]).into()
+    });
+    module.register(SYS_IOCTL, "ioctl", 3, |_, _| {
+        SyscallResult::Error(Errno::ENOTTY)
+    });
+    module.register(SYS_OPENAT, "openat", 3, |args, _| {
+        sys_openat(args[0] as isize, UserPtr::new(args[1]), args[2]).into()
+    });
+    module.register(SYS_CLOSE, "close", 1, |args, _| {
+        function_fs_close(args[0]).into()
+    });
+    module.register(SYS_READ, "read", 3, |args, _| {
+        sys_read(args[0], UserSlice::new(args[1], args[2])).into()
+    });
+    module.register(SYS_WRITE, "write", 3, |args, _| {
+        sys_write(args[0], UserSlice::new(args[1], args[2])).into()
+    });
+    module.register(SYS_WRITEV, "writev", 3, |args, _| {
+        sys_writev(args[0], UserSlice::new(args[1], args[2])).into()
+    });
+    // 目前每个进程只有一个线程，退出线程就是退出进程
+    module.register(SYS_EXIT, "exit", 1, |args, _| sys_exit(args[0] as i32));
+    module.register(SYS_EXIT_GROUP, "exit_group", 1, |args, _| {
+        sys_exit(args[0] as i32)
+    });
+    module.register(SYS_SET_TID_ADDRESS, "set_tid_address", 1, |_, _| {
+        SyscallResult::Proceed(PROCESSOR.get().current_thread().thread_id().0 as isize)
+    });
+    module.register(SYS_CLOCK_GETTIME, "clock_gettime", 2, |args, _| {
+        sys_clock_gettime(args[0], UserPtr::new(args[1])).into()
+    });
+    module.register(SYS_GETPID, "getpid", 0, |_, _| {
+        let process = PROCESSOR.get().current_thread().process();
+        let process_id = process.read().process_id();
+        SyscallResult::Proceed(process_id.0 as isize)
+    });
+    module.register(SYS_BRK, "brk", 1, |args, _| sys_brk(args[0]).into());
+    module.register(SYS_MUNMAP, "munmap", 2, |args, _| {
+        sys_munmap(args[0], args[1]).into()
+    });
+    module.register(SYS_MMAP, "mmap", 5, |args, _| {
+        sys_mmap(args[0], args[1], args[2], args[3], args[4] as isize).into()
+    });
+}
+
+/// 只支持绝对路径和相对于 [`AT_FDCWD`] 的路径
+fn sys_openat(dirfd: isize, path: UserPtr<u8>, flags: usize) -> Result<isize, Errno> {
+    let path = path.read_string()?;
+    if dirfd != AT_FDCWD && !path.starts_with('/') {
+        return Err(Errno::ENOSYS);
+    }
+    let file = open_file(&path, flags)?;
+    add_descriptor(file, flags & O_CLOEXEC != 0)
+}
+
+/// 和 dup2 相同，但是 `fd` 和 `new_fd` 相同时失败，并且可以设置 close-on-exec
+fn sys_dup3(fd: usize, new_fd: usize, flags: usize) -> Result<isize, Errno> {
+    if fd == new_fd || flags & !O_CLOEXEC != 0 {
+        return Err(Errno::EINVAL);
+    }
+    function_fs_dup2(fd, new_fd)?;
+    if flags & O_CLOEXEC != 0 {
+        function_fs_fcntl(new_fd, F_SETFD, FD_CLOEXEC)?;
+    }
+    Ok(new_fd as isize)
+}
+
+/// 一次最多读取 [`MAX_TRANSFER_SIZE`] 字节
+fn sys_read(fd: usize, buffer: UserSlice<u8>) -> Result<isize, Errno> {
+    let file = get_descriptor(fd)?;
+    let buffer = buffer.truncate(MAX_TRANSFER_SIZE);
+    buffer.check_writable()?;
+    let mut data = vec![0; buffer.len()];
+    let len = file.read(&mut data)?;
+    buffer.write(&data[..len])?;
+    Ok(len as isize)
+}
+
+/// 一次最多写入 [`MAX_TRANSFER_SIZE`] 字节
+fn sys_write(fd: usize, buffer: UserSlice<u8>) -> Result<isize, Errno> {
+    let file = get_descriptor(fd)?;
+    let data = buffer.truncate(MAX_TRANSFER_SIZE).read()?;
+    Ok(file.write(&data)? as isize)
+}
+
+/// 依次写入每个缓冲区，返回写入的总字节数
+fn sys_writev(fd: usize, iov: UserSlice<IoVec>) -> Result<isize, Errno> {
+    if iov.len() > IOV_MAX {
+        return Err(Errno::EINVAL);
+    }
+    let mut total = 0;
+    for vec in iov.read()?.iter().filter(|vec| vec.len != 0) {
+        let ret = match sys_write(fd, UserSlice::new(vec.base, vec.len)) {
+            Ok(ret) => ret,
+            // 已经写入了一部分时返回写入的字节数
+            Err(errno) if total == 0 => return Err(errno),
+            Err(_) => break,
+        };
+        total += ret;
+        if (ret as usize) < vec.len {
+            break;
+        }
+    }
+    Ok(total)
+}
+
+fn sys_exit(code: i32) -> SyscallResult {
+    let thread = PROCESSOR.get().current_thread();
+    Process::exit(&thread.process(), code as isize);
+    SyscallResult::Kill
+}
+
+fn sys_clock_gettime(clock_id: usize, time: UserPtr<TimeSpec>) -> Result<isize, Errno> {
+    let nanos = match clock_id {
+        CLOCK_REALTIME => realtime(),
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_BOOTTIME => monotonic(),
+        _ => return Err(Errno::EINVAL),
+    };
+    time.write(TimeSpec::from_nanos(nanos))?;
+    Ok(0)
+}
+
+/// 移动 program break，返回新的位置；失败或者 `address` 为 0 时返回当前的位置
+fn sys_brk(address: usize) -> Result<isize, Errno> {
+    let process = PROCESSOR.get().current_thread().process();
+    let mut process = process.write();
+    if address != 0 {
+        // 失败时 Linux 返回原来的位置，而不是错误码
+        let _ = process.set_break(VirtualAddress(address));
+    }
+    Ok(process.program_break().0 as isize)
+}
+
+/// 只支持匿名的私有映射，由内核选择地址
+fn sys_mmap(
+    _address: usize,
+    length: usize,
+    prot: usize,
+    flags: usize,
+    fd: isize,
+) -> Result<isize, Errno> {
+    if length == 0 || flags & MAP_FIXED != 0 || flags & MAP_PRIVATE == 0 {
+        return Err(Errno::EINVAL);
+    }
+    if flags & MAP_ANONYMOUS == 0 || fd != -1 {
+        return Err(Errno::ENOSYS);
+    }
+    let flags = Flags::readable(prot & PROT_READ != 0)
+        | Flags::writable(prot & PROT_WRITE != 0)
+        | Flags::executable(prot & PROT_EXEC != 0);
+    let process = PROCESSOR.get().current_thread().process();
+    let result = process.write().mmap(length, flags);
+    Ok(result?.start.0 as isize)
+}
+
+/// 撤销 [`sys_mmap`] 建立的映射中的页面，可以只撤销其中的一部分
+///
+/// `address` 必须按页对齐，`length` 向上取整到页。区间中有不是 mmap 建立的页面时返回 [`Errno::EINVAL`]
+fn sys_munmap(address: usize, length: usize) -> Result<isize, Errno> {
+    if address % PAGE_SIZE != 0 || length == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let end = address.checked_add(length).ok_or(Errno::EINVAL)?;
+    let range = VirtualPageNumber::floor(VirtualAddress(address))
+        ..VirtualPageNumber::ceil(VirtualAddress(end));
+    let process = PROCESSOR.get().current_thread().process();
+    let result = process.write().munmap(range);
+    result?;
+    Ok(0)
+}
@@ -0,0 +1,109 @@
+use super::fs::{add_descriptor, get_descriptor};
+use super::syscall::SyscallModule;
+use super::user::{UserSlice, MAX_TRANSFER_SIZE};
+use super::Errno;
+use crate::fs::File;
+use crate::net::{Socket, SocketType};
+use alloc::sync::Arc;
+use alloc::vec;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+const FUNCTION_NET_SOCKET: usize = 0x50000001;
+const FUNCTION_NET_BIND: usize = 0x50000002;
+const FUNCTION_NET_LISTEN: usize = 0x50000003;
+const FUNCTION_NET_ACCEPT: usize = 0x50000004;
+const FUNCTION_NET_CONNECT: usize = 0x50000005;
+const FUNCTION_NET_SEND: usize = 0x50000006;
+const FUNCTION_NET_RECV: usize = 0x50000007;
+
+/// 注册网络相关的系统调用
+///
+/// socket 和文件共用描述符，失败时返回负的错误码
+pub fn register(module: &mut SyscallModule) {
+    module.register(FUNCTION_NET_SOCKET, "socket", 1, |args, _| {
+        function_net_socket(args[0]).into()
+    });
+    module.register(FUNCTION_NET_BIND, "bind", 2, |args, _| {
+        function_net_bind(args[0], args[1] as u16).into()
+    });
+    module.register(FUNCTION_NET_LISTEN, "listen", 1, |args, _| {
+        function_net_listen(args[0]).into()
+    });
+    module.register(FUNCTION_NET_ACCEPT, "accept", 1, |args, _| {
+        function_net_accept(args[0]).into()
+    });
+    module.register(FUNCTION_NET_CONNECT, "connect", 3, |args, _| {
+        function_net_connect(args[0], args[1] as u32, args[2] as u16).into()
+    });
+    module.register(FUNCTION_NET_SEND, "send", 3, |args, _| {
+        function_net_send(args[0], UserSlice::new(args[1], args[2])).into()
+    });
+    module.register(FUNCTION_NET_RECV, "recv", 3, |args, _| {
+        function_net_recv(args[0], UserSlice::new(args[1], args[2])).into()
+    });
+}
+
+/// 把 socket 放入当前进程的描述符表，返回描述符
+fn add_socket(socket: Socket) -> Result<isize, Errno> {
+    add_descriptor(File::device(Arc::new(socket)), false)
+}
+
+/// 对描述符对应的 socket 执行操作
+fn with_socket(fd: usize, f: impl FnOnce(&Socket) -> Result<isize, Errno>) -> Result<isize, Errno> {
+    let file = get_descriptor(fd)?;
+    let socket = file
+        .inode()
+        .as_any_ref()
+        .downcast_ref::<Socket>()
+        .ok_or(Errno::ENOTSOCK)?;
+    f(socket)
+}
+
+/// 创建 socket，`socket_type` 为 1 表示 TCP，2 表示 UDP
+fn function_net_socket(socket_type: usize) -> Result<isize, Errno> {
+    let socket_type = match socket_type {
+        1 => SocketType::Stream,
+        2 => SocketType::Datagram,
+        _ => return Err(Errno::EINVAL),
+    };
+    let socket = Socket::new(socket_type)?;
+    add_socket(socket)
+}
+
+fn function_net_bind(fd: usize, port: u16) -> Result<isize, Errno> {
+    with_socket(fd, |socket| socket.bind(port).map(|_| 0))
+}
+
+fn function_net_listen(fd: usize) -> Result<isize, Errno> {
+    with_socket(fd, |socket| socket.listen().map(|_| 0))
+}
+
+/// 等待连接，返回新连接的描述符
+fn function_net_accept(fd: usize) -> Result<isize, Errno> {
+    with_socket(fd, |socket| socket.accept().and_then(add_socket))
+}
+
+/// 连接到 `address:port`，`address` 为大端序的 IPv4 地址
+fn function_net_connect(fd: usize, address: u32, port: u16) -> Result<isize, Errno> {
+    let remote = IpEndpoint::new(
+        IpAddress::Ipv4(Ipv4Address::from_bytes(&address.to_be_bytes())),
+        port,
+    );
+    with_socket(fd, |socket| socket.connect(remote).map(|_| 0))
+}
+
+/// 发送 `buffer` 中的数据，一次最多 [`MAX_TRANSFER_SIZE`] 字节
+fn function_net_send(fd: usize, buffer: UserSlice<u8>) -> Result<isize, Errno> {
+    let data = buffer.truncate(MAX_TRANSFER_SIZE).read()?;
+    with_socket(fd, |socket| socket.send(&data).map(|len| len as isize))
+}
+
+/// 接收数据到 `buffer` 中，一次最多 [`MAX_TRANSFER_SIZE`] 字节
+fn function_net_recv(fd: usize, buffer: UserSlice<u8>) -> Result<isize, Errno> {
+    let buffer = buffer.truncate(MAX_TRANSFER_SIZE);
+    buffer.check_writable()?;
+    let mut data = vec![0; buffer.len()];
+    let len = with_socket(fd, |socket| socket.recv(&mut data).map(|len| len as isize))?;
+    buffer.write(&data[..len as usize])?;
+    Ok(len)
+}
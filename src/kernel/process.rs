@@ -1,16 +1,73 @@
-use super::syscall::SyscallResult;
+use super::syscall::{SyscallModule, SyscallResult};
+use super::user::{UserPtr, UserSlice};
+use super::Errno;
+use crate::driver::syscon::{poweroff, reboot};
+use crate::fs::{INodeExt, ROOT_INODE};
+use crate::interrupt::Context;
+use crate::mem::{MemoryError, MemorySet};
+use crate::process::{auxiliary_vector, Personality, Process};
+use crate::timer::MAX_TIME_SLICE;
 use crate::PROCESSOR;
+use alloc::string::String;
+use alloc::vec::Vec;
 use riscv_sbi::println;
+use xmas_elf::ElfFile;
 
 const FUNCTION_PROCESS_EXIT: usize = 0x99998888;
 const FUNCTION_PROCESS_GET_ID: usize = 0x77776666;
+const FUNCTION_PROCESS_FORK: usize = 0x55554444;
+const FUNCTION_PROCESS_EXEC: usize = 0x33332222;
+const FUNCTION_PROCESS_WAIT: usize = 0x11110000;
+const FUNCTION_PROCESS_REBOOT: usize = 0xFEE1DEAD;
+const FUNCTION_PROCESS_YIELD: usize = 0x12345678;
+const FUNCTION_PROCESS_SET_TIME_SLICE: usize = 0x87654321;
+const FUNCTION_PROCESS_STRERROR: usize = 0x24681357;
 
-pub fn module_process(function: usize, param0: usize) -> SyscallResult {
-    match function {
-        FUNCTION_PROCESS_EXIT => function_process_exit(param0),
-        FUNCTION_PROCESS_GET_ID => function_process_get_id(),
-        _ => unimplemented!(),
-    }
+/// reboot 的命令：关机，参数为退出码
+const REBOOT_CMD_POWER_OFF: usize = 0x4321FEDC;
+/// reboot 的命令：重启
+const REBOOT_CMD_RESTART: usize = 0x01234567;
+
+/// wait 的选项：没有子进程退出时立即返回
+const WAIT_NO_HANG: usize = 1;
+
+/// 注册进程相关的系统调用
+pub fn register(module: &mut SyscallModule) {
+    module.register(FUNCTION_PROCESS_EXIT, "exit", 1, |args, _| {
+        function_process_exit(args[0])
+    });
+    module.register(FUNCTION_PROCESS_GET_ID, "get_id", 0, |_, _| {
+        function_process_get_id()
+    });
+    module.register(FUNCTION_PROCESS_FORK, "fork", 0, |_, context| {
+        function_process_fork(context)
+    });
+    module.register(FUNCTION_PROCESS_EXEC, "exec", 3, |args, context| {
+        function_process_exec(
+            UserPtr::new(args[0]),
+            UserPtr::new(args[1]),
+            UserPtr::new(args[2]),
+            context,
+        )
+    });
+    module.register(FUNCTION_PROCESS_WAIT, "wait", 3, |args, _| {
+        function_process_wait(args[0] as isize, UserPtr::new(args[1]), args[2])
+    });
+    module.register(FUNCTION_PROCESS_REBOOT, "reboot", 2, |args, _| {
+        function_process_reboot(args[0], args[1])
+    });
+    module.register(FUNCTION_PROCESS_YIELD, "yield", 0, |_, _| {
+        function_process_yield()
+    });
+    module.register(
+        FUNCTION_PROCESS_SET_TIME_SLICE,
+        "set_time_slice",
+        1,
+        |args, _| function_process_set_time_slice(args[0]),
+    );
+    module.register(FUNCTION_PROCESS_STRERROR, "strerror", 3, |args, _| {
+        function_process_strerror(args[0], UserSlice::new(args[1], args[2]))
+    });
 }
 
 fn function_process_exit(code: usize) -> SyscallResult {
@@ -20,6 +77,7 @@ fn function_process_exit(code: usize) -> SyscallResult {
         "[Kernel] Process {:?} exited with code {}",
         process_id, code
     );
+    Process::exit(&thread.process(), code as isize);
     SyscallResult::Kill
 }
 
@@ -32,3 +90,201 @@ fn function_process_get_id() -> SyscallResult {
         .process_id();
     SyscallResult::Proceed(process_id.0 as isize)
 }
+
+/// 让出 CPU，当前线程仍然就绪，下次被调度时返回 0
+fn function_process_yield() -> SyscallResult {
+    PROCESSOR.get().yield_current_thread();
+    SyscallResult::Proceed(0)
+}
+
+/// 设置当前线程的时间片长度（微秒），为 0 时恢复使用全局的设置
+///
+/// 返回原来的设置，没有设置过时为 0。新的长度从下一次调度开始生效，
+/// 超过 [`MAX_TIME_SLICE`] 时返回 [`Errno::EINVAL`]
+fn function_process_set_time_slice(micros: usize) -> SyscallResult {
+    if micros as u64 > MAX_TIME_SLICE {
+        return SyscallResult::Error(Errno::EINVAL);
+    }
+    let thread = PROCESSOR.get().current_thread();
+    let mut inner = thread.inner();
+    let old = inner.time_slice.unwrap_or(0);
+    inner.time_slice = if micros == 0 {
+        None
+    } else {
+        Some(micros as u64)
+    };
+    SyscallResult::Proceed(old as isize)
+}
+
+/// 复制当前进程和线程
+///
+/// 父进程中返回子进程的编号，子进程中返回 0，内存不足时返回 [`Errno::ENOMEM`]
+fn function_process_fork(context: &Context) -> SyscallResult {
+    let thread = PROCESSOR.get().current_thread();
+    let process = match thread.process().write().fork() {
+        Ok(process) => process,
+        Err(error) => return SyscallResult::Error(error.into()),
+    };
+    let process_id = process.read().process_id();
+    // 子线程从 ecall 的下一条指令继续执行，返回值为 0
+    let mut child_context = *context;
+    child_context.a0 = 0;
+    let child_thread = match thread.fork(process.clone(), child_context) {
+        Ok(child_thread) => child_thread,
+        Err(error) => return SyscallResult::Error(error.into()),
+    };
+    Process::adopt(&thread.process(), process);
+    PROCESSOR.get().add_thread(child_thread);
+    SyscallResult::Proceed(process_id.0 as isize)
+}
+
+/// 读取用户空间中以空指针结尾的字符串数组，空指针视为空数组
+fn read_user_string_array(pointer: UserPtr<UserPtr<u8>>) -> Result<Vec<String>, Errno> {
+    let mut strings = Vec::new();
+    if pointer.is_null() {
+        return Ok(strings);
+    }
+    loop {
+        let string = pointer.add(strings.len()).read()?;
+        if string.is_null() {
+            return Ok(strings);
+        }
+        strings.push(string.read_string()?);
+    }
+}
+
+/// 从文件系统中读取程序，替换当前进程的地址空间并执行
+///
+/// `argv` 和 `envp` 是以空指针结尾的字符串数组，会按照 RISC-V psABI 的约定放在新程序的栈上。
+/// 成功时从新程序的入口开始执行，`a0` 为 `argc`；找不到文件时返回文件系统的错误，
+/// ELF 格式错误时返回 [`Errno::ENOEXEC`]
+fn function_process_exec(
+    path: UserPtr<u8>,
+    argv: UserPtr<UserPtr<u8>>,
+    envp: UserPtr<UserPtr<u8>>,
+    context: &mut Context,
+) -> SyscallResult {
+    // 在替换地址空间之前读出所有参数
+    let arguments = path.read_string().and_then(|path| {
+        Ok((
+            path,
+            read_user_string_array(argv)?,
+            read_user_string_array(envp)?,
+        ))
+    });
+    let (path, args, envs) = match arguments {
+        Ok(arguments) => arguments,
+        Err(errno) => return SyscallResult::Error(errno),
+    };
+    let data = match ROOT_INODE.lookup(&path).and_then(|inode| inode.readall()) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("[Kernel] exec {}: {:?}", path, err);
+            return SyscallResult::Error(err.into());
+        }
+    };
+    // 解析 ELF 文件并建立新的地址空间
+    let loaded = ElfFile::new(data.as_slice())
+        .map_err(MemoryError::from)
+        .and_then(|elf| {
+            let memory_set = MemorySet::from_elf(&elf, true)?;
+            Ok((
+                memory_set,
+                Personality::from_elf(&elf),
+                elf.header.pt2.entry_point() as usize,
+                auxiliary_vector(&elf),
+            ))
+        });
+    let (memory_set, personality, entry_point, auxv) = match loaded {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            println!("[Kernel] exec {}: {}", path, error);
+            // 文件格式不对时为 ENOEXEC，而不是一般的参数错误
+            return SyscallResult::Error(match error {
+                MemoryError::Invalid(_) => Errno::ENOEXEC,
+                error => error.into(),
+            });
+        }
+    };
+    let thread = PROCESSOR.get().current_thread();
+    match thread.exec(
+        memory_set,
+        personality,
+        entry_point,
+        &auxv,
+        &args,
+        &envs,
+        context,
+    ) {
+        Ok(()) => SyscallResult::Proceed(args.len() as isize),
+        // 进程已经被终止，或者其他线程先一步 exec，当前线程直接结束
+        Err(_) if thread.is_stale() => SyscallResult::Kill,
+        Err(message) => {
+            // 旧的地址空间已经被替换，无法再返回原来的程序
+            println!("[Kernel] exec {}: {}", path, message);
+            Process::exit(&thread.process(), -1);
+            SyscallResult::Kill
+        }
+    }
+}
+
+/// 等待子进程退出并回收
+///
+/// `pid` 为 -1 时等待任意子进程，退出码写入 `status`（可以为空指针）。返回被回收的子进程编号；
+/// 没有符合条件的子进程时返回 [`Errno::ECHILD`]。子进程都没有退出时，如果设置了 [`WAIT_NO_HANG`] 则返回 0，
+/// 否则休眠直到有子进程退出
+fn function_process_wait(pid: isize, status: UserPtr<isize>, options: usize) -> SyscallResult {
+    let process = PROCESSOR.get().current_thread().process();
+    loop {
+        let mut process = process.write();
+        match process.reap_child(pid) {
+            Ok(Some((child_id, code))) => {
+                // 写入用户内存时可能发生缺页异常，不能持有进程的锁
+                drop(process);
+                if !status.is_null() {
+                    if let Err(errno) = status.write(code) {
+                        return SyscallResult::Error(errno);
+                    }
+                }
+                return SyscallResult::Proceed(child_id.0 as isize);
+            }
+            Ok(None) if options & WAIT_NO_HANG != 0 => return SyscallResult::Proceed(0),
+            Ok(None) => {
+                // 休眠前必须释放进程的锁，被唤醒后重新检查
+                let child_exited = process.child_exited();
+                child_exited.wait_with(process);
+            }
+            Err(_) => return SyscallResult::Error(Errno::ECHILD),
+        }
+    }
+}
+
+/// 关机或重启整个系统，成功时不会返回；命令无法识别时返回 [`Errno::EINVAL`]
+///
+/// 关机时 `code` 作为退出码，在 QEMU 中会成为 QEMU 的退出状态，便于自动测试判断结果
+fn function_process_reboot(command: usize, code: usize) -> SyscallResult {
+    match command {
+        REBOOT_CMD_POWER_OFF => poweroff(code),
+        REBOOT_CMD_RESTART => reboot(),
+        _ => SyscallResult::Error(Errno::EINVAL),
+    }
+}
+
+/// 把错误码 `errno`（正数）的说明写入 `buffer`，返回说明的长度
+///
+/// 说明以 0 结尾，`buffer` 不够长时截断；错误码无法识别时返回 [`Errno::EINVAL`]
+fn function_process_strerror(errno: usize, buffer: UserSlice<u8>) -> SyscallResult {
+    let message = match Errno::from_code(errno as isize) {
+        Some(errno) => errno.message(),
+        None => return SyscallResult::Error(Errno::EINVAL),
+    };
+    if !buffer.is_empty() {
+        let len = message.len().min(buffer.len() - 1);
+        let mut data = message.as_bytes()[..len].to_vec();
+        data.push(0);
+        if let Err(errno) = buffer.write(&data) {
+            return SyscallResult::Error(errno);
+        }
+    }
+    SyscallResult::Proceed(message.len() as isize)
+}
@@ -1,60 +1,205 @@
+//! 系统调用的分发
+//!
+//! 各模块在 `register` 中把自己的系统调用注册到 [`SyscallTable`]，Linux 约定的调用也注册在其中，
+//! 没有注册的调用返回 [`Errno::ENOSYS`]。启动参数 `syscall_log=unknown` 时输出没有注册的调用，
+//! `syscall_log=all` 时输出所有的调用
+
+use super::Errno;
+use crate::interrupt::Context;
+use crate::process::Personality;
 use crate::PROCESSOR;
-use riscv_sbi_rt::TrapFrame as Context;
+use alloc::collections::BTreeMap;
+use core::fmt;
+use lazy_static::lazy_static;
+use riscv_sbi::println;
 
 const MODULE_PROCESS: usize = 0x23336666;
 const MODULE_FS: usize = 0xF0114514;
+const MODULE_NET: usize = 0x4E455453;
+const MODULE_TIME: usize = 0x54494D45;
+/// Linux 约定的调用在表中的模块编号，函数编号为系统调用号。本内核约定的调用不能使用这个模块
+const MODULE_LINUX: usize = 0x4C4E5558;
 
 pub enum SyscallResult {
     /// 继续执行，带返回值
     Proceed(isize),
-    /// 返回两个值
-    ProceedTwo(isize, isize),
-    /// 记录返回值，但暂存当前线程
-    Park(isize),
-    /// 返回两个值
-    ParkTwo(isize, isize),
-    /// 丢弃当前 context，调度下一个线程继续执行
+    /// 继续执行，返回负的错误码
+    Error(Errno),
+    /// 终止当前线程，调度下一个线程继续执行
     Kill,
 }
 
+/// 系统调用的处理函数，参数为六个寄存器：本内核的约定从 `a2` 开始，Linux 的约定从 `a0` 开始
+pub type SyscallHandler = fn([usize; 6], &mut Context) -> SyscallResult;
+
+/// 注册的一个系统调用
+struct SyscallEntry {
+    name: &'static str,
+    /// 使用的参数个数，输出日志时只输出这些参数
+    arg_count: usize,
+    handler: SyscallHandler,
+}
+
+/// 按照模块编号和函数编号索引的系统调用表
+pub struct SyscallTable(BTreeMap<(usize, usize), SyscallEntry>);
+
+/// 系统调用表中的一个模块，各模块通过它注册自己的系统调用
+pub struct SyscallModule<'a> {
+    table: &'a mut SyscallTable,
+    module: usize,
+}
+
+impl SyscallTable {
+    fn module(&mut self, module: usize) -> SyscallModule {
+        SyscallModule {
+            table: self,
+            module,
+        }
+    }
+}
+
+impl SyscallModule<'_> {
+    /// 注册函数编号为 `function` 的系统调用，同一个编号不能注册两次
+    pub fn register(
+        &mut self,
+        function: usize,
+        name: &'static str,
+        arg_count: usize,
+        handler: SyscallHandler,
+    ) {
+        let entry = SyscallEntry {
+            name,
+            arg_count,
+            handler,
+        };
+        let old = self.table.0.insert((self.module, function), entry);
+        assert!(old.is_none(), "syscall {} registered twice", name);
+    }
+}
+
+/// 系统调用的日志，由启动参数 `syscall_log=` 指定
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+enum SyscallLog {
+    Off,
+    Unknown,
+    All,
+}
+
+lazy_static! {
+    /// 所有模块的系统调用，第一次系统调用时建立
+    static ref SYSCALL_TABLE: SyscallTable = {
+        let mut table = SyscallTable(BTreeMap::new());
+        super::process::register(&mut table.module(MODULE_PROCESS));
+        super::fs::register(&mut table.module(MODULE_FS));
+        super::net::register(&mut table.module(MODULE_NET));
+        super::time::register(&mut table.module(MODULE_TIME));
+        super::linux::register(&mut table.module(MODULE_LINUX));
+        table
+    };
+    static ref SYSCALL_LOG: SyscallLog = match crate::driver::syscall_log() {
+        Some("all") => SyscallLog::All,
+        Some("unknown") => SyscallLog::Unknown,
+        _ => SyscallLog::Off,
+    };
+}
+
+impl From<Result<isize, Errno>> for SyscallResult {
+    fn from(result: Result<isize, Errno>) -> Self {
+        match result {
+            Ok(ret) => SyscallResult::Proceed(ret),
+            Err(errno) => SyscallResult::Error(errno),
+        }
+    }
+}
+
+/// 系统调用的入口，按照当前进程的 [`Personality`] 选择分发的方式
 pub fn syscall_handler(context: &mut Context) -> *mut Context {
     // 无论如何处理，一定会跳过当前的 ecall 指令
     context.sepc += 4;
 
-    let ans = match context.a0 {
-        MODULE_PROCESS => super::process::module_process(context.a1, context.a2),
-        MODULE_FS => super::fs::module_fs(context.a1, context.a2, context.a3, context.a4),
-        _ => unimplemented!(),
+    let personality = PROCESSOR
+        .get()
+        .current_thread()
+        .process()
+        .read()
+        .personality;
+    let ans = match personality {
+        Personality::Native => native_syscall(context),
+        Personality::Linux => linux_syscall(context),
+    };
+    finish(context, ans)
+}
+
+/// 本内核自己的约定：`a0` 为模块编号，`a1` 为函数编号，`a2` 开始为参数，
+/// 返回值放在 `a0` 中，失败时为负的错误码
+fn native_syscall(context: &mut Context) -> SyscallResult {
+    let entry = match context.a0 {
+        MODULE_LINUX => None,
+        module => SYSCALL_TABLE.0.get(&(module, context.a1)),
+    };
+    let entry = match entry {
+        Some(entry) => entry,
+        None => {
+            log_unknown_syscall(format_args!(
+                "module {:#x}, function {:#x}",
+                context.a0, context.a1
+            ));
+            return SyscallResult::Error(Errno::ENOSYS);
+        }
     };
+    let args = [
+        context.a2, context.a3, context.a4, context.a5, context.a6, context.a7,
+    ];
+    call(entry, args, context)
+}
+
+/// Linux riscv64 的约定：`a7` 为系统调用号，`a0` 到 `a5` 为参数，
+/// 返回值放在 `a0` 中，失败时为负的错误码
+fn linux_syscall(context: &mut Context) -> SyscallResult {
+    let entry = match SYSCALL_TABLE.0.get(&(MODULE_LINUX, context.a7)) {
+        Some(entry) => entry,
+        None => {
+            log_unknown_syscall(format_args!("linux {}", context.a7));
+            return SyscallResult::Error(Errno::ENOSYS);
+        }
+    };
+    let args = [
+        context.a0, context.a1, context.a2, context.a3, context.a4, context.a5,
+    ];
+    call(entry, args, context)
+}
 
+/// 按照启动参数输出调用，然后执行
+fn call(entry: &SyscallEntry, args: [usize; 6], context: &mut Context) -> SyscallResult {
+    if *SYSCALL_LOG == SyscallLog::All {
+        println!(
+            "[Kernel] syscall {}{:x?}",
+            entry.name,
+            &args[..entry.arg_count]
+        );
+    }
+    (entry.handler)(args, context)
+}
+
+/// 按照启动参数输出没有实现的系统调用，`call` 描述调用的编号
+fn log_unknown_syscall(call: fmt::Arguments) {
+    if *SYSCALL_LOG >= SyscallLog::Unknown {
+        println!("[Kernel] unknown syscall: {}", call);
+    }
+}
+
+/// 按照系统调用的结果设置返回值，或者终止当前线程
+fn finish(context: &mut Context, ans: SyscallResult) -> *mut Context {
     match ans {
         SyscallResult::Proceed(ret) => {
             // 将返回值放入 context 中
             context.a0 = ret as usize;
             context
         }
-        SyscallResult::ProceedTwo(ans, err) => {
-            context.a0 = ans as usize;
-            context.a1 = err as usize;
+        SyscallResult::Error(errno) => {
+            context.a0 = -(errno as isize) as usize;
             context
         }
-        SyscallResult::Park(ret) => {
-            // 将返回值放入 context 中
-            context.a0 = ret as usize;
-            // 保存 context，准备下一个线程
-            PROCESSOR.get().park_current_thread(context);
-            PROCESSOR.get().prepare_next_thread(context)
-        }
-        SyscallResult::ParkTwo(ans, err) => {
-            context.a0 = ans as usize;
-            context.a1 = err as usize;
-            PROCESSOR.get().park_current_thread(context);
-            PROCESSOR.get().prepare_next_thread(context)
-        }
-        SyscallResult::Kill => {
-            // 终止，跳转到 PROCESSOR 调度的下一个线程
-            PROCESSOR.get().kill_current_thread();
-            PROCESSOR.get().prepare_next_thread(context)
-        }
+        SyscallResult::Kill => PROCESSOR.get().exit_current_thread(),
     }
 }
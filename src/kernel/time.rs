@@ -0,0 +1,102 @@
+use super::syscall::SyscallModule;
+use super::user::UserPtr;
+use super::Errno;
+use crate::time::{monotonic, nanos_to_ticks, realtime, NANOS_PER_SEC};
+use crate::timer;
+use riscv::register::time;
+
+const FUNCTION_TIME_CLOCK_GETTIME: usize = 0x60000001;
+const FUNCTION_TIME_GETTIMEOFDAY: usize = 0x60000002;
+const FUNCTION_TIME_NANOSLEEP: usize = 0x60000003;
+
+/// 墙上时间，自 Unix 纪元开始
+const CLOCK_REALTIME: usize = 0;
+/// 单调时间，自启动开始
+const CLOCK_MONOTONIC: usize = 1;
+
+/// 以秒和纳秒表示的时间，和 Linux 的 `struct timespec` 相同
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeSpec {
+    pub sec: i64,
+    pub nsec: i64,
+}
+
+/// 以秒和微秒表示的时间，和 Linux 的 `struct timeval` 相同
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeVal {
+    pub sec: i64,
+    pub usec: i64,
+}
+
+impl TimeSpec {
+    pub fn from_nanos(nanos: u64) -> Self {
+        Self {
+            sec: (nanos / NANOS_PER_SEC) as i64,
+            nsec: (nanos % NANOS_PER_SEC) as i64,
+        }
+    }
+
+    /// 转换为纳秒，时间不合法时返回 `None`
+    fn to_nanos(&self) -> Option<u64> {
+        if self.sec < 0 || self.nsec < 0 || self.nsec >= NANOS_PER_SEC as i64 {
+            return None;
+        }
+        (self.sec as u64)
+            .checked_mul(NANOS_PER_SEC)?
+            .checked_add(self.nsec as u64)
+    }
+}
+
+/// 注册时间相关的系统调用，成功时都返回 0
+pub fn register(module: &mut SyscallModule) {
+    module.register(
+        FUNCTION_TIME_CLOCK_GETTIME,
+        "clock_gettime",
+        2,
+        |args, _| function_time_clock_gettime(args[0], UserPtr::new(args[1])).into(),
+    );
+    module.register(FUNCTION_TIME_GETTIMEOFDAY, "gettimeofday", 1, |args, _| {
+        function_time_gettimeofday(UserPtr::new(args[0])).into()
+    });
+    module.register(FUNCTION_TIME_NANOSLEEP, "nanosleep", 2, |args, _| {
+        function_time_nanosleep(UserPtr::new(args[0]), UserPtr::new(args[1])).into()
+    });
+}
+
+/// 读取 [`CLOCK_REALTIME`] 或 [`CLOCK_MONOTONIC`] 时钟
+fn function_time_clock_gettime(clock_id: usize, result: UserPtr<TimeSpec>) -> Result<isize, Errno> {
+    let nanos = match clock_id {
+        CLOCK_REALTIME => realtime(),
+        CLOCK_MONOTONIC => monotonic(),
+        _ => return Err(Errno::EINVAL),
+    };
+    result.write(TimeSpec::from_nanos(nanos))?;
+    Ok(0)
+}
+
+/// 读取墙上时间，精确到微秒
+fn function_time_gettimeofday(result: UserPtr<TimeVal>) -> Result<isize, Errno> {
+    let nanos = realtime();
+    result.write(TimeVal {
+        sec: (nanos / NANOS_PER_SEC) as i64,
+        usec: (nanos % NANOS_PER_SEC / 1000) as i64,
+    })?;
+    Ok(0)
+}
+
+/// 休眠 `request` 指定的时间，`remain`（可以为空指针）中写入剩余的时间，总是为 0
+///
+/// 线程在休眠期间不会被调度，由定时器在期限到达时唤醒
+fn function_time_nanosleep(
+    request: UserPtr<TimeSpec>,
+    remain: UserPtr<TimeSpec>,
+) -> Result<isize, Errno> {
+    let nanos = request.read()?.to_nanos().ok_or(Errno::EINVAL)?;
+    timer::sleep_until(time::read64().saturating_add(nanos_to_ticks(nanos)));
+    if !remain.is_null() {
+        remain.write(TimeSpec::default())?;
+    }
+    Ok(0)
+}
@@ -0,0 +1,30 @@
+# 内核访问用户态内存
+#
+# 调用者负责在复制期间开启 sstatus.SUM。__copy_user 和 __copy_user_fault 之间的缺页异常
+# 如果无法处理，异常处理会把 sepc 改为 __copy_user_fault，使复制返回失败
+
+    .section .text
+
+# __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize
+#
+# 逐字节复制，不要求对齐。成功返回 0，发生无法处理的缺页异常时返回 1
+    .globl __copy_user
+    .balign 4
+__copy_user:
+    beqz    a2, 2f
+1:
+    lbu     t0, 0(a1)
+    sb      t0, 0(a0)
+    addi    a0, a0, 1
+    addi    a1, a1, 1
+    addi    a2, a2, -1
+    bnez    a2, 1b
+2:
+    li      a0, 0
+    ret
+
+    .globl __copy_user_fault
+    .balign 4
+__copy_user_fault:
+    li      a0, 1
+    ret
@@ -0,0 +1,223 @@
+//! 访问用户态内存的 [`UserPtr`] 和 [`UserSlice`]
+//!
+//! 系统调用收到的地址先按照当前进程的 [`crate::mem::MemorySet`] 检查所在的段和权限，
+//! 再由 `user.asm` 中的 `__copy_user` 复制，只在复制期间开启 `sstatus.SUM`。
+//! 复制时的缺页异常照常分配页面；无法处理时（例如段已经被其他线程撤销）复制返回 [`Errno::EFAULT`]。
+//!
+//! 复制时可能需要处理缺页异常，因此调用时不能持有当前进程的锁
+
+use super::Errno;
+use crate::interrupt::Context;
+use crate::mem::{Flags, VirtualAddress};
+use crate::PROCESSOR;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+use riscv::register::sstatus;
+
+global_asm!(include_str!("user.asm"));
+
+extern "C" {
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn __copy_user_fault();
+}
+
+/// 系统调用一次最多经过内核缓冲区读写的字节数，更长的读写只处理这么多，返回实际的字节数
+pub const MAX_TRANSFER_SIZE: usize = 0x10000;
+
+/// 从用户态读取的字符串的最大长度，包括结尾的 0
+const MAX_STRING_SIZE: usize = 4096;
+
+/// 异常是否发生在 `__copy_user` 中
+pub fn in_user_copy(context: &Context) -> bool {
+    let range = __copy_user as usize..__copy_user_fault as usize;
+    range.contains(&context.sepc)
+}
+
+/// 让发生异常的 `__copy_user` 返回失败
+pub fn abort_user_copy(context: &mut Context) {
+    context.sepc = __copy_user_fault as usize;
+}
+
+/// 检查 `address` 开始的 `size` 字节是否可以被当前进程按照 `access` 访问
+fn check(address: usize, size: usize, access: Flags) -> Result<(), Errno> {
+    if size == 0 {
+        return Ok(());
+    }
+    let end = address.checked_add(size).ok_or(Errno::EFAULT)?;
+    let process = PROCESSOR.get().current_thread().process();
+    let valid = process
+        .read()
+        .memory_set
+        .check_user_range(VirtualAddress(address)..VirtualAddress(end), access);
+    if valid {
+        Ok(())
+    } else {
+        Err(Errno::EFAULT)
+    }
+}
+
+/// 开启 SUM 后复制 `len` 字节
+unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), Errno> {
+    sstatus::set_sum();
+    let failed = __copy_user(dst, src, len);
+    sstatus::clear_sum();
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(Errno::EFAULT)
+    }
+}
+
+/// 从用户态的 `address` 复制到内核的 `dst`
+fn copy_from_user(dst: *mut u8, address: usize, len: usize) -> Result<(), Errno> {
+    check(address, len, Flags::READABLE)?;
+    unsafe { copy(dst, address as *const u8, len) }
+}
+
+/// 从内核的 `src` 复制到用户态的 `address`
+fn copy_to_user(address: usize, src: *const u8, len: usize) -> Result<(), Errno> {
+    check(address, len, Flags::WRITABLE)?;
+    unsafe { copy(address as *mut u8, src, len) }
+}
+
+/// 用户态中指向一个 `T` 的指针
+///
+/// 和用户态的指针布局相同，可以作为其他用户态结构的字段。
+/// `T` 必须是任意内容都合法的类型，例如整数和只包含整数的结构
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct UserPtr<T> {
+    address: usize,
+    _marker: PhantomData<*const T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: Copy> UserPtr<T> {
+    pub fn new(address: usize) -> Self {
+        Self {
+            address,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.address == 0
+    }
+
+    /// 向后偏移 `count` 个 `T`
+    pub fn add(self, count: usize) -> Self {
+        Self::new(self.address.wrapping_add(count * size_of::<T>()))
+    }
+
+    /// 从用户态读出
+    pub fn read(self) -> Result<T, Errno> {
+        let mut value = MaybeUninit::<T>::uninit();
+        copy_from_user(value.as_mut_ptr() as *mut u8, self.address, size_of::<T>())?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// 写入用户态
+    pub fn write(self, value: T) -> Result<(), Errno> {
+        copy_to_user(
+            self.address,
+            &value as *const T as *const u8,
+            size_of::<T>(),
+        )
+    }
+}
+
+impl UserPtr<u8> {
+    /// 读取以 0 结尾的字符串，不是 UTF-8 的部分会被替换
+    ///
+    /// 超过 [`MAX_STRING_SIZE`] 时返回 [`Errno::ENAMETOOLONG`]
+    pub fn read_string(self) -> Result<String, Errno> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.add(bytes.len()).read()?;
+            if byte == 0 {
+                break;
+            }
+            bytes.push(byte);
+            if bytes.len() >= MAX_STRING_SIZE {
+                return Err(Errno::ENAMETOOLONG);
+            }
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+/// 用户态中 `len` 个连续的 `T`，要求同 [`UserPtr`]
+#[derive(Clone, Copy, Debug)]
+pub struct UserSlice<T> {
+    ptr: UserPtr<T>,
+    len: usize,
+}
+
+impl<T: Copy> UserSlice<T> {
+    pub fn new(address: usize, len: usize) -> Self {
+        Self {
+            ptr: UserPtr::new(address),
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 只保留前 `len` 个元素
+    pub fn truncate(self, len: usize) -> Self {
+        Self {
+            ptr: self.ptr,
+            len: self.len.min(len),
+        }
+    }
+
+    fn size(&self) -> Result<usize, Errno> {
+        self.len.checked_mul(size_of::<T>()).ok_or(Errno::EFAULT)
+    }
+
+    /// 检查是否可以写入。读取设备这类无法撤销的操作之前先检查，避免数据在复制时丢失
+    pub fn check_writable(&self) -> Result<(), Errno> {
+        check(self.ptr.address, self.size()?, Flags::WRITABLE)
+    }
+
+    /// 全部读出
+    pub fn read(&self) -> Result<Vec<T>, Errno> {
+        let size = self.size()?;
+        check(self.ptr.address, size, Flags::READABLE)?;
+        let mut data = Vec::with_capacity(self.len);
+        unsafe {
+            copy(
+                data.as_mut_ptr() as *mut u8,
+                self.ptr.address as *const u8,
+                size,
+            )?;
+            data.set_len(self.len);
+        }
+        Ok(data)
+    }
+
+    /// 从头写入 `data`，超出长度的部分被忽略
+    pub fn write(&self, data: &[T]) -> Result<(), Errno> {
+        let len = data.len().min(self.len);
+        copy_to_user(
+            self.ptr.address,
+            data.as_ptr() as *const u8,
+            len * size_of::<T>(),
+        )
+    }
+}
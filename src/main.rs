@@ -8,14 +8,20 @@
 mod algo;
 mod driver;
 mod fs;
+mod interrupt;
 mod kernel;
 mod mem;
+mod net;
 mod process;
+mod sbi;
+mod time;
+mod timer;
 
 use crate::process::{Process, Thread, PROCESSOR};
-use riscv::register::{scause::Scause, sie, sip, time};
-use riscv_sbi::{self as sbi, println, HartMask};
-use riscv_sbi_rt::{entry, heap_start, interrupt, max_hart_id, pre_init, TrapFrame};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::sie;
+use riscv_sbi::println;
+use riscv_sbi_rt::{entry, heap_start, max_hart_id, pre_init};
 
 use linked_list_allocator::LockedHeap;
 #[global_allocator]
@@ -37,48 +43,125 @@ unsafe fn pre_init() {
 
 extern crate alloc;
 
-// 启动一个核，其它的核等待软中断
-// 多核操作系统需要改这个函数
+/// 启动核的编号，由第一个进入内核的核取得
+///
+/// 初始值不为 0，因此放在 .data 段中，不会被之后清零 .bss 段时覆盖
+static BOOT_HART: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// 启动核是否已经完成全局的初始化
+static BOOT_DONE: AtomicBool = AtomicBool::new(false);
+/// 设备树的物理地址，用 HSM 启动其它的核时作为 `a1` 传入
+static DTB_PA: AtomicUsize = AtomicUsize::new(0);
+/// 已经启动过的核，空闲时停止的核重新启动时不再打印
+static STARTED_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+// 第一个进入的核作为启动核，其它的核等待启动核完成全局的初始化
+// 固件支持 HSM 时，其它的核由启动核通过 hart_start 启动，进入时初始化已经完成；
+// 否则所有的核同时进入，等待启动核的软中断
 #[export_name = "_mp_hook"]
 pub extern "C" fn mp_hook(hartid: usize, _dtb: usize) -> bool {
-    if hartid == 0 {
-        true
-    } else {
-        unsafe {
-            sbi::legacy::clear_ipi();
-            sie::set_ssoft();
-            loop {
-                riscv::asm::wfi();
-                if sip::read().ssoft() {
-                    break;
-                }
-            }
-            sie::clear_ssoft();
-            sbi::legacy::clear_ipi();
+    if BOOT_HART
+        .compare_exchange(usize::MAX, hartid, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+    {
+        return true;
+    }
+    unsafe {
+        sbi::clear_ipi();
+        sie::set_ssoft();
+        while !BOOT_DONE.load(Ordering::SeqCst) {
+            riscv::asm::wfi();
         }
-        false
+        sie::clear_ssoft();
+        sbi::clear_ipi();
     }
+    false
 }
 
 #[entry]
 fn main(hartid: usize, dtb_pa: usize) {
-    println!("Hello, OpenSBI!");
-    println!("hartid={}, dtb_pa={:#x}", hartid, dtb_pa);
-    println!("spec_version = {:?}", sbi::base::get_spec_version());
-    println!("impl_id      = {:?}", sbi::base::get_impl_id());
-    println!("impl_version = {:?}", sbi::base::get_impl_version());
-    println!("mvendorid    = {:?}", sbi::base::get_mvendorid());
-    println!("marchid      = {:?}", sbi::base::get_marchid());
-    println!("mimpid       = {:?}", sbi::base::get_mimpid());
-
-    if hartid == 0 {
-        unsafe {
-            HEAP_ALLOCATOR.lock().init(heap_start() as usize, HEAP_SIZE);
+    // 内核运行时 tp 中总是保存着核的编号
+    unsafe { llvm_asm!("mv tp, $0" :: "r"(hartid) :: "volatile") };
+    sbi::set_online(hartid, true);
+    if hartid == BOOT_HART.load(Ordering::SeqCst) {
+        DTB_PA.store(dtb_pa, Ordering::SeqCst);
+        boot(dtb_pa);
+        // 全局的初始化已经完成，唤醒其它的核
+        BOOT_DONE.store(true, Ordering::SeqCst);
+        start_other_harts(hartid);
+    }
+    if STARTED_HARTS.fetch_or(1 << hartid, Ordering::SeqCst) & (1 << hartid) == 0 {
+        println!("hart {} started", hartid);
+    }
+    hart_init();
+    process::PROCESSOR.get().run()
+}
+
+/// 启动核的编号。启动核不会在空闲时停止，它负责处理定时器和关机
+pub fn boot_hart() -> usize {
+    BOOT_HART.load(Ordering::SeqCst)
+}
+
+/// 用 HSM 启动一个处于停止状态的核
+///
+/// 它会从内核入口开始执行，`a1` 同样为设备树的地址，之后进入调度循环
+pub fn start_hart(hart: usize) -> Result<(), isize> {
+    extern "C" {
+        fn _start();
+    }
+    let start_address = mem::PhysicalAddress::from(mem::VirtualAddress(_start as usize));
+    sbi::hart_start(hart, start_address.0, DTB_PA.load(Ordering::SeqCst))
+}
+
+/// 启动其它的核
+///
+/// 已经在 `mp_hook` 中等待的核通过软中断唤醒；固件支持 HSM 时，处于停止状态的核用 hart_start 启动
+fn start_other_harts(boot_hart: usize) {
+    sbi::send_ipi(((1 << (max_hart_id() + 1)) - 1) & !(1 << boot_hart));
+    if !sbi::extensions().hsm {
+        return;
+    }
+    for hart in (0..=max_hart_id()).filter(|&hart| hart != boot_hart) {
+        if sbi::hart_get_status(hart) == Ok(sbi::HART_STOPPED) {
+            if let Err(error) = start_hart(hart) {
+                println!("failed to start hart {}: error {}", hart, error);
+            }
         }
-        // wake other harts
-        let mut hart_mask = HartMask::all(max_hart_id());
-        hart_mask.clear(0); // unset hart 0
-        sbi::legacy::send_ipi(hart_mask);
+    }
+}
+
+/// 每个核各自的初始化
+fn hart_init() {
+    interrupt::init();
+    mem::KERNEL_MEMORY_SET.wait().unwrap().activate();
+
+    unsafe {
+        // 开启 STIE，允许时钟中断
+        sie::set_stimer();
+        // 开启 SSIE，空闲的核通过核间中断唤醒
+        sie::set_ssoft();
+        // // 开启 SIE（不是 sie 寄存器），允许内核态被中断打断
+        // sstatus::set_sie();
+    }
+    // 开启 SEIE 和当前核上各个设备的中断
+    driver::plic::init_hart();
+    // 还没有线程运行，只为定时器设置时钟中断
+    timer::program_next();
+}
+
+/// 启动核上的全局初始化
+fn boot(dtb_pa: usize) {
+    println!("Hello, OpenSBI!");
+    println!("dtb_pa={:#x}", dtb_pa);
+    println!("spec_version = {:?}", riscv_sbi::base::get_spec_version());
+    println!("impl_id      = {:?}", riscv_sbi::base::get_impl_id());
+    println!("impl_version = {:?}", riscv_sbi::base::get_impl_version());
+    println!("mvendorid    = {:?}", riscv_sbi::base::get_mvendorid());
+    println!("marchid      = {:?}", riscv_sbi::base::get_marchid());
+    println!("mimpid       = {:?}", riscv_sbi::base::get_mimpid());
+    println!("extensions   = {:x?}", sbi::init());
+
+    unsafe {
+        HEAP_ALLOCATOR.lock().init(heap_start() as usize, HEAP_SIZE);
     }
 
     use alloc::boxed::Box;
@@ -94,6 +177,11 @@ fn main(hartid: usize, dtb_pa: usize) {
     }
     println!("heap test passed");
 
+    interrupt::init();
+
+    // 从设备树读取物理内存布局，之后才能使用帧分配器
+    mem::init(driver::memory_layout(mem::PhysicalAddress(dtb_pa)));
+    println!("memory banks: {:016x?}", mem::memory_layout().banks);
     println!("frame start: {:016x?}", *mem::MEMORY_START_ADDRESS);
     println!("frame end: {:016x?}", *mem::MEMORY_END_ADDRESS);
 
@@ -109,23 +197,30 @@ fn main(hartid: usize, dtb_pa: usize) {
         };
         println!("{:x?} and {:x?}", frame_0.address(), frame_1.address());
     }
+    // 连续物理页分配
+    let frames = match mem::FRAME_ALLOCATOR.lock().alloc_contiguous(3, 4) {
+        Result::Ok(frames) => frames,
+        Result::Err(err) => panic!("{}", err),
+    };
+    assert_eq!(frames.page_number().0 % 4, 0);
+    println!("contiguous frames: {:x?}", frames.page_range());
+    drop(frames);
 
     println!("Initializing page system");
-    let remap = mem::MemorySet::new_kernel().unwrap();
+    let remap = mem::KERNEL_MEMORY_SET.call_once(|| mem::MemorySet::new_kernel().unwrap());
     println!("Instance created");
     remap.activate();
     println!("Page system activated");
-    // 允许内核读写用户态内存
-    // 其实只需要在部分的syscall里打开就可以了
-    // 第一次写操作系统，别忘了这玩意，否则会有莫名其妙的页异常
-    unsafe { riscv::register::sstatus::set_sum() };
 
     // unsafe {
     //     llvm_asm!("ebreak"::::"volatile");
     // }
 
     driver::init(mem::PhysicalAddress(dtb_pa));
+    time::init();
+    timer::init();
     fs::init();
+    net::init();
 
     // let process = Process::new_kernel().unwrap();
 
@@ -149,23 +244,6 @@ fn main(hartid: usize, dtb_pa: usize) {
 
     // 把多余的 process 引用丢弃掉
     // drop(process);
-
-    unsafe {
-        // 开启 STIE，允许时钟中断
-        sie::set_stimer();
-        // // 开启 SIE（不是 sie 寄存器），允许内核态被中断打断
-        // sstatus::set_sie();
-    }
-    // 设置下一次时钟中断
-    const INTERVAL: u64 = 100000;
-    sbi::legacy::set_timer(time::read64().wrapping_add(INTERVAL));
-
-    process::PROCESSOR.get().run()
-}
-
-#[interrupt]
-fn SupervisorSoft() {
-    println!("SupervisorSoft!");
 }
 
 // fn sample_process(message: usize) {
@@ -178,6 +256,7 @@ fn SupervisorSoft() {
 
 fn start_user_thread(app_name: &str) {
     use crate::fs::*;
+    use alloc::string::String;
     use xmas_elf::ElfFile;
     // 从文件系统中找到程序
     let app = fs::ROOT_INODE.find(app_name).unwrap();
@@ -187,52 +266,17 @@ fn start_user_thread(app_name: &str) {
     let elf = ElfFile::new(data.as_slice()).unwrap();
     // 利用 ELF 文件创建线程，映射空间并加载数据
     let process = Process::from_elf(&elf, true).unwrap();
-    // 再从 ELF 中读出程序入口地址
-    let thread = Thread::new(process, elf.header.pt2.entry_point() as usize, None).unwrap();
+    // 第一个用户进程作为 init 进程
+    process::INIT_PROCESS.call_once(|| process.clone());
+    // 再从 ELF 中读出程序入口地址，像 exec 一样放入参数和辅助向量
+    let thread = Thread::new_program(
+        process,
+        elf.header.pt2.entry_point() as usize,
+        &process::auxiliary_vector(&elf),
+        &[String::from(app_name)],
+        &[],
+    )
+    .unwrap();
     // 添加线程
     PROCESSOR.get().add_thread(thread);
 }
-
-const INTERVAL: u64 = 100000;
-
-// #[interrupt]
-// fn SupervisorTimer() {
-
-#[export_name = "SupervisorTimer"]
-unsafe extern "C" fn supervisor_timer(context: &mut TrapFrame) -> *mut TrapFrame {
-    static mut TICKS: usize = 0;
-
-    sbi::legacy::set_timer(time::read64().wrapping_add(INTERVAL));
-    TICKS += 1;
-    if TICKS % 100 == 0 {
-        println!("100 ticks~");
-    }
-
-    PROCESSOR.get().prepare_next_thread(context)
-}
-
-#[export_name = "ExceptionHandler"]
-pub fn handle_exception(trap_frame: &mut TrapFrame) -> *mut TrapFrame {
-    use riscv::register::{
-        scause::{self, Exception, Trap},
-        stval,
-    };
-    let scause = scause::read();
-    let stval = stval::read();
-    // println!(
-    //     "Exception occurred: {:?}; stval: 0x{:x}, sepc: 0x{:x}",
-    //     scause.cause(),
-    //     stval,
-    //     trap_frame.sepc
-    // );
-    if scause.cause() == Trap::Exception(Exception::Breakpoint) {
-        println!("Breakpoint at 0x{:x}", trap_frame.sepc);
-        trap_frame.sepc += 2;
-    }
-    if scause.cause() == Trap::Exception(Exception::UserEnvCall) {
-        // println!("Syscall at 0x{:x}", trap_frame.sepc);
-        // println!("{:x?}", trap_frame);
-        return kernel::syscall::syscall_handler(trap_frame);
-    }
-    trap_frame as *mut _
-}
@@ -1,10 +1,10 @@
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use core::ops::Range;
 use lazy_static::lazy_static;
+use spin::Once;
 // use riscv::register::satp;
 
-// todo: on embedded devices, we know how much memory are there on SoC,
-// but on pc or other platforms where we can install external memory,
-// we should auto detect them other than hardcode it into linker script.
-
 extern "Rust" {
     static _sstack: u8;
 }
@@ -14,9 +14,13 @@ lazy_static! {
     /// 可以访问的内存区域起始地址
     pub static ref MEMORY_START_ADDRESS: PhysicalAddress =
         PhysicalAddress(unsafe { &_sstack as *const _ as usize } - KERNEL_MAP_OFFSET);
-    /// 可以访问的内存区域结束地址
-    pub static ref MEMORY_END_ADDRESS: PhysicalAddress =
-        PhysicalAddress(0x8800_0000);
+    /// 可以访问的内存区域结束地址，即最高的内存条的结束地址
+    pub static ref MEMORY_END_ADDRESS: PhysicalAddress = memory_layout()
+        .banks
+        .iter()
+        .map(|bank| bank.end)
+        .max()
+        .expect("no memory bank found");
 }
 pub use self::memory_set::MemorySet;
 
@@ -24,13 +28,112 @@ mod address;
 mod frame;
 mod mapping;
 mod memory_set;
+mod mmio;
 mod page_table;
 mod page_table_entry;
 mod segment;
 
-pub use self::frame::{FrameTracker, FRAME_ALLOCATOR};
+pub use self::frame::{FrameRangeTracker, FrameTracker, FRAME_ALLOCATOR};
 pub use self::mapping::Mapping;
+pub use self::mmio::ioremap;
 pub use self::page_table_entry::Flags;
 pub use self::segment::{MapType, Segment};
 
-pub type MemoryResult<T> = core::result::Result<T, &'static str>;
+pub type MemoryResult<T> = core::result::Result<T, MemoryError>;
+
+/// 内存管理中的错误，带有一句说明
+///
+/// 系统调用通过 `From<MemoryError> for Errno` 统一转换为错误码
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MemoryError {
+    /// 物理页面或者虚拟地址空间不足
+    NoMemory(&'static str),
+    /// 地址不属于任何段，或者段没有所需的权限
+    BadAddress(&'static str),
+    /// 区间或者文件不符合要求，例如与已有的段重叠、ELF 格式不支持
+    Invalid(&'static str),
+}
+
+impl MemoryError {
+    /// 错误的说明
+    pub fn message(self) -> &'static str {
+        match self {
+            MemoryError::NoMemory(message)
+            | MemoryError::BadAddress(message)
+            | MemoryError::Invalid(message) => message,
+        }
+    }
+}
+
+impl core::fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// 解析 ELF 文件时 [`xmas_elf`] 给出的错误
+impl From<&'static str> for MemoryError {
+    fn from(message: &'static str) -> Self {
+        MemoryError::Invalid(message)
+    }
+}
+
+/// 物理内存布局，从设备树的 `/memory` 和 `/reserved-memory` 节点中读出
+#[derive(Debug, Default)]
+pub struct MemoryLayout {
+    /// 所有内存条的物理地址区间，可能不连续
+    pub banks: Vec<Range<PhysicalAddress>>,
+    /// 不能交给帧分配器的保留区域
+    pub reserved: Vec<Range<PhysicalAddress>>,
+}
+
+impl MemoryLayout {
+    /// 可以交给帧分配器的区间：内存条中去掉内核本身和所有保留区域
+    pub fn available_ranges(&self) -> Vec<Range<PhysicalAddress>> {
+        let mut ranges: Vec<Range<PhysicalAddress>> = self
+            .banks
+            .iter()
+            .map(|bank| max(bank.start, *MEMORY_START_ADDRESS)..bank.end)
+            .filter(|range| range.start < range.end)
+            .collect();
+        for reserved in self.reserved.iter() {
+            let mut remaining = Vec::new();
+            for range in ranges {
+                // 保留区域可能把一个区间切成左右两段
+                let left = range.start..min(range.end, reserved.start);
+                let right = max(range.start, reserved.end)..range.end;
+                remaining.extend(
+                    [left, right]
+                        .iter()
+                        .filter(|part| part.start < part.end)
+                        .cloned(),
+                );
+            }
+            ranges = remaining;
+        }
+        ranges
+    }
+}
+
+/// 内核的地址空间，由启动核建立，其他核启动后激活同一个页表
+pub static KERNEL_MEMORY_SET: Once<MemorySet> = Once::new();
+
+/// 物理内存布局，只会初始化一次
+static MEMORY_LAYOUT: Once<MemoryLayout> = Once::new();
+
+/// 记录物理内存布局，必须在第一次使用 [`static@FRAME_ALLOCATOR`] 之前调用
+pub fn init(mut layout: MemoryLayout) {
+    // 线性映射只能覆盖 PHYSICAL_MEMORY_LIMIT 以下的物理内存
+    for bank in layout.banks.iter_mut() {
+        bank.end = min(bank.end, PHYSICAL_MEMORY_LIMIT);
+    }
+    layout.banks.retain(|bank| bank.start < bank.end);
+    MEMORY_LAYOUT.call_once(|| layout);
+}
+
+/// 获取物理内存布局
+pub fn memory_layout() -> &'static MemoryLayout {
+    MEMORY_LAYOUT
+        .r#try()
+        .expect("memory layout is not initialized")
+}
@@ -10,6 +10,18 @@ pub const PAGE_SIZE: usize = 4096;
 /// 内核使用线性映射的偏移量
 pub const KERNEL_MAP_OFFSET: usize = 0xffff_ffff_0000_0000;
 
+/// 线性映射能够覆盖的物理地址上限，更高的物理内存无法使用
+pub const PHYSICAL_MEMORY_LIMIT: PhysicalAddress = PhysicalAddress(0x1_0000_0000);
+
+/// 所有地址空间共享的内核区域起始地址，用于放置线程的内核栈和设备的 MMIO 映射
+///
+/// 这段区域恰好占据根页表中的一项（1 GB），线性映射之下
+pub const SHARED_KERNEL_AREA_START: VirtualAddress = VirtualAddress(0xffff_fffe_0000_0000);
+/// 所有地址空间共享的内核区域结束地址
+pub const SHARED_KERNEL_AREA_END: VirtualAddress = VirtualAddress(0xffff_fffe_4000_0000);
+/// 共享内核区域的后一半用于映射设备的 MMIO 寄存器，前一半用于线程的内核栈
+pub const MMIO_AREA_START: VirtualAddress = VirtualAddress(0xffff_fffe_2000_0000);
+
 /// MMIO 设备段内存区域起始地址
 pub const DEVICE_START_ADDRESS: PhysicalAddress = PhysicalAddress(0x1000_0000);
 /// MMIO 设备段内存区域结束地址
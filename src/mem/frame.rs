@@ -1,9 +1,15 @@
 use crate::mem::*;
-use alloc::{vec, vec::Vec};
+use alloc::vec::Vec;
 use core::ops::Range;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
+mod buddy_allocator;
+pub use buddy_allocator::BuddyAllocator;
+
+/// 连续分配时支持的最大对齐（页数），即 2 MiB 的大页
+pub const MAX_CONTIGUOUS_ALIGN: usize = 512;
+
 #[derive(Debug)]
 pub struct FrameTracker(PhysicalPageNumber);
 
@@ -41,51 +47,145 @@ impl core::ops::DerefMut for FrameTracker {
     }
 }
 
+/// 一段物理上连续的帧
+///
+/// 由 [`FrameAllocator::alloc_contiguous`] 分配，在 drop 时整体放回 [`static@FRAME_ALLOCATOR`]。
+#[derive(Debug)]
+pub struct FrameRangeTracker {
+    /// 起始物理页号
+    start: PhysicalPageNumber,
+    /// 帧的数量
+    count: usize,
+}
+
+impl FrameRangeTracker {
+    /// 起始帧的物理地址
+    pub fn address(&self) -> PhysicalAddress {
+        PhysicalAddress::from(self.start)
+    }
+
+    /// 起始帧的物理页号
+    pub fn page_number(&self) -> PhysicalPageNumber {
+        self.start
+    }
+
+    /// 帧的数量
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// 所有帧的物理页号区间
+    pub fn page_range(&self) -> Range<PhysicalPageNumber> {
+        self.start..self.start + self.count
+    }
+}
+
+/// 连续的帧在释放时整体放回 [`static@FRAME_ALLOCATOR`]
+impl Drop for FrameRangeTracker {
+    fn drop(&mut self) {
+        FRAME_ALLOCATOR.lock().dealloc_contiguous(self);
+    }
+}
+
 lazy_static! {
-    /// 帧分配器
+    /// 帧分配器，管理设备树中描述的所有可用物理内存
     pub static ref FRAME_ALLOCATOR: Mutex<
-        FrameAllocator<StackedAllocator>
+        FrameAllocator<BuddyAllocator>
     > = Mutex::new(FrameAllocator::new(
-        PhysicalPageNumber::ceil(*MEMORY_START_ADDRESS)
-        ..PhysicalPageNumber::floor(*MEMORY_END_ADDRESS)
+        memory_layout()
+            .available_ranges()
+            .into_iter()
+            .map(|range| PhysicalPageNumber::ceil(range.start)..PhysicalPageNumber::floor(range.end))
     ));
 }
 
-/// 基于线段树的帧分配 / 回收
+/// 帧分配 / 回收
+///
+/// 物理内存可能由多段不连续的区间组成，每一段区间使用一个单独的分配器
 pub struct FrameAllocator<T: Allocator> {
-    /// 可用区间的起始
-    start_ppn: PhysicalPageNumber,
-    /// 分配器
-    allocator: T,
+    /// 每一段区间的页号范围，以及对应的分配器
+    ///
+    /// 分配器中的下标相对于区间起始页号向下对齐到 [`MAX_CONTIGUOUS_ALIGN`] 的位置，
+    /// 保证连续分配得到的物理地址同样满足对齐
+    regions: Vec<(Range<PhysicalPageNumber>, T)>,
 }
 
 impl<T: Allocator> FrameAllocator<T> {
     /// 创建对象
-    pub fn new(range: Range<PhysicalPageNumber>) -> Self {
-        FrameAllocator {
-            start_ppn: range.start,
-            allocator: T::new(range.end.0 - range.start.0),
-        }
+    pub fn new(ranges: impl IntoIterator<Item = Range<PhysicalPageNumber>>) -> Self {
+        let regions = ranges
+            .into_iter()
+            .filter(|range| range.start < range.end)
+            .map(|range| {
+                let base = PhysicalPageNumber(range.start.0 & !(MAX_CONTIGUOUS_ALIGN - 1));
+                // 从空的分配器开始，只放入区间内的页面
+                let mut allocator = T::new(0);
+                allocator.dealloc_contiguous(range.start - base, range.end - range.start);
+                (base..range.end, allocator)
+            })
+            .collect();
+        FrameAllocator { regions }
     }
 
     /// 分配帧，如果没有剩余则返回 `Err`
     pub fn alloc(&mut self) -> MemoryResult<FrameTracker> {
-        self.allocator
-            .alloc()
-            .ok_or("no available frame to allocate")
-            .map(|offset| FrameTracker(self.start_ppn + offset))
+        for (range, allocator) in self.regions.iter_mut() {
+            if let Some(offset) = allocator.alloc() {
+                return Ok(FrameTracker(range.start + offset));
+            }
+        }
+        Err(MemoryError::NoMemory("no available frame to allocate"))
     }
 
     /// 将被释放的帧添加到空闲列表的尾部
     ///
     /// 这个函数会在 [`FrameTracker`] 被 drop 时自动调用，不应在其他地方调用
     pub fn dealloc(&mut self, frame: &FrameTracker) {
-        self.allocator
-            .dealloc(frame.page_number().0 - self.start_ppn.0);
+        let (range, allocator) = self.region_of(frame.page_number());
+        allocator.dealloc(frame.page_number() - range.start);
+    }
+
+    /// 分配 `count` 个物理上连续的帧，起始地址按 `align` 个页对齐
+    ///
+    /// `align` 必须是 2 的幂，且不超过 [`MAX_CONTIGUOUS_ALIGN`]
+    pub fn alloc_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+    ) -> MemoryResult<FrameRangeTracker> {
+        assert!(align.is_power_of_two() && align <= MAX_CONTIGUOUS_ALIGN);
+        for (range, allocator) in self.regions.iter_mut() {
+            if let Some(offset) = allocator.alloc_contiguous(count, align.trailing_zeros() as usize)
+            {
+                return Ok(FrameRangeTracker {
+                    start: range.start + offset,
+                    count,
+                });
+            }
+        }
+        Err(MemoryError::NoMemory(
+            "no available contiguous frames to allocate",
+        ))
+    }
+
+    /// 将一段连续的帧放回
+    ///
+    /// 这个函数会在 [`FrameRangeTracker`] 被 drop 时自动调用，不应在其他地方调用
+    pub fn dealloc_contiguous(&mut self, frames: &FrameRangeTracker) {
+        let (range, allocator) = self.region_of(frames.page_number());
+        allocator.dealloc_contiguous(frames.page_number() - range.start, frames.count());
+    }
+
+    /// 找到某个页号所在的区间
+    fn region_of(&mut self, ppn: PhysicalPageNumber) -> &mut (Range<PhysicalPageNumber>, T) {
+        self.regions
+            .iter_mut()
+            .find(|(range, _)| range.contains(&ppn))
+            .expect("frame does not belong to any memory region")
     }
 }
 
-/// 分配器：固定容量，每次分配 / 回收一个元素
+/// 分配器：固定容量，每次分配 / 回收一个或一段连续的元素
 pub trait Allocator {
     /// 给定容量，创建分配器
     fn new(capacity: usize) -> Self;
@@ -93,6 +193,10 @@ pub trait Allocator {
     fn alloc(&mut self) -> Option<usize>;
     /// 回收一个元素
     fn dealloc(&mut self, index: usize);
+    /// 分配 `count` 个连续元素，起始下标按 `2^align_log2` 对齐，无法分配则返回 `None`
+    fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize>;
+    /// 回收从 `start` 开始的 `count` 个连续元素
+    fn dealloc_contiguous(&mut self, start: usize, count: usize);
 }
 
 pub struct StackedAllocator {
@@ -101,9 +205,11 @@ pub struct StackedAllocator {
 
 impl Allocator for StackedAllocator {
     fn new(capacity: usize) -> Self {
-        Self {
-            list: vec![(0, capacity)],
+        let mut list = Vec::new();
+        if capacity > 0 {
+            list.push((0, capacity));
         }
+        Self { list }
     }
 
     fn alloc(&mut self) -> Option<usize> {
@@ -120,4 +226,28 @@ impl Allocator for StackedAllocator {
     fn dealloc(&mut self, index: usize) {
         self.list.push((index, index + 1));
     }
+
+    fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize> {
+        let align = 1 << align_log2;
+        // 找到第一个能容纳对齐后区间的空闲段，并将其切开
+        for i in 0..self.list.len() {
+            let (start, end) = self.list[i];
+            let aligned = (start + align - 1) & !(align - 1);
+            if aligned + count <= end {
+                self.list.remove(i);
+                if start < aligned {
+                    self.list.push((start, aligned));
+                }
+                if aligned + count < end {
+                    self.list.push((aligned + count, end));
+                }
+                return Some(aligned);
+            }
+        }
+        None
+    }
+
+    fn dealloc_contiguous(&mut self, start: usize, count: usize) {
+        self.list.push((start, start + count));
+    }
 }
@@ -0,0 +1,98 @@
+//! 伙伴系统分配器 [`BuddyAllocator`]
+
+use super::Allocator;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+/// 最大阶数，一个块最多包含 2^MAX_ORDER 个元素
+const MAX_ORDER: usize = 24;
+
+/// 采用伙伴系统的分配器
+///
+/// 第 `k` 阶的空闲块包含 `2^k` 个元素，且起始下标按 `2^k` 对齐。
+/// 分配时从满足大小的最低阶开始寻找，必要时拆分更高阶的块；
+/// 回收时只要伙伴块也空闲，就合并为更高一阶的块。
+pub struct BuddyAllocator {
+    /// 每一阶的空闲块起始下标
+    free_lists: Vec<BTreeSet<usize>>,
+}
+
+impl BuddyAllocator {
+    /// 放回一个第 `order` 阶的空闲块，并尽可能与伙伴合并
+    fn insert_block(&mut self, mut start: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = start ^ (1 << order);
+            if self.free_lists[order].remove(&buddy) {
+                start = start.min(buddy);
+                order += 1;
+            } else {
+                break;
+            }
+        }
+        self.free_lists[order].insert(start);
+    }
+
+    /// 取出一个第 `order` 阶的块，必要时拆分更高阶的块
+    fn take_block(&mut self, order: usize) -> Option<usize> {
+        let found = (order..=MAX_ORDER).find(|&o| !self.free_lists[o].is_empty())?;
+        let start = *self.free_lists[found].iter().next().unwrap();
+        self.free_lists[found].remove(&start);
+        // 拆分出来的后半部分逐级放回
+        for o in (order..found).rev() {
+            self.free_lists[o].insert(start + (1 << o));
+        }
+        Some(start)
+    }
+
+    /// 将任意一段区间拆分成若干对齐的块放回
+    fn insert_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            // 在对齐和剩余长度的限制下，取尽可能大的块
+            let mut order = if start == 0 {
+                MAX_ORDER
+            } else {
+                (start.trailing_zeros() as usize).min(MAX_ORDER)
+            };
+            while start + (1 << order) > end {
+                order -= 1;
+            }
+            self.insert_block(start, order);
+            start += 1 << order;
+        }
+    }
+}
+
+impl Allocator for BuddyAllocator {
+    fn new(capacity: usize) -> Self {
+        let mut allocator = Self {
+            free_lists: (0..=MAX_ORDER).map(|_| BTreeSet::new()).collect(),
+        };
+        allocator.insert_range(0, capacity);
+        allocator
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        self.take_block(0)
+    }
+
+    fn dealloc(&mut self, index: usize) {
+        self.insert_block(index, 0);
+    }
+
+    fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize> {
+        assert!(count > 0);
+        // 块的大小必须是 2 的幂，同时满足对齐要求
+        let order = (count.next_power_of_two().trailing_zeros() as usize).max(align_log2);
+        if order > MAX_ORDER {
+            return None;
+        }
+        let start = self.take_block(order)?;
+        // 多出来的尾部立即放回
+        self.insert_range(start + count, start + (1 << order));
+        Some(start)
+    }
+
+    fn dealloc_contiguous(&mut self, start: usize, count: usize) {
+        self.insert_range(start, start + count);
+    }
+}
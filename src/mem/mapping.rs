@@ -13,6 +13,22 @@ use crate::mem::{
 };
 use alloc::{vec, vec::Vec};
 use core::ptr::slice_from_raw_parts_mut;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+lazy_static! {
+    /// 所有地址空间共享的内核区域的映射
+    ///
+    /// 其中的第二级页表在创建时就已经建立，之后每个 [`Mapping`] 的根页表都指向它，
+    /// 因此在这里建立的映射对所有地址空间同时可见
+    static ref SHARED_MAPPING: Mutex<Mapping> = {
+        let mut mapping = Mapping::new_root().unwrap();
+        mapping
+            .find_entry(VirtualPageNumber::floor(SHARED_KERNEL_AREA_START))
+            .unwrap();
+        Mutex::new(mapping)
+    };
+}
 
 #[derive(Default, Debug)]
 /// 某个进程的内存映射关系
@@ -36,8 +52,22 @@ impl Mapping {
         }
     }
 
-    /// 创建一个有根节点的映射
+    /// 创建一个有根节点的映射，其中已经包含所有地址空间共享的内核区域
     pub fn new() -> MemoryResult<Mapping> {
+        let mapping = Self::new_root()?;
+        let index = VirtualPageNumber::floor(SHARED_KERNEL_AREA_START).levels()[0];
+        let shared_entry = {
+            let shared = SHARED_MAPPING.lock();
+            let shared_root: &mut PageTable = PhysicalAddress::from(shared.root_ppn).deref_kernel();
+            shared_root.entries[index]
+        };
+        let root_table: &mut PageTable = PhysicalAddress::from(mapping.root_ppn).deref_kernel();
+        root_table.entries[index] = shared_entry;
+        Ok(mapping)
+    }
+
+    /// 创建一个只有空的根页表的映射
+    fn new_root() -> MemoryResult<Mapping> {
         let root_table = PageTableTracker::new(FRAME_ALLOCATOR.lock().alloc()?);
         let root_ppn = root_table.page_number();
         Ok(Mapping {
@@ -46,9 +76,28 @@ impl Mapping {
         })
     }
 
+    /// 在所有地址空间共享的内核区域中建立映射
+    pub fn map_shared(
+        vpn: VirtualPageNumber,
+        ppn: PhysicalPageNumber,
+        flags: Flags,
+    ) -> MemoryResult<()> {
+        assert!(VirtualAddress::from(vpn) >= SHARED_KERNEL_AREA_START);
+        SHARED_MAPPING.lock().map_one(vpn, ppn, flags)
+    }
+
+    /// 撤销共享内核区域中的映射
+    pub fn unmap_shared(vpn: VirtualPageNumber) {
+        assert!(VirtualAddress::from(vpn) >= SHARED_KERNEL_AREA_START);
+        SHARED_MAPPING.lock().unmap_one(vpn);
+        // 所有核都可能缓存了这个页表项
+        crate::sbi::remote_sfence_vma(VirtualAddress::from(vpn).0, PAGE_SIZE);
+    }
+
     /// 加入一段映射，可能会相应地分配物理页面
     ///
     /// 未被分配物理页面的虚拟页号暂时不会写入页表当中，它们会在发生 PageFault 后再建立页表项。
+    /// 对于按帧映射的 [`Segment`]，只有 `init_data` 为 `Some` 时才会立即分配物理页面（数据可以为空）。
     pub fn map(
         &mut self,
         segment: &Segment,
@@ -70,6 +119,8 @@ impl Mapping {
                 }
                 Ok(Vec::new())
             }
+            // 没有初始数据，等到发生 PageFault 时再分配
+            MapType::Framed if init_data.is_none() => Ok(Vec::new()),
             // 需要分配帧进行映射
             MapType::Framed => {
                 // 记录所有成功分配的页面映射
@@ -140,7 +191,7 @@ impl Mapping {
     }
 
     /// 为给定的虚拟 / 物理页号建立映射关系
-    fn map_one(
+    pub fn map_one(
         &mut self,
         vpn: VirtualPageNumber,
         ppn: PhysicalPageNumber,
@@ -154,6 +205,13 @@ impl Mapping {
         Ok(())
     }
 
+    /// 撤销给定虚拟页号的映射，页表本身会保留
+    pub fn unmap_one(&mut self, vpn: VirtualPageNumber) {
+        if let Ok(entry) = self.find_entry(vpn) {
+            *entry = PageTableEntry::default();
+        }
+    }
+
     /// 查找虚拟地址对应的物理地址
     pub fn lookup(va: VirtualAddress) -> Option<PhysicalAddress> {
         let mut current_ppn;
@@ -3,13 +3,14 @@
 
 use crate::mem::{
     address::*,
-    frame::FrameTracker,
+    frame::{FrameTracker, FRAME_ALLOCATOR},
     mapping::Mapping,
-    page_table_entry::Flags,
+    memory_layout,
+    page_table_entry::{Flags, PageTableEntry},
     segment::{MapType, Segment},
-    MemoryResult,
+    MemoryError, MemoryResult, MEMORY_START_ADDRESS,
 };
-use alloc::{vec, vec::Vec};
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
 use core::ops::Range;
 use xmas_elf::{
     program::{SegmentData, Type},
@@ -24,7 +25,9 @@ pub struct MemorySet {
     /// 每个字段
     pub segments: Vec<Segment>,
     /// 所有分配的物理页面映射信息
-    pub allocated_pairs: Vec<(VirtualPageNumber, FrameTracker)>,
+    ///
+    /// 物理页面可能在 fork 之后被多个进程共享，因此使用引用计数
+    pub allocated_pairs: BTreeMap<VirtualPageNumber, Arc<FrameTracker>>,
 }
 
 impl MemorySet {
@@ -47,7 +50,7 @@ impl MemorySet {
         }
 
         // 建立字段
-        let segments = vec![
+        let mut segments = vec![
             // DEVICE 段，rw-
             Segment {
                 map_type: MapType::Linear,
@@ -90,21 +93,28 @@ impl MemorySet {
                 range: (_estack as usize).into()..(_sstack as usize).into(),
                 flags: Flags::READABLE | Flags::WRITABLE,
             },
-            // 剩下的部分，rw-
-            Segment {
-                map_type: MapType::Linear,
-                range: (_sstack as usize).into()..PhysicalAddress(0x8800_0000).into(),
-                flags: Flags::READABLE | Flags::WRITABLE,
-            },
         ];
+        // 剩下的内存，按照每一根内存条分别映射，rw-
+        for bank in memory_layout().banks.iter() {
+            let start = core::cmp::max(bank.start, *MEMORY_START_ADDRESS);
+            if start < bank.end {
+                segments.push(Segment {
+                    map_type: MapType::Linear,
+                    range: start.into()..bank.end.into(),
+                    flags: Flags::READABLE | Flags::WRITABLE,
+                });
+            }
+        }
         let mut mapping = Mapping::new()?;
         // 准备保存所有新分配的物理页面
-        let mut allocated_pairs = Vec::new();
+        let mut allocated_pairs = BTreeMap::new();
 
         // 每个字段在页表中进行映射
         for segment in segments.iter() {
             // 同时将新分配的映射关系保存到 allocated_pairs 中
-            allocated_pairs.extend(mapping.map(segment, None)?);
+            for (vpn, frame) in mapping.map(segment, None)? {
+                allocated_pairs.insert(vpn, Arc::new(frame));
+            }
         }
         Ok(MemorySet {
             mapping,
@@ -125,12 +135,193 @@ impl MemorySet {
         // 检测 segment 没有重合
         assert!(!self.overlap_with(segment.page_range()));
         // 映射并将新分配的页面保存下来
-        self.allocated_pairs
-            .extend(self.mapping.map(&segment, init_data)?);
+        for (vpn, frame) in self.mapping.map(&segment, init_data)? {
+            self.allocated_pairs.insert(vpn, Arc::new(frame));
+        }
         self.segments.push(segment);
         Ok(())
     }
 
+    /// 撤销 `range` 中页面的映射并释放这些页面，用于 munmap
+    ///
+    /// `range` 必须完全位于用户态的按帧映射的 [`Segment`] 中，否则返回 `Err` 且不做任何修改。
+    /// 只覆盖了一部分的段会被切开，保留区间之外的部分
+    pub fn unmap_range(&mut self, range: Range<VirtualPageNumber>) -> MemoryResult<()> {
+        use super::segment::RangeIter;
+        let user_framed = |segment: &Segment| {
+            segment.map_type == MapType::Framed && segment.flags.contains(Flags::USER)
+        };
+        let mut vpn = range.start;
+        while vpn < range.end {
+            match self
+                .segments
+                .iter()
+                .find(|segment| segment.page_range().contains(&vpn))
+            {
+                Some(segment) if user_framed(segment) => vpn = segment.page_range().end,
+                _ => return Err(MemoryError::Invalid("range is not in user framed segments")),
+            }
+        }
+        // 与区间重叠的段只保留区间之前和之后的部分
+        let mut segments = Vec::new();
+        for segment in core::mem::take(&mut self.segments) {
+            let page_range = segment.page_range();
+            if page_range.end <= range.start || page_range.start >= range.end {
+                segments.push(segment);
+                continue;
+            }
+            if page_range.start < range.start {
+                segments.push(Segment {
+                    range: segment.range.start..VirtualAddress::from(range.start),
+                    ..segment.clone()
+                });
+            }
+            if page_range.end > range.end {
+                segments.push(Segment {
+                    range: VirtualAddress::from(range.end)..segment.range.end,
+                    ..segment.clone()
+                });
+            }
+        }
+        self.segments = segments;
+        let mut frames = Vec::new();
+        for vpn in RangeIter(range.clone()) {
+            self.mapping.unmap_one(vpn);
+            frames.extend(self.allocated_pairs.remove(&vpn));
+        }
+        // 其他核可能还缓存着页表项，刷新之后才能释放页面
+        crate::sbi::remote_sfence_vma(
+            VirtualAddress::from(range.start).0,
+            (range.end - range.start) * PAGE_SIZE,
+        );
+        drop(frames);
+        Ok(())
+    }
+
+    /// 处理缺页异常，为按帧映射但尚未分配物理页面的地址分配页面，或者复制写时复制的页面
+    ///
+    /// `access` 是引发异常的访问所需的权限（读、写或执行）。如果地址不属于任何 [`Segment`]，
+    /// 或者段本身没有这个权限，则返回 `Err`。页面可能已经被其他核上的线程处理过，此时只需刷新 TLB
+    pub fn handle_page_fault(&mut self, va: VirtualAddress, access: Flags) -> MemoryResult<()> {
+        let vpn = VirtualPageNumber::floor(va);
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| segment.page_range().contains(&vpn))
+            .ok_or(MemoryError::BadAddress("address is not in any segment"))?;
+        if segment.map_type != MapType::Framed {
+            return Err(MemoryError::BadAddress("page fault in a linear segment"));
+        }
+        let flags = segment.flags | Flags::VALID;
+        if !flags.contains(access) {
+            return Err(MemoryError::BadAddress(
+                "access is not permitted by the segment",
+            ));
+        }
+        let entry = self.mapping.find_entry(vpn)?;
+        if !entry.is_empty() && entry.flags().contains(access) {
+            // 其他核已经为这个页面建立了映射，本核的 TLB 中还是旧的页表项
+        } else if !entry.is_empty() {
+            // 可写的段中没有写权限的页面，说明是 fork 后写时复制的共享页面
+            let frame = self
+                .allocated_pairs
+                .get_mut(&vpn)
+                .expect("shared page is not tracked");
+            if Arc::strong_count(frame) > 1 {
+                // 仍然与其他进程共享，复制一份；否则直接恢复写权限
+                let mut new_frame = FRAME_ALLOCATOR.lock().alloc()?;
+                new_frame.copy_from_slice(&frame[..]);
+                *frame = Arc::new(new_frame);
+            }
+            *entry = PageTableEntry::new(frame.page_number(), flags);
+            // 其他核上的线程可能还在通过旧的页表项读取原来的页面
+            crate::sbi::remote_sfence_vma(VirtualAddress::from(vpn).0, PAGE_SIZE);
+        } else {
+            // 分配物理页面，填充 0，映射并记录
+            let mut frame = FRAME_ALLOCATOR.lock().alloc()?;
+            frame.fill(0);
+            *entry = PageTableEntry::new(frame.page_number(), flags);
+            self.allocated_pairs.insert(vpn, Arc::new(frame));
+        }
+        // 页表项由无效变为有效，同样需要刷新 TLB
+        unsafe { llvm_asm!("sfence.vma $0" :: "r"(va.0) :: "volatile") };
+        Ok(())
+    }
+
+    /// 复制出一个新的 `MemorySet`，用于 fork
+    ///
+    /// 内核部分重新建立映射；按帧映射的页面不会立即复制，而是由双方共享。
+    /// 其中可写的页面在双方的页表中都去掉写权限，等到写入时再复制（Copy-on-Write）
+    pub fn fork(&mut self) -> MemoryResult<MemorySet> {
+        let mut memory_set = MemorySet::new_kernel()?;
+        for segment in self
+            .segments
+            .iter()
+            .filter(|segment| segment.map_type == MapType::Framed)
+        {
+            let flags = (segment.flags | Flags::VALID) - Flags::WRITABLE;
+            for (&vpn, frame) in self.allocated_pairs.range(segment.page_range()) {
+                // 当前进程中同样去掉写权限
+                *self.mapping.find_entry(vpn)? = PageTableEntry::new(frame.page_number(), flags);
+                memory_set
+                    .mapping
+                    .map_one(vpn, frame.page_number(), flags)?;
+                memory_set.allocated_pairs.insert(vpn, frame.clone());
+            }
+            memory_set.segments.push(segment.clone());
+        }
+        // 当前进程的页表项被修改，需要刷新 TLB。进程的其他线程可能正在其他核上运行
+        crate::sbi::remote_sfence_vma(0, usize::MAX);
+        Ok(memory_set)
+    }
+
+    /// 将数据写入这个地址空间中 `va` 开始的位置
+    ///
+    /// 通过页表找到对应的物理页面再写入，因此不要求页表已经激活。
+    /// 尚未分配的页面会立即分配，写时复制的页面会先被复制
+    pub fn write_bytes(&mut self, va: VirtualAddress, data: &[u8]) -> MemoryResult<()> {
+        let mut written = 0;
+        while written < data.len() {
+            let address = va + written;
+            let vpn = VirtualPageNumber::floor(address);
+            let entry = self.mapping.find_entry(vpn)?;
+            if entry.is_empty() || !entry.flags().contains(Flags::WRITABLE) {
+                self.handle_page_fault(address, Flags::WRITABLE)?;
+            }
+            let page = self.mapping.find_entry(vpn)?.page_number().deref_kernel();
+            let offset = address.page_offset();
+            let length = core::cmp::min(PAGE_SIZE - offset, data.len() - written);
+            page[offset..offset + length].copy_from_slice(&data[written..written + length]);
+            written += length;
+        }
+        Ok(())
+    }
+
+    /// 检查 `range` 是否完全位于用户态可以按照 `access` 访问的按帧映射的 [`Segment`] 中
+    ///
+    /// 系统调用访问用户给出的地址之前用它检查。其中的页面可能还没有分配，访问时由缺页异常处理
+    pub fn check_user_range(&self, range: Range<VirtualAddress>, access: Flags) -> bool {
+        let mut address = range.start;
+        while address < range.end {
+            let vpn = VirtualPageNumber::floor(address);
+            let segment = match self
+                .segments
+                .iter()
+                .find(|segment| segment.page_range().contains(&vpn))
+            {
+                Some(segment) => segment,
+                None => return false,
+            };
+            if segment.map_type != MapType::Framed || !segment.flags.contains(access | Flags::USER)
+            {
+                return false;
+            }
+            // 相邻的段可能共同覆盖这个区间
+            address = VirtualAddress::from(segment.page_range().end);
+        }
+        true
+    }
+
     /// 检测一段内存区域和已有的是否存在重叠区域
     pub fn overlap_with(&self, range: Range<VirtualPageNumber>) -> bool {
         fn range_overlap<T: core::cmp::Ord>(a: &Range<T>, b: &Range<T>) -> bool {
@@ -161,12 +352,11 @@ impl MemorySet {
             let start = VirtualAddress(program_header.virtual_addr() as usize);
             let size = program_header.mem_size() as usize;
             riscv_sbi::println!("Start: {:016x?}; Size: {:016x?}", start, size);
-            let data: &[u8] =
-                if let SegmentData::Undefined(data) = program_header.get_data(file).unwrap() {
-                    data
-                } else {
-                    return Err("unsupported elf format");
-                };
+            let data: &[u8] = if let SegmentData::Undefined(data) = program_header.get_data(file)? {
+                data
+            } else {
+                return Err(MemoryError::Invalid("unsupported elf format"));
+            };
 
             // 将每一部分作为 Segment 进行映射
             let segment = Segment {
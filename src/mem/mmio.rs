@@ -0,0 +1,44 @@
+//! 将设备的 MMIO 寄存器映射到内核的地址空间 [`ioremap`]
+
+use super::{address::*, mapping::Mapping, page_table_entry::Flags, MemoryError, MemoryResult};
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// MMIO 区域中已经使用的页数，映射只会增加不会撤销
+static USED_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// 将一段物理地址区间映射到共享内核区域中，返回 `range.start` 对应的虚拟地址
+///
+/// 映射对所有地址空间都可见，设备可以位于物理内存的任意位置
+pub fn ioremap(range: Range<PhysicalAddress>) -> MemoryResult<VirtualAddress> {
+    let start = PhysicalPageNumber::floor(range.start);
+    let count = PhysicalPageNumber::ceil(range.end) - start;
+    let capacity = (SHARED_KERNEL_AREA_END.0 - MMIO_AREA_START.0) / PAGE_SIZE;
+    // 先检查剩余空间再占用，失败的映射不会消耗空间
+    let mut offset = USED_PAGES.load(Ordering::Relaxed);
+    loop {
+        match offset.checked_add(count) {
+            Some(end) if end <= capacity => {}
+            _ => return Err(MemoryError::NoMemory("no virtual space for MMIO")),
+        }
+        match USED_PAGES.compare_exchange_weak(
+            offset,
+            offset + count,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => break,
+            Err(current) => offset = current,
+        }
+    }
+    let va_start = MMIO_AREA_START + offset * PAGE_SIZE;
+    let vpn_start = VirtualPageNumber::floor(va_start);
+    for i in 0..count {
+        Mapping::map_shared(
+            vpn_start + i,
+            start + i,
+            Flags::VALID | Flags::READABLE | Flags::WRITABLE,
+        )?;
+    }
+    Ok(va_start + range.start.page_offset())
+}
@@ -0,0 +1,199 @@
+//! 网络协议栈
+//!
+//! 使用 [`smoltcp`] 实现 ARP、IPv4、ICMP、UDP 和 TCP，运行在第一个网络设备上。
+//! 网卡的中断和定时器都会驱动协议栈处理收发，等待 socket 的线程在协议栈状态变化时被唤醒
+
+mod socket;
+
+pub use socket::{Socket, SocketType};
+
+use crate::driver::{registry, NetDriver};
+use crate::kernel::condvar::Condvar;
+use crate::time::nanos_to_ticks;
+use crate::timer::{self, TimerHandle};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use riscv::register::time;
+use riscv_sbi::println;
+use smoltcp::iface::{EthernetInterface, EthernetInterfaceBuilder, NeighborCache, Routes};
+use smoltcp::phy::{self, DeviceCapabilities};
+use smoltcp::socket::SocketSet;
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address};
+use spin::{Mutex, MutexGuard, Once};
+
+/// 本机的 IP 地址，和 QEMU user 模式网络的默认设置一致
+const LOCAL_ADDRESS: Ipv4Address = Ipv4Address([10, 0, 2, 15]);
+/// 子网前缀长度
+const PREFIX_LENGTH: u8 = 24;
+/// 默认网关
+const GATEWAY: Ipv4Address = Ipv4Address([10, 0, 2, 2]);
+/// 以太网帧的最大长度
+const MAX_FRAME_SIZE: usize = 1514;
+
+/// 协议栈，只有存在网络设备时才会初始化
+static NET: Once<Mutex<NetStack>> = Once::new();
+
+lazy_static! {
+    /// 协议栈的状态发生变化时通知等待的线程
+    static ref CONDVAR: Condvar = Condvar::default();
+}
+
+/// 协议栈和所有 socket
+pub struct NetStack {
+    iface: EthernetInterface<'static, 'static, 'static, NetDevice>,
+    sockets: SocketSet<'static, 'static, 'static>,
+    /// 协议栈下一次需要处理定时任务（例如 TCP 重传）的定时器
+    next_poll: Option<TimerHandle>,
+}
+
+impl NetStack {
+    /// 处理收到的帧和需要发送的数据，必要时唤醒等待的线程
+    fn poll(&mut self) {
+        let timestamp = now();
+        match self.iface.poll(&mut self.sockets, timestamp) {
+            Ok(true) => CONDVAR.notify_all(),
+            Ok(false) => {}
+            Err(error) => println!("[Kernel] net: {}", error),
+        }
+        // 清理已经关闭、不再被使用的 socket
+        self.sockets.prune();
+        // 按照协议栈的需要重新设置定时器，没有时钟中断时也能按时重传
+        if let Some(handle) = self.next_poll.take() {
+            timer::cancel_timer(handle);
+        }
+        if let Some(delay) = self.iface.poll_delay(&self.sockets, timestamp) {
+            let nanos = delay.total_millis().max(1) * 1_000_000;
+            let deadline = time::read64().wrapping_add(nanos_to_ticks(nanos));
+            self.next_poll = Some(timer::add_timer(deadline, poll));
+        }
+    }
+}
+
+/// 以时钟计时的当前时间
+fn now() -> Instant {
+    Instant::from_millis((crate::time::monotonic() / 1_000_000) as i64)
+}
+
+/// 在第一个网络设备上初始化协议栈，没有网络设备时什么也不做
+pub fn init() {
+    let driver = match registry::net_devices().into_iter().next() {
+        Some(driver) => driver,
+        None => return,
+    };
+    let mac = EthernetAddress(driver.mac_address());
+    let mut routes = Routes::new(BTreeMap::new());
+    routes.add_default_ipv4_route(GATEWAY).unwrap();
+    let iface = EthernetInterfaceBuilder::new(NetDevice(driver))
+        .ethernet_addr(mac)
+        .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+        .ip_addrs(vec![IpCidr::new(
+            IpAddress::Ipv4(LOCAL_ADDRESS),
+            PREFIX_LENGTH,
+        )])
+        .routes(routes)
+        .finalize();
+    NET.call_once(|| {
+        Mutex::new(NetStack {
+            iface,
+            sockets: SocketSet::new(Vec::new()),
+            next_poll: None,
+        })
+    });
+    println!("mod net initialized: {} at {}", mac, LOCAL_ADDRESS);
+}
+
+/// 让协议栈处理一次收发，由网卡中断和协议栈自己设置的定时器调用
+///
+/// 其他核正在使用协议栈时不等待锁，而是在 1 毫秒后重试，避免丢失协议栈设置的定时器
+pub fn poll() {
+    if let Some(net) = NET.r#try() {
+        match net.try_lock() {
+            Some(mut stack) => stack.poll(),
+            None => {
+                let deadline = time::read64().wrapping_add(nanos_to_ticks(1_000_000));
+                timer::add_timer(deadline, poll);
+            }
+        }
+    }
+}
+
+/// 获取协议栈，没有网络设备时返回 `None`
+fn stack() -> Option<MutexGuard<'static, NetStack>> {
+    NET.r#try().map(|net| net.lock())
+}
+
+/// 反复检查 `condition`，直到它返回 `Some`
+///
+/// 每次检查前都会先处理一次收发；不满足时休眠，直到协议栈的状态发生变化
+fn wait_until<T>(
+    mut condition: impl FnMut(&mut SocketSet<'static, 'static, 'static>) -> Option<T>,
+) -> T {
+    loop {
+        let mut stack = stack().unwrap();
+        stack.poll();
+        if let Some(result) = condition(&mut stack.sockets) {
+            return result;
+        }
+        CONDVAR.wait_with(stack);
+    }
+}
+
+/// 把 [`NetDriver`] 接入 [`smoltcp`]
+pub struct NetDevice(Arc<dyn NetDriver>);
+
+/// 已经从网卡取出的帧
+pub struct NetRxToken(Vec<u8>);
+
+/// 发送时由协议栈填入帧的内容
+pub struct NetTxToken(Arc<dyn NetDriver>);
+
+impl<'a> phy::Device<'a> for NetDevice {
+    type RxToken = NetRxToken;
+    type TxToken = NetTxToken;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let mut buffer = vec![0; MAX_FRAME_SIZE];
+        let len = self.0.receive(&mut buffer)?;
+        buffer.truncate(len);
+        Some((NetRxToken(buffer), NetTxToken(self.0.clone())))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        Some(NetTxToken(self.0.clone()))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.max_transmission_unit = MAX_FRAME_SIZE;
+        capabilities.max_burst_size = Some(1);
+        capabilities
+    }
+}
+
+impl phy::RxToken for NetRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(&mut self.0)
+    }
+}
+
+impl phy::TxToken for NetTxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer)?;
+        if self.0.send(&buffer) {
+            Ok(result)
+        } else {
+            Err(smoltcp::Error::Exhausted)
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! 作为文件描述符使用的 [`Socket`]
+
+use super::{stack, wait_until};
+use crate::kernel::Errno;
+use alloc::vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU16, Ordering};
+use rcore_fs::vfs::{FsError, INode, PollStatus, Result};
+use smoltcp::socket::{
+    self as smoltcp_socket, SocketHandle, SocketSet, TcpSocket, TcpSocketBuffer, UdpPacketMetadata,
+    UdpSocket, UdpSocketBuffer,
+};
+use smoltcp::wire::IpEndpoint;
+use spin::Mutex;
+
+/// 每个 socket 的收发缓冲区大小
+const BUFFER_SIZE: usize = 0x4000;
+/// UDP 每个方向最多缓存的包数
+const UDP_PACKET_COUNT: usize = 16;
+
+/// 临时端口的起始，到 65535 为止
+const EPHEMERAL_PORT_START: u16 = 49152;
+/// 下一个临时端口
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORT_START);
+
+/// 协议栈中是否有 socket 使用了本地端口 `port`
+fn port_in_use(sockets: &SocketSet<'static, 'static, 'static>, port: u16) -> bool {
+    sockets.iter().any(|socket| match socket {
+        smoltcp_socket::Socket::Tcp(socket) => socket.local_endpoint().port == port,
+        smoltcp_socket::Socket::Udp(socket) => socket.endpoint().port == port,
+        _ => false,
+    })
+}
+
+/// 分配一个没有被使用的临时端口，全部被使用时返回 [`Errno::EADDRINUSE`]
+///
+/// 调用者需要在使用这个端口之前一直持有协议栈的锁
+fn ephemeral_port(sockets: &SocketSet<'static, 'static, 'static>) -> SocketResult<u16> {
+    let count = (u16::MAX - EPHEMERAL_PORT_START) as usize + 1;
+    for _ in 0..count {
+        let port = NEXT_EPHEMERAL_PORT
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |port| {
+                Some(if port == u16::MAX {
+                    EPHEMERAL_PORT_START
+                } else {
+                    port + 1
+                })
+            })
+            .unwrap();
+        if !port_in_use(sockets, port) {
+            return Ok(port);
+        }
+    }
+    Err(Errno::EADDRINUSE)
+}
+
+/// socket 的类型，取值和 Linux 的 `SOCK_STREAM`、`SOCK_DGRAM` 相同
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SocketType {
+    Stream = 1,
+    Datagram = 2,
+}
+
+/// socket 操作的结果
+pub type SocketResult<T> = core::result::Result<T, Errno>;
+
+struct SocketState {
+    /// 在协议栈 [`smoltcp::socket::SocketSet`] 中的编号
+    handle: SocketHandle,
+    /// 绑定的本地端口，0 表示还没有绑定
+    local_port: u16,
+    /// UDP 通过 connect 设置的对端地址
+    remote: Option<IpEndpoint>,
+}
+
+/// 一个 TCP 或 UDP socket
+pub struct Socket {
+    socket_type: SocketType,
+    state: Mutex<SocketState>,
+}
+
+fn new_tcp_socket() -> TcpSocket<'static> {
+    TcpSocket::new(
+        TcpSocketBuffer::new(vec![0; BUFFER_SIZE]),
+        TcpSocketBuffer::new(vec![0; BUFFER_SIZE]),
+    )
+}
+
+fn new_udp_socket() -> UdpSocket<'static, 'static> {
+    UdpSocket::new(
+        UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; UDP_PACKET_COUNT],
+            vec![0; BUFFER_SIZE],
+        ),
+        UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; UDP_PACKET_COUNT],
+            vec![0; BUFFER_SIZE],
+        ),
+    )
+}
+
+impl Socket {
+    /// 在协议栈中创建 socket，没有网络设备时失败
+    pub fn new(socket_type: SocketType) -> SocketResult<Self> {
+        let mut stack = stack().ok_or(Errno::ENETDOWN)?;
+        let handle = match socket_type {
+            SocketType::Stream => stack.sockets.add(new_tcp_socket()),
+            SocketType::Datagram => stack.sockets.add(new_udp_socket()),
+        };
+        Ok(Self {
+            socket_type,
+            state: Mutex::new(SocketState {
+                handle,
+                local_port: 0,
+                remote: None,
+            }),
+        })
+    }
+
+    /// 绑定本地端口
+    pub fn bind(&self, port: u16) -> SocketResult<()> {
+        let mut state = self.state.lock();
+        if state.local_port != 0 {
+            return Err(Errno::EINVAL);
+        }
+        if self.socket_type == SocketType::Datagram {
+            let mut stack = stack().unwrap();
+            stack
+                .sockets
+                .get::<UdpSocket>(state.handle)
+                .bind(port)
+                .map_err(|_| Errno::EADDRINUSE)?;
+        }
+        state.local_port = port;
+        Ok(())
+    }
+
+    /// 开始在绑定的端口上等待连接
+    pub fn listen(&self) -> SocketResult<()> {
+        let state = self.state.lock();
+        if self.socket_type != SocketType::Stream {
+            return Err(Errno::EOPNOTSUPP);
+        }
+        if state.local_port == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let mut stack = stack().unwrap();
+        stack
+            .sockets
+            .get::<TcpSocket>(state.handle)
+            .listen(state.local_port)
+            .map_err(|_| Errno::EADDRINUSE)
+    }
+
+    /// 等待一个连接，返回代表这个连接的 socket
+    ///
+    /// [`smoltcp`] 中正在监听的 socket 收到连接后会直接成为这个连接，
+    /// 因此把它交给新的 socket，再在原来的端口上创建一个新的监听 socket
+    pub fn accept(&self) -> SocketResult<Socket> {
+        if self.socket_type != SocketType::Stream {
+            return Err(Errno::EOPNOTSUPP);
+        }
+        loop {
+            // 等待时不能持有 socket 的锁，否则其他线程无法使用这个 socket
+            let handle = self.state.lock().handle;
+            wait_until(|sockets| {
+                let socket = sockets.get::<TcpSocket>(handle);
+                if socket.is_active() {
+                    Some(Ok(()))
+                } else if !socket.is_listening() {
+                    Some(Err(Errno::EINVAL))
+                } else {
+                    None
+                }
+            })?;
+            let mut state = self.state.lock();
+            if state.handle != handle {
+                // 这个连接已经被其他线程取走
+                continue;
+            }
+            let mut stack = stack().unwrap();
+            let listener = stack.sockets.add(new_tcp_socket());
+            stack
+                .sockets
+                .get::<TcpSocket>(listener)
+                .listen(state.local_port)
+                .map_err(|_| Errno::EADDRINUSE)?;
+            state.handle = listener;
+            return Ok(Socket {
+                socket_type: SocketType::Stream,
+                state: Mutex::new(SocketState {
+                    handle,
+                    local_port: state.local_port,
+                    remote: None,
+                }),
+            });
+        }
+    }
+
+    /// 连接到 `remote`。TCP 会等待连接建立；UDP 只记录对端地址
+    pub fn connect(&self, remote: IpEndpoint) -> SocketResult<()> {
+        let mut state = self.state.lock();
+        let mut stack = stack().unwrap();
+        if state.local_port == 0 {
+            state.local_port = ephemeral_port(&stack.sockets)?;
+        }
+        let handle = state.handle;
+        match self.socket_type {
+            SocketType::Stream => {
+                stack
+                    .sockets
+                    .get::<TcpSocket>(handle)
+                    .connect(remote, state.local_port)
+                    .map_err(|_| Errno::EISCONN)?;
+                drop(stack);
+                drop(state);
+                wait_until(|sockets| {
+                    let socket = sockets.get::<TcpSocket>(handle);
+                    if socket.may_send() {
+                        Some(Ok(()))
+                    } else if !socket.is_open() {
+                        Some(Err(Errno::ECONNREFUSED))
+                    } else {
+                        None
+                    }
+                })
+            }
+            SocketType::Datagram => {
+                let mut socket = stack.sockets.get::<UdpSocket>(handle);
+                if !socket.is_open() {
+                    socket
+                        .bind(state.local_port)
+                        .map_err(|_| Errno::EADDRINUSE)?;
+                }
+                state.remote = Some(remote);
+                Ok(())
+            }
+        }
+    }
+
+    /// 发送数据，返回发送的字节数。发送缓冲区满时等待
+    pub fn send(&self, data: &[u8]) -> SocketResult<usize> {
+        let (handle, remote) = {
+            let state = self.state.lock();
+            (state.handle, state.remote)
+        };
+        match self.socket_type {
+            SocketType::Stream => wait_until(|sockets| {
+                let mut socket = sockets.get::<TcpSocket>(handle);
+                if !socket.may_send() {
+                    Some(Err(Errno::EPIPE))
+                } else if socket.can_send() {
+                    Some(socket.send_slice(data).map_err(|_| Errno::EIO))
+                } else {
+                    None
+                }
+            }),
+            SocketType::Datagram => {
+                let remote = remote.ok_or(Errno::EDESTADDRREQ)?;
+                let result = wait_until(|sockets| {
+                    let mut socket = sockets.get::<UdpSocket>(handle);
+                    if socket.can_send() {
+                        Some(
+                            socket
+                                .send_slice(data, remote)
+                                .map(|_| data.len())
+                                .map_err(|_| Errno::EIO),
+                        )
+                    } else {
+                        None
+                    }
+                });
+                // 立即发出，不必等到下一次中断
+                stack().unwrap().poll();
+                result
+            }
+        }
+    }
+
+    /// 接收数据，返回接收的字节数。没有数据时等待，TCP 连接关闭时返回 0
+    pub fn recv(&self, buf: &mut [u8]) -> SocketResult<usize> {
+        let handle = self.state.lock().handle;
+        match self.socket_type {
+            SocketType::Stream => wait_until(|sockets| {
+                let mut socket = sockets.get::<TcpSocket>(handle);
+                if socket.can_recv() {
+                    Some(socket.recv_slice(buf).map_err(|_| Errno::EIO))
+                } else if !socket.may_recv() {
+                    Some(Ok(0))
+                } else {
+                    None
+                }
+            }),
+            SocketType::Datagram => wait_until(|sockets| {
+                let mut socket = sockets.get::<UdpSocket>(handle);
+                if socket.can_recv() {
+                    Some(
+                        socket
+                            .recv_slice(buf)
+                            .map(|(len, _)| len)
+                            .map_err(|_| Errno::EIO),
+                    )
+                } else {
+                    None
+                }
+            }),
+        }
+    }
+}
+
+/// 关闭连接，协议栈在连接完全关闭后回收 socket
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let handle = self.state.lock().handle;
+        let mut stack = stack().unwrap();
+        match self.socket_type {
+            SocketType::Stream => stack.sockets.get::<TcpSocket>(handle).close(),
+            SocketType::Datagram => stack.sockets.get::<UdpSocket>(handle).close(),
+        }
+        stack.sockets.release(handle);
+        stack.poll();
+    }
+}
+
+/// socket 也可以用 read 和 write 收发数据
+impl INode for Socket {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.recv(buf).map_err(|_| FsError::InvalidParam)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        self.send(buf).map_err(|_| FsError::InvalidParam)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::NotSupported)
+    }
+
+    /// This is used to implement dynamics cast.
+    /// Simply return self in the implement of the function.
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
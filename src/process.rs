@@ -1,30 +1,80 @@
 mod kernel_stack;
 mod processor;
+mod switch;
 mod thread;
 
-pub use processor::PROCESSOR;
-pub use thread::Thread;
+pub use processor::{hart_id, PROCESSOR};
+pub use thread::{auxiliary_vector, Thread};
 
 /// 每个线程的运行栈大小 512 KB
 pub const STACK_SIZE: usize = 0x8_0000;
-/// 共用的内核栈大小 512 KB
-pub const KERNEL_STACK_SIZE: usize = 0x8_0000;
+/// 最多支持的核数，需要与 `linker64.x` 中的 `_max_hart_id` 以及 `interrupt.asm` 一致
+pub const MAX_HART_COUNT: usize = 8;
+/// 每个线程的内核栈大小 128 KB，必须是 2^n，需要与 `interrupt.asm` 一致
+pub const KERNEL_STACK_SIZE: usize = 0x2_0000;
 
-use crate::mem::{Flags, MapType, MemoryResult, MemorySet, Segment, VirtualAddress, PAGE_SIZE};
-use alloc::sync::Arc;
+use crate::fs::FileTable;
+use crate::kernel::condvar::Condvar;
+use crate::mem::{
+    Flags, MapType, MemoryError, MemoryResult, MemorySet, Segment, VirtualAddress,
+    VirtualPageNumber, PAGE_SIZE,
+};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 use core::ops::Range;
-use spin::RwLock;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use spin::{Once, RwLock};
 use xmas_elf::ElfFile;
 
 #[derive(Clone, Copy, Debug)]
 pub struct ProcessId(pub u32);
 
 fn next_process_id() -> ProcessId {
-    // 这里应该用atomic
-    static mut PROCESS_COUNTER: u32 = 0;
-    let ans = unsafe { PROCESS_COUNTER };
-    unsafe { PROCESS_COUNTER += 1 };
-    ProcessId(ans)
+    static PROCESS_COUNTER: AtomicU32 = AtomicU32::new(0);
+    ProcessId(PROCESS_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// init 进程，收养所有的孤儿进程
+///
+/// 内核启动的第一个用户进程会成为 init 进程
+pub static INIT_PROCESS: Once<Arc<RwLock<Process>>> = Once::new();
+
+/// ELF 头中表示 Linux ABI 的 `EI_OSABI`
+const ELFOSABI_LINUX: u8 = 3;
+
+/// 进程所使用的系统调用约定
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Personality {
+    /// 本内核自己的约定：`a0` 为模块编号，`a1` 为函数编号
+    Native,
+    /// Linux riscv64 的约定：`a7` 为系统调用号，失败时返回负的错误码
+    Linux,
+}
+
+impl Personality {
+    /// 根据 ELF 头选择系统调用约定
+    ///
+    /// `EI_OSABI` 为 Linux 的程序使用 Linux 约定，其他程序使用本内核的约定。
+    /// 静态链接的 musl 程序默认是 System V，需要把 `EI_OSABI` 标记为 Linux 才能运行
+    pub fn from_elf(file: &ElfFile) -> Self {
+        if file.input[7] == ELFOSABI_LINUX {
+            Personality::Linux
+        } else {
+            Personality::Native
+        }
+    }
+}
+
+/// 程序加载之后的初始 program break：所有段之后的第一个页面
+fn initial_break(memory_set: &MemorySet) -> VirtualAddress {
+    let end = memory_set
+        .segments
+        .iter()
+        .filter(|segment| segment.map_type == MapType::Framed)
+        .map(|segment| segment.range.end)
+        .max()
+        .unwrap_or_default();
+    VirtualAddress::from(VirtualPageNumber::ceil(end))
 }
 
 #[derive(Debug)]
@@ -36,6 +86,32 @@ pub struct Process {
     pub memory_set: MemorySet,
     /// 进程的编号
     id: ProcessId,
+    /// 进程是否已经被终止，其余线程会在下一次被调度时丢弃
+    killed: bool,
+    /// 每次 exec 之后加一，属于旧程序的线程会在下一次被调度时丢弃
+    generation: usize,
+    /// 正在使用进程页表的核，每一位对应一个核，见 [`Thread::prepare`]
+    active_harts: AtomicUsize,
+    /// 父进程
+    parent: Weak<RwLock<Process>>,
+    /// 所有子进程，包括已经退出但尚未被回收的僵尸进程
+    children: Vec<Arc<RwLock<Process>>>,
+    /// 退出码，为 `Some` 时表示进程已经退出，成为僵尸进程
+    exit_code: Option<isize>,
+    /// 等待子进程退出的线程在此休眠
+    child_exited: Arc<Condvar>,
+    /// 系统调用约定
+    pub personality: Personality,
+    /// 堆的起始地址，即程序加载之后的初始 program break
+    heap_start: VirtualAddress,
+    /// 堆所在的段的结束地址，堆还没有建立时等于 `heap_start`
+    heap_end: VirtualAddress,
+    /// 当前的 program break，见 [`Process::set_break`]
+    program_break: VirtualAddress,
+    /// mmap 建立的、尚未撤销的映射，munmap 只能撤销其中的页面
+    mmap_regions: Vec<Range<VirtualPageNumber>>,
+    /// 打开的文件
+    pub files: FileTable,
 }
 
 impl Process {
@@ -45,15 +121,67 @@ impl Process {
             is_user: false,
             memory_set: MemorySet::new_kernel()?,
             id: next_process_id(),
+            killed: false,
+            generation: 0,
+            active_harts: AtomicUsize::new(0),
+            parent: Weak::new(),
+            children: Vec::new(),
+            exit_code: None,
+            child_exited: Default::default(),
+            personality: Personality::Native,
+            heap_start: VirtualAddress::default(),
+            heap_end: VirtualAddress::default(),
+            program_break: VirtualAddress::default(),
+            mmap_regions: Vec::new(),
+            files: FileTable::new(),
         })))
     }
 
     /// 创建进程，从文件中读取代码
     pub fn from_elf(file: &ElfFile, is_user: bool) -> MemoryResult<Arc<RwLock<Self>>> {
+        let memory_set = MemorySet::from_elf(file, is_user)?;
+        let heap_start = initial_break(&memory_set);
         Ok(Arc::new(RwLock::new(Self {
             is_user,
-            memory_set: MemorySet::from_elf(file, is_user)?,
+            memory_set,
+            id: next_process_id(),
+            killed: false,
+            generation: 0,
+            active_harts: AtomicUsize::new(0),
+            parent: Weak::new(),
+            children: Vec::new(),
+            exit_code: None,
+            child_exited: Default::default(),
+            personality: Personality::from_elf(file),
+            heap_start,
+            heap_end: heap_start,
+            program_break: heap_start,
+            mmap_regions: Vec::new(),
+            files: FileTable::new(),
+        })))
+    }
+
+    /// 复制当前进程，用于 fork
+    ///
+    /// 可写的页面会以写时复制的方式在两个进程之间共享
+    pub fn fork(&mut self) -> MemoryResult<Arc<RwLock<Self>>> {
+        Ok(Arc::new(RwLock::new(Self {
+            is_user: self.is_user,
+            memory_set: self.memory_set.fork()?,
             id: next_process_id(),
+            killed: false,
+            generation: 0,
+            active_harts: AtomicUsize::new(0),
+            parent: Weak::new(),
+            children: Vec::new(),
+            exit_code: None,
+            child_exited: Default::default(),
+            personality: self.personality,
+            heap_start: self.heap_start,
+            heap_end: self.heap_end,
+            program_break: self.program_break,
+            mmap_regions: self.mmap_regions.clone(),
+            files: self.files.clone(),
         })))
     }
 
@@ -62,6 +190,174 @@ impl Process {
         self.id
     }
 
+    /// 进程进入新的一代，用于 exec，返回新的一代
+    ///
+    /// 属于旧程序的线程不会再被调度，正在运行的会在下一次调度时丢弃
+    pub fn retire_threads(&mut self) -> usize {
+        self.generation += 1;
+        self.generation
+    }
+
+    /// 用新的地址空间替换当前的地址空间，用于 exec
+    ///
+    /// 旧的地址空间会被立即释放，调用前需要通过 [`Process::retire_threads`] 让其他线程失效，
+    /// 并等待 [`Process::active_harts`] 中只剩下当前核。
+    /// `memory_set` 中应当只有程序本身的段，堆从它们之后开始
+    pub fn replace_memory_set(&mut self, memory_set: MemorySet, personality: Personality) {
+        let old_memory_set = core::mem::replace(&mut self.memory_set, memory_set);
+        // 当前正在使用旧的页表，必须先切换到新的页表再释放
+        self.memory_set.activate();
+        drop(old_memory_set);
+        self.personality = personality;
+        self.heap_start = initial_break(&self.memory_set);
+        self.heap_end = self.heap_start;
+        self.program_break = self.heap_start;
+        self.mmap_regions.clear();
+    }
+
+    /// 当前的 program break
+    pub fn program_break(&self) -> VirtualAddress {
+        self.program_break
+    }
+
+    /// 移动 program break，用于 brk
+    ///
+    /// 堆是一个按需分配页面的段，只会增长：缩小时只修改 program break，页面仍然保留。
+    /// 不能低于堆的起始地址，也不能与其他段重叠
+    pub fn set_break(&mut self, new_break: VirtualAddress) -> MemoryResult<()> {
+        if new_break < self.heap_start {
+            return Err(MemoryError::Invalid("program break below heap start"));
+        }
+        let new_end = VirtualAddress::from(VirtualPageNumber::ceil(new_break));
+        if new_end > self.heap_end {
+            if self.memory_set.overlap_with(
+                VirtualPageNumber::from(self.heap_end)..VirtualPageNumber::from(new_end),
+            ) {
+                return Err(MemoryError::Invalid(
+                    "program break overlaps with other segments",
+                ));
+            }
+            let heap_start = self.heap_start;
+            if self.heap_end == heap_start {
+                self.memory_set.add_segment(
+                    Segment {
+                        map_type: MapType::Framed,
+                        range: heap_start..new_end,
+                        flags: Flags::READABLE | Flags::WRITABLE | Flags::user(self.is_user),
+                    },
+                    None,
+                )?;
+            } else {
+                let heap = self
+                    .memory_set
+                    .segments
+                    .iter_mut()
+                    .find(|segment| segment.range.start == heap_start)
+                    .ok_or(MemoryError::Invalid("heap segment is missing"))?;
+                heap.range.end = new_end;
+            }
+            self.heap_end = new_end;
+        }
+        self.program_break = new_break;
+        Ok(())
+    }
+
+    /// 进程当前运行的程序是第几代
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    /// 记录当前核开始或者停止使用进程的页表
+    pub fn set_active(&self, active: bool) {
+        let bit = 1 << hart_id();
+        if active {
+            self.active_harts.fetch_or(bit, Ordering::SeqCst);
+        } else {
+            self.active_harts.fetch_and(!bit, Ordering::SeqCst);
+        }
+    }
+
+    /// 正在使用进程页表的核，每一位对应一个核
+    pub fn active_harts(&self) -> usize {
+        self.active_harts.load(Ordering::SeqCst)
+    }
+
+    /// 终止进程
+    pub fn kill(&mut self) {
+        self.killed = true;
+    }
+
+    /// 将 `child` 加入 `parent` 的子进程中
+    pub fn adopt(parent: &Arc<RwLock<Self>>, child: Arc<RwLock<Self>>) {
+        child.write().parent = Arc::downgrade(parent);
+        parent.write().children.push(child);
+    }
+
+    /// 进程退出，成为保存退出码的僵尸进程，等待父进程回收
+    ///
+    /// 进程中的所有线程都会被丢弃，打开的文件全部关闭，子进程交给 init 进程收养，并唤醒等待子进程的父进程
+    pub fn exit(process: &Arc<RwLock<Self>>, code: isize) {
+        let (parent, children, files) = {
+            let mut process = process.write();
+            process.kill();
+            process.exit_code = Some(code);
+            (
+                process.parent.upgrade(),
+                core::mem::take(&mut process.children),
+                core::mem::take(&mut process.files),
+            )
+        };
+        drop(files);
+        // 孤儿进程由 init 进程收养，其中已经退出的需要通知 init 进程回收
+        if let Some(init) = INIT_PROCESS
+            .r#try()
+            .filter(|init| !Arc::ptr_eq(init, process))
+        {
+            for child in children {
+                let exited = child.read().exit_code.is_some();
+                Process::adopt(init, child);
+                if exited {
+                    init.read().child_exited.notify_all();
+                }
+            }
+        }
+        if let Some(parent) = parent {
+            parent.read().child_exited.notify_all();
+        }
+    }
+
+    /// 回收一个已经退出的子进程，`pid` 为 -1 时表示任意子进程
+    ///
+    /// 返回被回收的子进程编号和退出码；符合条件的子进程都还没有退出时返回 `Ok(None)`，
+    /// 没有符合条件的子进程则返回 `Err`
+    pub fn reap_child(&mut self, pid: isize) -> Result<Option<(ProcessId, isize)>, &'static str> {
+        let matches = |child: &Arc<RwLock<Process>>| pid == -1 || child.read().id.0 as isize == pid;
+        if !self.children.iter().any(matches) {
+            return Err("no such child process");
+        }
+        let index = self
+            .children
+            .iter()
+            .position(|child| matches(child) && child.read().exit_code.is_some());
+        Ok(index.map(|index| {
+            let child = self.children.remove(index);
+            let child = child.read();
+            (child.id, child.exit_code.unwrap())
+        }))
+    }
+
+    /// 等待子进程退出的条件变量
+    ///
+    /// 返回 `Arc` 以便在释放进程的锁之后再休眠
+    pub fn child_exited(&self) -> Arc<Condvar> {
+        self.child_exited.clone()
+    }
+
+    /// 进程是否已经被终止
+    pub fn is_killed(&self) -> bool {
+        self.killed
+    }
+
     /// 分配一定数量的连续虚拟空间
     ///
     /// 从 `memory_set` 中找到一段给定长度的未占用虚拟地址空间，分配物理页面并建立映射。返回对应的页面区间。
@@ -83,18 +379,58 @@ impl Process {
             range.start += alloc_size;
             range.end += alloc_size;
         }
-        // 分配物理页面，建立映射
+        // 建立映射。用户进程的页面等到发生 PageFault 时再分配；
+        // 内核线程会在自己的栈上处理中断，不能发生缺页，因此立即分配物理页面
+        let init_data: Option<&[u8]> = if self.is_user { None } else { Some(&[]) };
         self.memory_set.add_segment(
             Segment {
                 map_type: MapType::Framed,
                 range: range.clone(),
                 flags: flags | Flags::user(self.is_user),
             },
-            None,
+            init_data,
         )?;
         // riscv_sbi::println!("range: {:?}", range);
         // riscv_sbi::println!("Memory set: {:?}", self.memory_set);
         // 返回地址区间（使用参数 size，而非向上取整的 alloc_size）
         Ok(Range::from(range.start..(range.start + size)))
     }
+
+    /// 分配一段匿名映射，用于 mmap。与 [`Process::alloc_page_range`] 相同，但之后可以用 munmap 撤销
+    pub fn mmap(&mut self, size: usize, flags: Flags) -> MemoryResult<Range<VirtualAddress>> {
+        let range = self.alloc_page_range(size, flags)?;
+        self.mmap_regions
+            .push(VirtualPageNumber::floor(range.start)..VirtualPageNumber::ceil(range.end));
+        Ok(range)
+    }
+
+    /// 撤销 `range` 中页面的映射，用于 munmap
+    ///
+    /// `range` 必须完全位于 [`Process::mmap`] 建立的映射中，可以只撤销其中的一部分
+    pub fn munmap(&mut self, range: Range<VirtualPageNumber>) -> MemoryResult<()> {
+        let mut vpn = range.start;
+        while vpn < range.end {
+            match self
+                .mmap_regions
+                .iter()
+                .find(|region| region.contains(&vpn))
+            {
+                Some(region) => vpn = region.end,
+                None => return Err(MemoryError::Invalid("range is not mapped by mmap")),
+            }
+        }
+        self.memory_set.unmap_range(range.clone())?;
+        // 只去掉区间中的部分，两端剩下的仍然可以撤销
+        let mut regions = Vec::new();
+        for region in self.mmap_regions.drain(..) {
+            if region.start < range.start {
+                regions.push(region.start..region.end.min(range.start));
+            }
+            if region.end > range.end {
+                regions.push(region.start.max(range.end)..region.end);
+            }
+        }
+        self.mmap_regions = regions;
+        Ok(())
+    }
 }
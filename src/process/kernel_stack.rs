@@ -1,29 +1,103 @@
+//! 线程的内核栈 [`KernelStack`]
+
+use super::KERNEL_STACK_SIZE;
+use crate::interrupt::Context;
+use crate::mem::{
+    Flags, FrameRangeTracker, Mapping, MemoryError, MemoryResult, VirtualAddress,
+    VirtualPageNumber, FRAME_ALLOCATOR, MMIO_AREA_START, PAGE_SIZE, SHARED_KERNEL_AREA_START,
+};
+use alloc::vec::Vec;
 use core::mem::size_of;
-use riscv_sbi_rt::TrapFrame as Context;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// 每个内核栈在共享区域中占用的空间：上一半是栈，下一半不映射，作为保护区域
+///
+/// `__interrupt` 按照这个布局检查内核栈是否溢出，修改时需要同时修改 `interrupt.asm`
+const SLOT_SIZE: usize = KERNEL_STACK_SIZE * 2;
 
-use super::{KERNEL_STACK_SIZE, STACK_SIZE};
+/// 下一个从未使用过的编号
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
 
-/// 内核栈
-#[repr(align(16))]
-#[repr(C)]
-pub struct KernelStack([u8; KERNEL_STACK_SIZE]);
+lazy_static! {
+    /// 已经释放、可以重新使用的编号
+    static ref FREE_SLOTS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+}
 
-/// 公用的内核栈
-pub static KERNEL_STACK: KernelStack = KernelStack([0; STACK_SIZE]);
+/// 线程的内核栈
+///
+/// 放在所有地址空间共享的内核区域中，因此无论切换到哪个页表都可以访问。
+/// 每个内核栈下方都留有不映射的保护区域，栈溢出时会触发缺页异常，而不会破坏相邻的栈
+pub struct KernelStack {
+    /// 在共享区域中的编号
+    slot: usize,
+    /// 栈所使用的物理页面
+    frames: FrameRangeTracker,
+}
 
 impl KernelStack {
-    /// 在栈顶加入 Context 并且返回新的栈顶指针
+    /// 分配一个内核栈并建立映射
+    pub fn new() -> MemoryResult<Self> {
+        let slot = match FREE_SLOTS.lock().pop() {
+            Some(slot) => slot,
+            None => NEXT_SLOT.fetch_add(1, Ordering::Relaxed),
+        };
+        if SHARED_KERNEL_AREA_START + (slot + 1) * SLOT_SIZE > MMIO_AREA_START {
+            return Err(MemoryError::NoMemory("no virtual space for kernel stack"));
+        }
+        let frames = match FRAME_ALLOCATOR
+            .lock()
+            .alloc_contiguous(KERNEL_STACK_SIZE / PAGE_SIZE, 1)
+        {
+            Ok(frames) => frames,
+            Err(message) => {
+                FREE_SLOTS.lock().push(slot);
+                return Err(message);
+            }
+        };
+        // 之后出错时，drop 会负责撤销映射和回收编号
+        let stack = Self { slot, frames };
+        let bottom = VirtualPageNumber::floor(stack.bottom());
+        for i in 0..stack.frames.count() {
+            Mapping::map_shared(
+                bottom + i,
+                stack.frames.page_number() + i,
+                Flags::VALID | Flags::READABLE | Flags::WRITABLE,
+            )?;
+        }
+        Ok(stack)
+    }
+
+    /// 栈底，其下方是保护区域
+    fn bottom(&self) -> VirtualAddress {
+        SHARED_KERNEL_AREA_START + self.slot * SLOT_SIZE + (SLOT_SIZE - KERNEL_STACK_SIZE)
+    }
+
+    /// 栈顶
+    pub fn top(&self) -> VirtualAddress {
+        self.bottom() + KERNEL_STACK_SIZE
+    }
+
+    /// 在栈顶放入 Context 并返回其地址
+    ///
+    /// 用户线程陷入内核时，`__interrupt` 总是把 Context 保存在这个位置
     pub fn push_context(&self, context: Context) -> *mut Context {
-        // 栈顶
-        let stack_top = &self.0 as *const _ as usize + size_of::<Self>();
-        // riscv_sbi::println!("Top: {:p}", unsafe { stack_top as *mut () });
-        // riscv_sbi::println!("Bottom: {:p}", &self.0 as *const _);
-        // Context 的位置
-        let push_address = (stack_top - size_of::<Context>()) as *mut Context;
-        // riscv_sbi::println!("Push addr: {:p}", unsafe { push_address as *mut () });
+        let push_address = (self.top().0 - size_of::<Context>()) as *mut Context;
         unsafe {
             *push_address = context;
         }
         push_address
     }
 }
+
+/// 撤销映射并回收编号，物理页面随 [`FrameRangeTracker`] 一起释放
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let bottom = VirtualPageNumber::floor(self.bottom());
+        for i in 0..self.frames.count() {
+            Mapping::unmap_shared(bottom + i);
+        }
+        FREE_SLOTS.lock().push(self.slot);
+    }
+}
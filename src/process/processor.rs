@@ -1,60 +1,157 @@
+use super::switch::{__switch, KernelContext};
 use crate::algo::Scheduler;
 use crate::algo::SchedulerImpl;
-use crate::process::Thread;
+use crate::process::{Process, Thread};
 use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
 use hashbrown::HashSet;
 use lazy_static::lazy_static;
+use riscv::register::sstatus;
 use riscv_sbi::println;
-use riscv_sbi_rt::TrapFrame;
+use spin::Mutex;
 
-mod unsafe_wrapper {
+mod per_hart {
+    use super::hart_id;
+    use crate::process::MAX_HART_COUNT;
     use core::cell::UnsafeCell;
 
-    /// 允许从 &self 获取 &mut 内部变量
-    pub struct UnsafeWrapper<T> {
-        object: UnsafeCell<T>,
+    /// 每个核各持有一份的变量，通过 [`hart_id`] 访问当前核的那一份
+    ///
+    /// 每一份只会被它所属的核访问，因此允许从 &self 获取 &mut 内部变量
+    pub struct PerHart<T> {
+        objects: [UnsafeCell<T>; MAX_HART_COUNT],
     }
 
-    impl<T> UnsafeWrapper<T> {
-        #[allow(unused)]
-        pub fn new(object: T) -> Self {
-            Self {
-                object: UnsafeCell::new(object),
-            }
-        }
-
+    impl<T> PerHart<T> {
         #[allow(clippy::mut_from_ref)]
         pub fn get(&self) -> &mut T {
-            unsafe { &mut *self.object.get() }
+            unsafe { &mut *self.objects[hart_id()].get() }
         }
     }
 
-    impl<T: Default> Default for UnsafeWrapper<T> {
+    impl<T: Default> Default for PerHart<T> {
         fn default() -> Self {
             Self {
-                object: UnsafeCell::new(T::default()),
+                objects: Default::default(),
             }
         }
     }
 
-    unsafe impl<T> Sync for UnsafeWrapper<T> {}
+    unsafe impl<T> Sync for PerHart<T> {}
 }
 
-use unsafe_wrapper::UnsafeWrapper;
+use per_hart::PerHart;
 lazy_static! {
-    /// 全局的 [`Processor`]
-    pub static ref PROCESSOR: UnsafeWrapper<Processor> = Default::default();
+    /// 每个核的 [`Processor`]
+    pub static ref PROCESSOR: PerHart<Processor> = Default::default();
+    /// 所有核共享的 [`ThreadPool`]
+    static ref THREAD_POOL: Mutex<ThreadPool> = Default::default();
 }
 
-/// 线程调度和管理
+/// 当前核的编号
+///
+/// 内核运行时 `tp` 寄存器中总是保存着核的编号，见 `interrupt.asm`
+pub fn hart_id() -> usize {
+    let hart_id: usize;
+    unsafe { llvm_asm!("mv $0, tp" : "=r"(hart_id) ::: "volatile") };
+    hart_id
+}
+
+/// 所有核共享的线程池
 #[derive(Default)]
-pub struct Processor {
-    /// 当前正在执行的线程
-    current_thread: Option<Arc<Thread>>,
-    /// 线程调度器，记录所有线程
+struct ThreadPool {
+    /// 线程调度器，记录所有就绪的线程。正在运行的线程不在其中，因此不会被两个核同时选中
     scheduler: SchedulerImpl<Arc<Thread>>,
     /// 保存休眠线程
     sleeping_threads: HashSet<Arc<Thread>>,
+    /// 在切换出去之前就已经被唤醒的线程，它们不会进入休眠
+    woken_threads: HashSet<Arc<Thread>>,
+    /// 正在运行线程的核数
+    running_count: usize,
+    /// 没有线程可运行、正在等待中断的核
+    idle_harts: usize,
+    /// 没有线程可运行、通过 HSM 停止的核
+    stopped_harts: usize,
+}
+
+impl ThreadPool {
+    /// 加入一个就绪的线程，并唤醒一个空闲的核来运行它
+    ///
+    /// 优先唤醒等待中断的核，没有时再启动一个停止的核。启动可能需要等待对方真正停止，
+    /// 因此只返回要启动的核，由调用者在释放线程池的锁之后调用 [`start_stopped_hart`]
+    #[must_use]
+    fn add_ready_thread(&mut self, thread: Arc<Thread>) -> Option<usize> {
+        self.scheduler.add_thread(thread, 0);
+        if self.idle_harts != 0 {
+            let hart_id = self.idle_harts.trailing_zeros() as usize;
+            self.idle_harts &= !(1 << hart_id);
+            crate::sbi::send_ipi(1 << hart_id);
+            None
+        } else if self.stopped_harts != 0 {
+            let hart_id = self.stopped_harts.trailing_zeros() as usize;
+            self.stopped_harts &= !(1 << hart_id);
+            Some(hart_id)
+        } else {
+            None
+        }
+    }
+}
+
+/// 有核调用 `hart_stop` 失败之后，空闲的核都改为等待中断
+static HART_STOP_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// 空闲时是否停止当前核
+///
+/// 启动核始终运行，负责处理定时器和关机，其它的核在固件支持 HSM 时停止
+fn should_stop() -> bool {
+    hart_id() != crate::boot_hart()
+        && crate::sbi::extensions().hsm
+        && !HART_STOP_FAILED.load(Ordering::SeqCst)
+}
+
+/// 启动一个在调度循环中停止的核，调用时不能持有线程池的锁
+///
+/// 核在释放线程池的锁之后才调用 `hart_stop`，在它真正停止之前 `hart_start` 会失败，此时重试。
+/// 如果它停止失败，改为用核间中断唤醒
+fn start_stopped_hart(hart_id: usize) {
+    while crate::start_hart(hart_id).is_err() {
+        if HART_STOP_FAILED.load(Ordering::SeqCst) {
+            crate::sbi::send_ipi(1 << hart_id);
+            return;
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// 线程切换回调度循环的原因
+enum SwitchReason {
+    /// 让出 CPU，仍然就绪
+    Yield,
+    /// 进入休眠
+    Sleep,
+    /// 线程已经结束
+    Exit,
+}
+
+impl Default for SwitchReason {
+    fn default() -> Self {
+        SwitchReason::Yield
+    }
+}
+
+/// 每个核的线程调度和管理
+///
+/// 调度循环 [`Processor::run`] 运行在每个核启动时的栈上，从共享的线程池中取出线程执行。
+/// 线程需要让出 CPU 时，通过 [`__switch`] 保存自己在内核中的状态并回到调度循环，
+/// 由调度循环在已经离开线程的内核栈之后，再把它放回线程池或者休眠队列
+#[derive(Default)]
+pub struct Processor {
+    /// 当前正在执行的线程
+    current_thread: Option<Arc<Thread>>,
+    /// 调度循环的 [`KernelContext`]
+    idle_context: KernelContext,
+    /// 当前线程切换回调度循环的原因
+    switch_reason: SwitchReason,
 }
 
 impl Processor {
@@ -63,89 +160,170 @@ impl Processor {
         self.current_thread.as_ref().unwrap().clone()
     }
 
-    /// 第一次开始运行
-    pub fn run(&mut self) -> ! {
-        // interrupt.asm 中的标签
-        extern "C" {
-            fn __restore(context: usize);
-        }
-        if self.current_thread.is_none() {
-            panic!("no thread to run, shutting down");
-        }
-        // 从 current_thread 中取出 Context
-        let context = self.current_thread().prepare();
-        // 从此将没有回头
-        unsafe {
-            __restore(context as usize);
-        }
-        unreachable!()
+    /// 当前核是否正在执行线程，启动阶段和调度循环中没有当前线程，不能休眠
+    pub fn has_current_thread(&self) -> bool {
+        self.current_thread.is_some()
     }
 
-    /// 在一个时钟中断时，替换掉 context
-    pub fn prepare_next_thread(&mut self, context: &mut TrapFrame) -> *mut TrapFrame {
+    /// 调度循环，不断选出下一个线程并切换过去执行
+    ///
+    /// 没有线程可运行时，启动核等待中断，其它的核通过 HSM 停止，有新的就绪线程时重新启动
+    pub fn run(&mut self) -> ! {
         loop {
-            // 向调度器询问下一个线程
-            if let Some(next_thread) = self.scheduler.get_next() {
-                if next_thread == self.current_thread() {
-                    // 没有更换线程，直接返回 Context
-                    return context;
-                } else {
-                    // 准备下一个线程
-                    let next_context = next_thread.prepare();
-                    let current_thread = self.current_thread.replace(next_thread).unwrap();
-                    // 储存当前线程 Context
-                    current_thread.park(context.clone());
-                    // 返回下一个线程的 Context
-                    return next_context;
-                }
-            } else {
-                // 没有活跃线程
-                if self.sleeping_threads.is_empty() {
-                    // 没有休眠线程，退出
+            let stop = should_stop();
+            // 从线程池中取出下一个线程
+            let (next_thread, scheduler_time_slice) = {
+                let mut pool = THREAD_POOL.lock();
+                let next_thread = pool.scheduler.get_next();
+                if let Some(thread) = &next_thread {
+                    pool.scheduler.remove_thread(thread);
+                    pool.running_count += 1;
+                } else if pool.running_count == 0 && pool.sleeping_threads.is_empty() {
+                    // 没有任何线程，退出
                     println!("[Kernel] All threads terminated, shutting down");
-                    riscv_sbi::legacy::shutdown()
+                    crate::driver::syscon::poweroff(0)
+                } else if stop {
+                    pool.stopped_harts |= 1 << hart_id();
                 } else {
-                    // 有休眠线程，等待中断
-                    unsafe { riscv::asm::wfi() }
+                    pool.idle_harts |= 1 << hart_id();
+                }
+                (next_thread, pool.scheduler.time_slice())
+            };
+            let thread = match next_thread {
+                Some(thread) => thread,
+                None if stop => {
+                    crate::timer::stop_slice();
+                    // 当前核设置的时钟随核停止失效，由启动核接管定时器
+                    crate::sbi::send_ipi(1 << crate::boot_hart());
+                    crate::sbi::set_online(hart_id(), false);
+                    // 成功时不会返回，重新启动后从内核入口开始执行
+                    let error = crate::sbi::hart_stop();
+                    println!("hart {} failed to stop: error {}", hart_id(), error);
+                    HART_STOP_FAILED.store(true, Ordering::SeqCst);
+                    // 标记为停止期间没有收到 TLB 刷新
+                    crate::sbi::set_online(hart_id(), true);
+                    unsafe { llvm_asm!("sfence.vma" :::: "volatile") };
+                    THREAD_POOL.lock().stopped_harts &= !(1 << hart_id());
+                    continue;
+                }
+                None => {
+                    // 空闲时停止时间片，只有定时器到期或者其他中断才会唤醒
+                    crate::timer::stop_slice();
+                    // 打开中断等待，有新的就绪线程时会收到核间中断
+                    unsafe {
+                        sstatus::set_sie();
+                        riscv::asm::wfi();
+                        sstatus::clear_sie();
+                    }
+                    continue;
+                }
+            };
+            // 已经失效的线程直接丢弃
+            if !thread.prepare() {
+                THREAD_POOL.lock().running_count -= 1;
+                continue;
+            }
+            // 每次调度都给线程一个完整的时间片，依次使用线程自己、启动参数和调度器的设置
+            let time_slice = thread
+                .inner()
+                .time_slice
+                .or_else(crate::timer::configured_time_slice)
+                .unwrap_or(scheduler_time_slice);
+            crate::timer::start_slice(time_slice);
+            self.current_thread = Some(thread.clone());
+            unsafe { __switch(&mut self.idle_context, thread.kernel_context()) };
+            self.current_thread = None;
+            // 换回内核的页表，线程所在的进程可能在 exec 时释放旧的页表
+            thread.release();
+            // 线程已经切换出去，可以安全地交给其他核
+            let mut pool = THREAD_POOL.lock();
+            pool.running_count -= 1;
+            let stopped_hart = match core::mem::take(&mut self.switch_reason) {
+                SwitchReason::Yield => pool.add_ready_thread(thread),
+                SwitchReason::Sleep => {
+                    if pool.woken_threads.remove(&thread) {
+                        pool.add_ready_thread(thread)
+                    } else {
+                        pool.sleeping_threads.insert(thread);
+                        None
+                    }
                 }
+                SwitchReason::Exit => {
+                    pool.woken_threads.remove(&thread);
+                    // 最后一个引用在释放锁之后才丢弃，此时已经不在它的内核栈上了
+                    drop(pool);
+                    drop(thread);
+                    continue;
+                }
+            };
+            drop(pool);
+            if let Some(hart_id) = stopped_hart {
+                start_stopped_hart(hart_id);
             }
         }
     }
 
+    /// 从当前线程切换回调度循环
+    ///
+    /// 调用前必须释放所有的锁；返回时当前线程已经再次被调度，可能在另一个核上
+    fn switch_to_idle(&mut self, reason: SwitchReason) {
+        self.switch_reason = reason;
+        // 切换前不能持有当前线程的引用，否则已经结束的线程无法被释放
+        let kernel_context = self.current_thread().kernel_context();
+        unsafe { __switch(kernel_context, &self.idle_context) };
+    }
+
+    /// 当前线程让出 CPU，仍然保持就绪
+    pub fn yield_current_thread(&mut self) {
+        // 在调度循环中发生的中断没有线程可以让出
+        if self.current_thread.is_some() {
+            self.switch_to_idle(SwitchReason::Yield);
+        }
+    }
+
     /// 添加一个待执行的线程
     pub fn add_thread(&mut self, thread: Arc<Thread>) {
-        if self.current_thread.is_none() {
-            self.current_thread = Some(thread.clone());
-        }
         // riscv_sbi::println!("[add_thread] add {:x?}", thread);
-        self.scheduler.add_thread(thread, 0);
+        let stopped_hart = THREAD_POOL.lock().add_ready_thread(thread);
+        if let Some(hart_id) = stopped_hart {
+            start_stopped_hart(hart_id);
+        }
     }
 
     /// 唤醒一个休眠线程
+    ///
+    /// 线程可能还没有完成切换，此时它切换出去之后会直接回到线程池
     pub fn wake_thread(&mut self, thread: Arc<Thread>) {
-        self.sleeping_threads.remove(&thread);
-        self.scheduler.add_thread(thread, 0);
+        let mut pool = THREAD_POOL.lock();
+        let stopped_hart = if pool.sleeping_threads.remove(&thread) {
+            pool.add_ready_thread(thread)
+        } else {
+            pool.woken_threads.insert(thread);
+            None
+        };
+        drop(pool);
+        if let Some(hart_id) = stopped_hart {
+            start_stopped_hart(hart_id);
+        }
     }
 
-    /// 保存当前线程的 `Context`
-    pub fn park_current_thread(&mut self, context: &TrapFrame) {
-        self.current_thread().park(*context);
+    /// 令当前线程进入休眠，直到被 [`Processor::wake_thread`] 唤醒才返回
+    pub fn sleep_current_thread(&mut self) {
+        self.switch_to_idle(SwitchReason::Sleep);
     }
 
-    /// 令当前线程进入休眠
-    pub fn sleep_current_thread(&mut self) {
-        // 从 current_thread 中取出
-        let current_thread = self.current_thread();
-        // 从 scheduler 移出到 sleeping_threads 中
-        self.scheduler.remove_thread(&current_thread);
-        self.sleeping_threads.insert(current_thread);
-    }
-
-    /// 终止当前的线程
-    pub fn kill_current_thread(&mut self) {
-        assert!(!self.current_thread.is_none());
-        // 从调度器中移除
-        let thread = self.current_thread.take().unwrap();
-        self.scheduler.remove_thread(&thread);
+    /// 终止当前的线程，切换到调度循环后不再返回
+    pub fn exit_current_thread(&mut self) -> ! {
+        // 调度循环中还有一个引用，线程会在回到调度循环之后才被释放
+        self.switch_to_idle(SwitchReason::Exit);
+        unreachable!()
+    }
+
+    /// 终止当前线程所属的进程，退出码为 -1
+    ///
+    /// 进程中其他的线程会在被调度到时丢弃
+    pub fn kill_current_process(&mut self) -> ! {
+        Process::exit(&self.current_thread().process(), -1);
+        self.exit_current_thread()
     }
 }
@@ -0,0 +1,48 @@
+# 内核中的线程切换
+#
+# KernelContext 的布局见 switch.rs：ra、sp、s0 到 s11
+
+    .section .text
+
+# __switch(current: *mut KernelContext, next: *const KernelContext)
+#
+# 保存当前的 callee-saved 寄存器到 current，再从 next 中恢复，
+# 返回时已经在 next 对应的栈上，从它上一次调用 __switch 的地方继续执行
+    .globl __switch
+    .balign 4
+__switch:
+    sd      ra, 0*8(a0)
+    sd      sp, 1*8(a0)
+    sd      s0, 2*8(a0)
+    sd      s1, 3*8(a0)
+    sd      s2, 4*8(a0)
+    sd      s3, 5*8(a0)
+    sd      s4, 6*8(a0)
+    sd      s5, 7*8(a0)
+    sd      s6, 8*8(a0)
+    sd      s7, 9*8(a0)
+    sd      s8, 10*8(a0)
+    sd      s9, 11*8(a0)
+    sd      s10, 12*8(a0)
+    sd      s11, 13*8(a0)
+    ld      ra, 0*8(a1)
+    ld      sp, 1*8(a1)
+    ld      s0, 2*8(a1)
+    ld      s1, 3*8(a1)
+    ld      s2, 4*8(a1)
+    ld      s3, 5*8(a1)
+    ld      s4, 6*8(a1)
+    ld      s5, 7*8(a1)
+    ld      s6, 8*8(a1)
+    ld      s7, 9*8(a1)
+    ld      s8, 10*8(a1)
+    ld      s9, 11*8(a1)
+    ld      s10, 12*8(a1)
+    ld      s11, 13*8(a1)
+    ret
+
+# 新线程第一次被切换到时从这里开始，此时 sp 指向内核栈上的 Context
+    .globl __trap_return
+__trap_return:
+    mv      a0, sp
+    j       __restore
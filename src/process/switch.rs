@@ -0,0 +1,33 @@
+//! 内核中的线程切换 [`KernelContext`]
+
+use crate::interrupt::Context;
+
+global_asm!(include_str!("switch.asm"));
+
+extern "C" {
+    /// 保存当前的 [`KernelContext`] 到 `current`，然后切换到 `next`
+    pub fn __switch(current: *mut KernelContext, next: *const KernelContext);
+    fn __trap_return();
+}
+
+/// 线程在内核中被切换出去时保存的寄存器
+///
+/// 切换发生在函数调用 [`__switch`] 时，因此只需要保存 callee-saved 寄存器
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelContext {
+    ra: usize,
+    sp: usize,
+    s: [usize; 12],
+}
+
+impl KernelContext {
+    /// 新线程的 `KernelContext`，第一次切换到它时会从 `context` 返回到线程的入口
+    pub fn new(context: *mut Context) -> Self {
+        Self {
+            ra: __trap_return as usize,
+            sp: context as usize,
+            s: [0; 12],
+        }
+    }
+}
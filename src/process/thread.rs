@@ -1,43 +1,50 @@
-use super::kernel_stack::KERNEL_STACK;
-use super::STACK_SIZE;
-use crate::fs::{STDIN, STDOUT};
-use crate::mem::{Flags, MemoryResult, VirtualAddress};
-use crate::process::Process;
+use super::kernel_stack::KernelStack;
+use super::switch::KernelContext;
+use super::{hart_id, STACK_SIZE};
+use crate::fs::ENTROPY_POOL;
+use crate::interrupt::Context;
+use crate::mem::{
+    Flags, MemoryError, MemoryResult, MemorySet, VirtualAddress, KERNEL_MEMORY_SET, PAGE_SIZE,
+};
+use crate::process::{Personality, Process};
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::hash::{Hash, Hasher};
+use core::mem::size_of;
 use core::ops::Range;
-use rcore_fs::vfs::INode;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use riscv::register::sstatus;
 use spin::{Mutex, RwLock};
+use xmas_elf::{program::Type, ElfFile};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
-pub struct ThreadId(usize);
-
-type Context = riscv_sbi_rt::TrapFrame;
+pub struct ThreadId(pub usize);
 
 /// 线程的信息
 pub struct Thread {
     /// 线程 ID
     id: ThreadId,
-    /// 线程的栈
-    stack: Range<VirtualAddress>,
     /// 用 `Mutex` 包装一些可变的变量
     inner: Mutex<ThreadInner>,
     /// 所属的进程
     process: Arc<RwLock<Process>>,
+    /// 线程的内核栈，用户线程陷入内核时 Context 保存在栈顶
+    #[allow(unused)]
+    kernel_stack: KernelStack,
 }
 
 // todo: private
 pub struct ThreadInner {
-    /// 线程执行上下文
-    ///
-    /// 当且仅当线程被暂停执行时，`context` 为 `Some`
-    context: Option<Context>,
-    // 占用的资源等等
-    /// 打开的文件
-    pub descriptors: Vec<Arc<dyn INode>>,
+    /// 线程在内核中被切换出去时保存的上下文
+    kernel_context: KernelContext,
+    /// 线程的栈，exec 之后会重新分配
+    stack: Range<VirtualAddress>,
+    /// 线程所运行的程序属于进程的第几代，见 [`Process::generation`]
+    generation: usize,
+    /// 线程自己的时间片长度（微秒），为 `None` 时使用全局的设置
+    pub time_slice: Option<u64>,
 }
 
 /// 通过线程 ID 来判等
@@ -61,7 +68,10 @@ impl Hash for Thread {
     }
 }
 
-static mut THREAD_COUNTER: usize = 0;
+fn next_thread_id() -> ThreadId {
+    static THREAD_COUNTER: AtomicUsize = AtomicUsize::new(1);
+    ThreadId(THREAD_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
 
 impl Thread {
     /// 创建一个线程
@@ -82,24 +92,127 @@ impl Thread {
             arguments,
             process.read().is_user,
         );
+        Self::with_context(process, stack, context)
+    }
+
+    /// 创建运行程序的第一个线程，与 exec 一样在栈上放入参数、环境变量和辅助向量
+    pub fn new_program(
+        process: Arc<RwLock<Process>>,
+        entry_point: usize,
+        auxv: &[(usize, usize)],
+        args: &[String],
+        envs: &[String],
+    ) -> MemoryResult<Arc<Thread>> {
+        let (stack, context) = {
+            let mut process = process.write();
+            let stack = process.alloc_page_range(STACK_SIZE, Flags::READABLE | Flags::WRITABLE)?;
+            let (stack_top, argv, envp) =
+                push_arguments(&mut process.memory_set, stack.end, auxv, args, envs)?;
+            let arguments = entry_arguments(process.personality, args.len(), argv, envp);
+            let context = new_context(stack_top, entry_point, Some(&arguments), process.is_user);
+            (stack, context)
+        };
+        Self::with_context(process, stack, context)
+    }
+
+    /// 用准备好的栈和 `Context` 打包成线程
+    fn with_context(
+        process: Arc<RwLock<Process>>,
+        stack: Range<VirtualAddress>,
+        context: Context,
+    ) -> MemoryResult<Arc<Thread>> {
+        let generation = process.read().generation();
+        // 分配内核栈，第一次运行时从栈顶的 Context 返回到线程入口
+        let kernel_stack = KernelStack::new()?;
+        let kernel_context = KernelContext::new(kernel_stack.push_context(context));
 
         // 打包成线程
         let thread = Arc::new(Thread {
-            id: unsafe {
-                THREAD_COUNTER += 1;
-                ThreadId(THREAD_COUNTER)
-            },
-            stack,
+            id: next_thread_id(),
             process,
+            kernel_stack,
             inner: Mutex::new(ThreadInner {
-                context: Some(context),
-                descriptors: vec![STDIN.clone(), STDOUT.clone()],
+                kernel_context,
+                stack,
+                generation,
+                time_slice: None,
             }),
         });
 
         Ok(thread)
     }
 
+    /// 将线程复制到 fork 得到的新进程中
+    ///
+    /// 新线程使用相同地址的栈（已经随进程复制），从 `context` 处继续执行
+    pub fn fork(
+        &self,
+        process: Arc<RwLock<Process>>,
+        context: Context,
+    ) -> MemoryResult<Arc<Thread>> {
+        let kernel_stack = KernelStack::new()?;
+        let kernel_context = KernelContext::new(kernel_stack.push_context(context));
+        let inner = self.inner();
+        Ok(Arc::new(Thread {
+            id: next_thread_id(),
+            process,
+            kernel_stack,
+            inner: Mutex::new(ThreadInner {
+                kernel_context,
+                stack: inner.stack.clone(),
+                generation: inner.generation,
+                time_slice: inner.time_slice,
+            }),
+        }))
+    }
+
+    /// 在当前线程中执行新的程序
+    ///
+    /// 用 `memory_set` 替换进程的地址空间，重新分配栈并放入参数、环境变量和辅助向量 `auxv`，
+    /// 然后将 `context` 重置到新程序的入口。进程中的其他线程会在被调度到时丢弃。
+    ///
+    /// 其他线程可能正在别的核上使用旧的页表，因此先让它们失效，等它们都离开之后再释放旧的地址空间。
+    /// 当前线程已经失效（进程被终止，或者其他线程先一步 exec）时返回 `Err`，不做任何修改
+    pub fn exec(
+        &self,
+        memory_set: MemorySet,
+        personality: Personality,
+        entry_point: usize,
+        auxv: &[(usize, usize)],
+        args: &[String],
+        envs: &[String],
+        context: &mut Context,
+    ) -> MemoryResult<()> {
+        {
+            let mut process = self.process.write();
+            let mut inner = self.inner();
+            if process.is_killed() || process.generation() != inner.generation {
+                return Err(MemoryError::Invalid("thread is stale"));
+            }
+            inner.generation = process.retire_threads();
+        }
+        wait_for_other_harts(&self.process);
+        let mut process = self.process.write();
+        process.replace_memory_set(memory_set, personality);
+        let closed = process.files.exec();
+        let stack = process.alloc_page_range(STACK_SIZE, Flags::READABLE | Flags::WRITABLE)?;
+        let (stack_top, argv, envp) =
+            push_arguments(&mut process.memory_set, stack.end, auxv, args, envs)?;
+        let arguments = entry_arguments(personality, args.len(), argv, envp);
+        *context = new_context(stack_top, entry_point, Some(&arguments), process.is_user);
+        self.inner().stack = stack;
+        drop(process);
+        // 关闭 close-on-exec 的文件，socket 会在此时断开连接
+        drop(closed);
+        Ok(())
+    }
+
+    /// 线程是否已经失效：所属进程已被终止，或者进程已经 exec 为新的程序
+    pub fn is_stale(&self) -> bool {
+        let process = self.process.read();
+        process.is_killed() || process.generation() != self.inner().generation
+    }
+
     pub fn thread_id(&self) -> ThreadId {
         self.id
     }
@@ -112,33 +225,160 @@ impl Thread {
         self.inner.lock()
     }
 
-    /// 准备执行一个线程
+    /// 准备执行一个线程，激活对应进程的页表并记录当前核正在使用它
     ///
-    /// 激活对应进程的页表，并返回其 Context
-    pub fn prepare(&self) -> *mut Context {
-        // 激活页表
-        self.process.read().memory_set.activate();
-        // 取出 Context
-        let parked_frame = self.inner().context.take().unwrap();
-
-        if self.process.read().is_user {
-            // 用户线程则将 Context 放至内核栈顶
-            KERNEL_STACK.push_context(parked_frame)
-        } else {
-            // 内核线程则将 Context 放至 sp 下
-            let context = (parked_frame.sp - core::mem::size_of::<Context>()) as *mut Context;
-            unsafe { *context = parked_frame };
-            context
+    /// 线程已经失效时返回 `false`，不会激活页表。检查和记录在同一次持有进程的锁时完成，
+    /// 因此 exec 让线程失效之后，不会再有核开始使用旧的页表
+    pub fn prepare(&self) -> bool {
+        let process = self.process.read();
+        if process.is_killed() || process.generation() != self.inner().generation {
+            return false;
         }
+        process.set_active(true);
+        process.memory_set.activate();
+        true
     }
 
-    /// 发生时钟中断后暂停线程，保存状态
-    pub fn park(&self, context: Context) {
-        // 检查目前线程内的 context 应当为 None
-        let slot = &mut self.inner().context;
-        assert!(slot.is_none());
-        // 将 Context 保存到线程中
-        slot.replace(context);
+    /// 线程切换回调度循环之后调用，换回内核的页表，当前核不再使用进程的页表
+    pub fn release(&self) {
+        KERNEL_MEMORY_SET.wait().unwrap().activate();
+        self.process.read().set_active(false);
+    }
+
+    /// 保存 [`KernelContext`] 的位置，用于 [`super::switch::__switch`]
+    ///
+    /// 线程存活期间这个位置不会改变，但只能在线程没有运行时读写
+    pub fn kernel_context(&self) -> *mut KernelContext {
+        &mut self.inner().kernel_context as *mut _
+    }
+}
+
+/// 等待 `process` 的页表在其他核上都不再使用，用于 exec 释放旧的地址空间之前
+///
+/// 其他线程此时已经失效，核间中断使它们尽快回到调度循环，见 `supervisor_soft`
+fn wait_for_other_harts(process: &RwLock<Process>) {
+    let others = process.read().active_harts() & !(1 << hart_id());
+    if others == 0 {
+        return;
+    }
+    crate::sbi::send_ipi(others);
+    while process.read().active_harts() & !(1 << hart_id()) != 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// 辅助向量的结束标志
+const AT_NULL: usize = 0;
+/// 程序头表的地址
+const AT_PHDR: usize = 3;
+/// 程序头表中每一项的大小
+const AT_PHENT: usize = 4;
+/// 程序头表的项数
+const AT_PHNUM: usize = 5;
+/// 页面大小
+const AT_PAGESZ: usize = 6;
+/// 程序入口
+const AT_ENTRY: usize = 9;
+/// 栈上 16 个随机字节的地址，musl 用它初始化栈保护
+const AT_RANDOM: usize = 25;
+
+/// 根据 ELF 文件生成放在程序栈上的辅助向量，不包括 `AT_RANDOM` 和 `AT_NULL`
+///
+/// musl 在启动时从中读取程序头表来初始化线程局部存储
+pub fn auxiliary_vector(file: &ElfFile) -> Vec<(usize, usize)> {
+    let ph_offset = file.header.pt2.ph_offset() as usize;
+    // 程序头表在内存中的地址：优先使用 PT_PHDR，否则从包含它的 PT_LOAD 段推算
+    let phdr = file
+        .program_iter()
+        .find_map(|header| {
+            let offset = header.offset() as usize;
+            match header.get_type() {
+                Ok(Type::Phdr) => Some(header.virtual_addr() as usize),
+                Ok(Type::Load)
+                    if offset <= ph_offset && ph_offset < offset + header.file_size() as usize =>
+                {
+                    Some(header.virtual_addr() as usize + ph_offset - offset)
+                }
+                _ => None,
+            }
+        })
+        .unwrap_or(0);
+    vec![
+        (AT_PHDR, phdr),
+        (AT_PHENT, file.header.pt2.ph_entry_size() as usize),
+        (AT_PHNUM, file.header.pt2.ph_count() as usize),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, file.header.pt2.entry_point() as usize),
+    ]
+}
+
+/// 按照 RISC-V psABI 的约定，在新程序的栈顶放入参数、环境变量和辅助向量
+///
+/// 从栈顶向下依次为：所有字符串，`AT_RANDOM` 指向的 16 个随机字节，（16 字节对齐后）
+/// 以 `AT_NULL` 结尾的辅助向量、以空指针结尾的 `envp` 和 `argv` 指针数组，以及位于栈顶的 `argc`。
+///
+/// 返回新的栈顶，以及 `argv` 和 `envp` 数组的地址
+fn push_arguments(
+    memory_set: &mut MemorySet,
+    stack_top: VirtualAddress,
+    auxv: &[(usize, usize)],
+    args: &[String],
+    envs: &[String],
+) -> MemoryResult<(usize, usize, usize)> {
+    let mut sp = stack_top;
+    // 先放入所有的字符串，记录它们的地址
+    let mut push_strings = |strings: &[String]| -> MemoryResult<Vec<usize>> {
+        let mut pointers = Vec::new();
+        for string in strings.iter() {
+            sp -= string.len() + 1;
+            memory_set.write_bytes(sp, string.as_bytes())?;
+            memory_set.write_bytes(sp + string.len(), &[0])?;
+            pointers.push(sp.0);
+        }
+        Ok(pointers)
+    };
+    let env_pointers = push_strings(envs)?;
+    let arg_pointers = push_strings(args)?;
+    let mut random = [0u8; 16];
+    ENTROPY_POOL.read_pseudo(&mut random);
+    sp -= random.len();
+    memory_set.write_bytes(sp, &random)?;
+    let random_pointer = sp.0;
+
+    // argc | argv[0..] | NULL | envp[0..] | NULL | auxv[0..] | AT_RANDOM | AT_NULL
+    let mut words = vec![args.len()];
+    words.extend(arg_pointers);
+    words.push(0);
+    let envp_index = words.len();
+    words.extend(env_pointers);
+    words.push(0);
+    for &(key, value) in auxv.iter() {
+        words.extend(&[key, value]);
+    }
+    words.extend(&[AT_RANDOM, random_pointer]);
+    words.extend(&[AT_NULL, 0]);
+
+    let mut bytes = Vec::with_capacity(words.len() * size_of::<usize>());
+    for word in words.iter() {
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let sp = VirtualAddress((sp.0 - bytes.len()) & !0xf);
+    memory_set.write_bytes(sp, &bytes)?;
+    Ok((
+        sp.0,
+        sp.0 + size_of::<usize>(),
+        sp.0 + envp_index * size_of::<usize>(),
+    ))
+}
+
+/// 程序入口处 `a0`、`a1` 和 `a2` 的值
+///
+/// 本内核的程序直接从中取得 argc、argv 和 envp。Linux 程序只从栈上读取参数，
+/// 而 `a0` 是动态链接器交给程序在退出时调用的函数（rtld_fini），因此全部为 0
+fn entry_arguments(personality: Personality, argc: usize, argv: usize, envp: usize) -> [usize; 3] {
+    match personality {
+        Personality::Native => [argc, argv, envp],
+        Personality::Linux => [0; 3],
     }
 }
 
@@ -179,7 +419,7 @@ pub fn new_context(
             ctx.a7 = arguments[7];
         }
     }
-    let mut context = riscv_sbi_rt::TrapFrame {
+    let mut context = Context {
         ..unsafe { core::mem::MaybeUninit::zeroed().assume_init() }
     };
     // 设置栈顶指针
@@ -212,6 +452,12 @@ pub fn new_context(
             context.sstatus = core::mem::transmute(a);
         }
     }
+    // 清除 SUM 位，内核只在复制用户内存时开启，从用户态陷入时不能带着它
+    unsafe {
+        let mut a: usize = core::mem::transmute(context.sstatus);
+        a &= !(1 << 18);
+        context.sstatus = core::mem::transmute(a);
+    }
     // 这样设置 SPIE 位，使得替换 sstatus 后关闭中断，
     // 而在 sret 到用户线程时开启中断。详见 SPIE 和 SIE 的定义
     // context.sstatus.set_spie();
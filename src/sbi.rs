@@ -0,0 +1,292 @@
+//! 内核使用的 SBI 调用
+//!
+//! 启动时探测固件支持的 SBI v0.2 扩展（TIME、IPI、RFENCE、HSM、SRST），之后的调用优先使用扩展，
+//! 固件不支持时退回 legacy 调用。探测之前的调用一律使用 legacy 调用
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+
+const EXTENSION_BASE: usize = 0x10;
+const EXTENSION_TIME: usize = 0x5449_4D45;
+const EXTENSION_IPI: usize = 0x73_5049;
+const EXTENSION_RFENCE: usize = 0x5246_4E43;
+const EXTENSION_HSM: usize = 0x48_534D;
+const EXTENSION_SRST: usize = 0x5352_5354;
+
+const FUNCTION_BASE_GET_SPEC_VERSION: usize = 0;
+const FUNCTION_BASE_PROBE_EXTENSION: usize = 3;
+const FUNCTION_TIME_SET_TIMER: usize = 0;
+const FUNCTION_IPI_SEND_IPI: usize = 0;
+const FUNCTION_RFENCE_REMOTE_SFENCE_VMA: usize = 1;
+const FUNCTION_HSM_HART_START: usize = 0;
+const FUNCTION_HSM_HART_STOP: usize = 1;
+const FUNCTION_HSM_HART_GET_STATUS: usize = 2;
+const FUNCTION_SRST_SYSTEM_RESET: usize = 0;
+
+const LEGACY_SET_TIMER: usize = 0;
+const LEGACY_CLEAR_IPI: usize = 3;
+const LEGACY_SEND_IPI: usize = 4;
+const LEGACY_REMOTE_SFENCE_VMA: usize = 6;
+const LEGACY_SHUTDOWN: usize = 8;
+
+/// `hart_get_status` 返回的状态：核已经停止，可以用 [`hart_start`] 启动
+pub const HART_STOPPED: usize = 1;
+
+/// SBI v0.2 调用的返回值
+#[derive(Clone, Copy, Debug)]
+pub struct SbiRet {
+    /// 错误码，0 表示成功
+    pub error: isize,
+    /// 返回值
+    pub value: usize,
+}
+
+impl SbiRet {
+    /// 转换为 `Result`，错误时返回错误码
+    pub fn into_result(self) -> Result<usize, isize> {
+        if self.error == 0 {
+            Ok(self.value)
+        } else {
+            Err(self.error)
+        }
+    }
+}
+
+/// 固件支持的扩展
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Extensions {
+    /// SBI 规范的版本，主版本号在高 8 位；只支持 legacy 调用的固件为 0
+    pub spec_version: usize,
+    pub time: bool,
+    pub ipi: bool,
+    pub rfence: bool,
+    pub hsm: bool,
+    pub srst: bool,
+}
+
+/// 探测到的扩展，只会初始化一次
+static EXTENSIONS: Once<Extensions> = Once::new();
+
+#[inline(always)]
+fn sbi_call(
+    extension: usize,
+    function: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+) -> SbiRet {
+    let (error, value);
+    unsafe {
+        llvm_asm!("ecall"
+            : "={x10}" (error), "={x11}" (value)
+            : "{x10}" (arg0), "{x11}" (arg1), "{x12}" (arg2), "{x13}" (arg3),
+              "{x16}" (function), "{x17}" (extension)
+            : "memory"
+            : "volatile");
+    }
+    SbiRet { error, value }
+}
+
+#[inline(always)]
+fn legacy_call(extension: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
+    let ret;
+    unsafe {
+        llvm_asm!("ecall"
+            : "={x10}" (ret)
+            : "{x10}" (arg0), "{x11}" (arg1), "{x12}" (arg2), "{x17}" (extension)
+            : "memory"
+            : "volatile");
+    }
+    ret
+}
+
+/// 探测固件支持的扩展，由启动核调用一次
+pub fn init() -> &'static Extensions {
+    EXTENSIONS.call_once(|| {
+        // 只支持 legacy 调用的固件没有 BASE 扩展
+        let spec_version = match sbi_call(
+            EXTENSION_BASE,
+            FUNCTION_BASE_GET_SPEC_VERSION,
+            0,
+            0,
+            0,
+            0,
+        )
+        .into_result()
+        {
+            Ok(version) if version != 0 => version,
+            _ => return Extensions::default(),
+        };
+        let probe = |extension| {
+            sbi_call(
+                EXTENSION_BASE,
+                FUNCTION_BASE_PROBE_EXTENSION,
+                extension,
+                0,
+                0,
+                0,
+            )
+            .into_result()
+            .map_or(false, |value| value != 0)
+        };
+        Extensions {
+            spec_version,
+            time: probe(EXTENSION_TIME),
+            ipi: probe(EXTENSION_IPI),
+            rfence: probe(EXTENSION_RFENCE),
+            hsm: probe(EXTENSION_HSM),
+            srst: probe(EXTENSION_SRST),
+        }
+    })
+}
+
+/// 固件支持的扩展，探测之前视为全部不支持
+pub fn extensions() -> Extensions {
+    EXTENSIONS.r#try().cloned().unwrap_or_default()
+}
+
+/// 正在运行内核的核的掩码，停止的核不需要刷新 TLB，重新启动时会刷新
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// 标记当前核开始或者停止运行内核，核在使用内核页表之前标记为运行
+pub fn set_online(hart_id: usize, online: bool) {
+    if online {
+        ONLINE_HARTS.fetch_or(1 << hart_id, Ordering::SeqCst);
+    } else {
+        ONLINE_HARTS.fetch_and(!(1 << hart_id), Ordering::SeqCst);
+    }
+}
+
+/// 包含所有正在运行的核的掩码
+fn all_harts() -> usize {
+    ONLINE_HARTS.load(Ordering::SeqCst)
+}
+
+/// 设置下一次时钟中断的时间
+pub fn set_timer(stime_value: u64) {
+    if extensions().time {
+        sbi_call(
+            EXTENSION_TIME,
+            FUNCTION_TIME_SET_TIMER,
+            stime_value as usize,
+            0,
+            0,
+            0,
+        );
+    } else {
+        legacy_call(LEGACY_SET_TIMER, stime_value as usize, 0, 0);
+    }
+}
+
+/// 向 `hart_mask` 中的核发送核间中断，第 `i` 位表示第 `i` 个核
+pub fn send_ipi(hart_mask: usize) {
+    if extensions().ipi {
+        sbi_call(EXTENSION_IPI, FUNCTION_IPI_SEND_IPI, hart_mask, 0, 0, 0);
+    } else {
+        // legacy 调用传入的是掩码的地址
+        legacy_call(LEGACY_SEND_IPI, &hart_mask as *const _ as usize, 0, 0);
+    }
+}
+
+/// 清除当前核上待处理的核间中断
+pub fn clear_ipi() {
+    if extensions().ipi {
+        // IPI 扩展通过 sip.SSIP 通知，由 S 态自己清除
+        unsafe { llvm_asm!("csrc sip, $0" :: "r"(1 << 1) :: "volatile") };
+    } else {
+        legacy_call(LEGACY_CLEAR_IPI, 0, 0, 0);
+    }
+}
+
+/// 在所有核上刷新 `start` 开始、长度为 `size` 的虚拟地址区间的 TLB，包括当前核
+///
+/// 修改了可能被其他核缓存的页表项之后调用
+pub fn remote_sfence_vma(start: usize, size: usize) {
+    let hart_mask = all_harts();
+    if extensions().rfence {
+        sbi_call(
+            EXTENSION_RFENCE,
+            FUNCTION_RFENCE_REMOTE_SFENCE_VMA,
+            hart_mask,
+            0,
+            start,
+            size,
+        );
+    } else {
+        legacy_call(
+            LEGACY_REMOTE_SFENCE_VMA,
+            &hart_mask as *const _ as usize,
+            start,
+            size,
+        );
+    }
+}
+
+/// 启动一个已经停止的核，它会以 `opaque` 作为 `a1` 从物理地址 `start_address` 开始执行
+pub fn hart_start(hart_id: usize, start_address: usize, opaque: usize) -> Result<(), isize> {
+    sbi_call(
+        EXTENSION_HSM,
+        FUNCTION_HSM_HART_START,
+        hart_id,
+        start_address,
+        opaque,
+        0,
+    )
+    .into_result()
+    .map(|_| ())
+}
+
+/// 停止当前核，成功时不会返回，之后可以用 [`hart_start`] 重新启动；失败时返回错误码
+///
+/// 调用时必须关闭 S 态中断
+pub fn hart_stop() -> isize {
+    sbi_call(EXTENSION_HSM, FUNCTION_HSM_HART_STOP, 0, 0, 0, 0).error
+}
+
+/// 查询一个核的状态，不存在的核返回 `Err`
+pub fn hart_get_status(hart_id: usize) -> Result<usize, isize> {
+    sbi_call(
+        EXTENSION_HSM,
+        FUNCTION_HSM_HART_GET_STATUS,
+        hart_id,
+        0,
+        0,
+        0,
+    )
+    .into_result()
+}
+
+/// 系统复位的类型
+#[allow(unused)]
+#[derive(Clone, Copy, Debug)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+/// 系统复位的原因
+#[derive(Clone, Copy, Debug)]
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+/// 复位整个系统
+///
+/// 固件不支持 SRST 扩展时只能使用 legacy 调用关机，此时无法重启，也不会记录原因
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> ! {
+    if extensions().srst {
+        sbi_call(
+            EXTENSION_SRST,
+            FUNCTION_SRST_SYSTEM_RESET,
+            reset_type as usize,
+            reason as usize,
+            0,
+            0,
+        );
+    }
+    legacy_call(LEGACY_SHUTDOWN, 0, 0, 0);
+    unreachable!()
+}
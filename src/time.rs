@@ -0,0 +1,67 @@
+//! 内核的计时
+//!
+//! 单调时间来自 `time` 寄存器，频率从设备树的 `/cpus/timebase-frequency` 读取；
+//! 墙上时间在启动时从实时时钟读取一次，之后用单调时间推算
+
+use crate::driver::{registry, RtcDriver};
+use core::sync::atomic::{AtomicU64, Ordering};
+use riscv::register::time;
+use riscv_sbi::println;
+
+/// 每秒的纳秒数
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// `time` 寄存器的频率，设备树中没有时使用 QEMU virt 平台的 10 MHz
+static TIMEBASE_FREQUENCY: AtomicU64 = AtomicU64::new(10_000_000);
+
+/// 启动（`time` 为 0）时的墙上时间，自 Unix 纪元以来的纳秒数。没有实时时钟时为 0
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// 设置 `time` 寄存器的频率，在遍历设备树时调用
+pub fn set_timebase_frequency(frequency: u64) {
+    if frequency != 0 {
+        TIMEBASE_FREQUENCY.store(frequency, Ordering::Relaxed);
+    }
+}
+
+/// `time` 寄存器的频率
+pub fn timebase_frequency() -> u64 {
+    TIMEBASE_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// 时钟周期数转换为纳秒
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    (ticks as u128 * NANOS_PER_SEC as u128 / timebase_frequency() as u128) as u64
+}
+
+/// 纳秒转换为时钟周期数，向上取整
+pub fn nanos_to_ticks(nanos: u64) -> u64 {
+    let frequency = timebase_frequency() as u128;
+    ((nanos as u128 * frequency + NANOS_PER_SEC as u128 - 1) / NANOS_PER_SEC as u128) as u64
+}
+
+/// 启动以来的纳秒数
+pub fn monotonic() -> u64 {
+    ticks_to_nanos(time::read64())
+}
+
+/// 墙上时间，自 Unix 纪元以来的纳秒数
+pub fn realtime() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + monotonic()
+}
+
+/// 从第一个实时时钟读取墙上时间，需要在驱动初始化之后调用
+pub fn init() {
+    match registry::rtc_devices().first() {
+        Some(rtc) => {
+            let now = rtc.read_time();
+            BOOT_TIME.store(now.saturating_sub(monotonic()), Ordering::Relaxed);
+            println!(
+                "mod time initialized: {} s since epoch, timebase {} Hz",
+                now / NANOS_PER_SEC,
+                timebase_frequency()
+            );
+        }
+        None => println!("mod time initialized without rtc"),
+    }
+}
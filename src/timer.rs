@@ -0,0 +1,153 @@
+//! 定时器
+//!
+//! 所有核共享一个按照期限排序的定时器队列，每个核还有自己的时间片期限。
+//! 每次时钟中断之后，各个核把 SBI 时钟设置为这两者中较早的一个，不再有固定周期的时钟中断。
+//! 核空闲时没有时间片，只会被定时器或者其他中断唤醒
+
+use crate::process::{hart_id, MAX_HART_COUNT, PROCESSOR};
+use crate::sbi;
+use crate::time::nanos_to_ticks;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use riscv::register::time;
+use spin::Mutex;
+
+/// 定时器到期时执行的回调，在中断处理中执行，不能休眠
+pub type TimerCallback = Box<dyn FnOnce() + Send>;
+
+/// 已经添加的定时器，可以用来取消
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct TimerHandle {
+    /// 到期时间，以 `time` 寄存器计
+    deadline: u64,
+    /// 区分相同期限的定时器
+    id: u64,
+}
+
+/// 没有期限
+const NO_DEADLINE: u64 = u64::MAX;
+
+lazy_static! {
+    /// 所有未到期的定时器，按照期限排序
+    static ref TIMERS: Mutex<BTreeMap<TimerHandle, TimerCallback>> = Mutex::new(BTreeMap::new());
+}
+
+/// 每个核当前时间片的期限，到期后让出当前线程
+static SLICE_DEADLINES: [AtomicU64; MAX_HART_COUNT] = [
+    AtomicU64::new(NO_DEADLINE),
+    AtomicU64::new(NO_DEADLINE),
+    AtomicU64::new(NO_DEADLINE),
+    AtomicU64::new(NO_DEADLINE),
+    AtomicU64::new(NO_DEADLINE),
+    AtomicU64::new(NO_DEADLINE),
+    AtomicU64::new(NO_DEADLINE),
+    AtomicU64::new(NO_DEADLINE),
+];
+
+/// 时间片长度（微秒）的上限，超过的设置会被拒绝或者截断
+pub const MAX_TIME_SLICE: u64 = 10_000_000;
+
+/// 启动参数 `timeslice=` 指定的时间片长度（微秒），为 0 时使用调度器的设置
+static CONFIGURED_TIME_SLICE: AtomicU64 = AtomicU64::new(0);
+
+/// 读取启动参数中的时间片长度，需要在驱动初始化之后调用
+pub fn init() {
+    if let Some(time_slice) = crate::driver::time_slice() {
+        CONFIGURED_TIME_SLICE.store(time_slice.min(MAX_TIME_SLICE), Ordering::Relaxed);
+    }
+}
+
+/// 启动参数指定的时间片长度（微秒）
+pub fn configured_time_slice() -> Option<u64> {
+    match CONFIGURED_TIME_SLICE.load(Ordering::Relaxed) {
+        0 => None,
+        time_slice => Some(time_slice),
+    }
+}
+
+/// 添加一个在 `deadline`（以 `time` 寄存器计）执行 `callback` 的一次性定时器
+///
+/// 期限早于当前核已经设置的时钟时，会重新设置时钟
+pub fn add_timer(deadline: u64, callback: impl FnOnce() + Send + 'static) -> TimerHandle {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let handle = TimerHandle {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+    };
+    TIMERS.lock().insert(handle, Box::new(callback));
+    program_next();
+    handle
+}
+
+/// 取消一个还没有到期的定时器，返回是否成功
+pub fn cancel_timer(handle: TimerHandle) -> bool {
+    TIMERS.lock().remove(&handle).is_some()
+}
+
+/// 最早的定时器期限
+fn earliest_deadline() -> u64 {
+    TIMERS
+        .lock()
+        .keys()
+        .next()
+        .map_or(NO_DEADLINE, |handle| handle.deadline)
+}
+
+/// 把当前核的 SBI 时钟设置为定时器和时间片中最早的期限
+pub fn program_next() {
+    let slice_deadline = SLICE_DEADLINES[hart_id()].load(Ordering::Relaxed);
+    sbi::set_timer(earliest_deadline().min(slice_deadline));
+}
+
+/// 为当前核开始一个长度为 `micros` 微秒的时间片
+pub fn start_slice(micros: u64) {
+    let deadline = time::read64().wrapping_add(nanos_to_ticks(micros.saturating_mul(1000)));
+    SLICE_DEADLINES[hart_id()].store(deadline, Ordering::Relaxed);
+    program_next();
+}
+
+/// 结束当前核的时间片，核空闲时调用
+pub fn stop_slice() {
+    SLICE_DEADLINES[hart_id()].store(NO_DEADLINE, Ordering::Relaxed);
+    program_next();
+}
+
+/// 处理时钟中断：执行所有到期的定时器，返回当前核的时间片是否已经用完
+pub fn handle_timer_interrupt() -> bool {
+    let now = time::read64();
+    // 先取出所有到期的定时器，回调可能会再添加定时器，不能持有锁执行
+    let expired = {
+        let mut timers = TIMERS.lock();
+        let mut expired = Vec::new();
+        while let Some(&handle) = timers.keys().next() {
+            if handle.deadline > now {
+                break;
+            }
+            expired.push(timers.remove(&handle).unwrap());
+        }
+        expired
+    };
+    for callback in expired {
+        callback();
+    }
+    // 时间片用完之后由调度循环为下一个线程开始新的时间片
+    let slice_expired = SLICE_DEADLINES[hart_id()].load(Ordering::Relaxed) <= now;
+    if slice_expired {
+        SLICE_DEADLINES[hart_id()].store(NO_DEADLINE, Ordering::Relaxed);
+    }
+    program_next();
+    slice_expired
+}
+
+/// 令当前线程休眠，直到 `deadline`（以 `time` 寄存器计）
+pub fn sleep_until(deadline: u64) {
+    if time::read64() >= deadline {
+        return;
+    }
+    let thread = PROCESSOR.get().current_thread();
+    add_timer(deadline, move || PROCESSOR.get().wake_thread(thread));
+    PROCESSOR.get().sleep_current_thread();
+}